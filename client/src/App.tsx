@@ -1,15 +1,54 @@
-import React, { useState } from 'react';
+import React, { useState, useEffect } from 'react';
 import './App.css';
 import InteractionPane from './InteractionPane';
-import { withCookies, useCookies } from 'react-cookie';
+
+type AuthProvider = { id: string, redirect: boolean };
 
 const App = () => {
-  const [cookies, setCookie] = useCookies(['username']);
+  // undefined until we've asked the server who we are
+  const [username, setUsername] = useState(undefined as string | null | undefined);
   const [newUsername, setNewUsername] = useState("");
-  
+  const [password, setPassword] = useState("");
+  const [setupToken, setSetupToken] = useState("");
+  const [error, setError] = useState("");
+  const [providers, setProviders] = useState([] as AuthProvider[]);
+
+  useEffect(() => {
+    fetch('/api/auth_providers')
+      .then(response => response.json())
+      .then(setProviders)
+      .catch(e => console.error("Unable to get auth providers", e));
+  }, []);
+
+  useEffect(() => {
+    fetch('/api/me', { credentials: 'same-origin' })
+      .then(response => response.ok ? response.json() : { username: null })
+      .then(body => setUsername(body.username))
+      .catch(e => {
+        console.error("Unable to check login", e);
+        setUsername(null);
+      });
+  }, []);
+
   const handleSubmit = (event: React.FormEvent) => {
     event.preventDefault();
-    setCookie('username', newUsername, { path: '/' });
+    fetch('/api/login', {
+      method: 'POST',
+      credentials: 'same-origin',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(setupToken
+        ? { provider: 'local', username: newUsername, password: password, setup_token: setupToken }
+        : { provider: 'local', username: newUsername, password: password }),
+    })
+      .then(response => response.json().then(body => {
+        if (response.ok) {
+          setError("");
+          setUsername(body.username);
+        } else {
+          setError(body.error);
+        }
+      }))
+      .catch(e => setError(e.toString()));
   }
 
   const handleChange = (event: React.ChangeEvent<HTMLInputElement>) => {
@@ -17,23 +56,48 @@ const App = () => {
     event.preventDefault();
   }
 
-  function body(username: string | undefined) {
-    if (username) {
+  const handlePasswordChange = (event: React.ChangeEvent<HTMLInputElement>) => {
+    setPassword(event.target.value);
+    event.preventDefault();
+  }
+
+  const handleSetupTokenChange = (event: React.ChangeEvent<HTMLInputElement>) => {
+    setSetupToken(event.target.value);
+    event.preventDefault();
+  }
+
+  const hasLocal = providers.some(p => p.id === 'local');
+  const redirectProviders = providers.filter(p => p.redirect);
+
+  function body() {
+    if (username === undefined) {
+      return null;
+    } else if (username) {
       return <InteractionPane username={username} />
     } else {
-      return <form onSubmit={handleSubmit}>
-        <label htmlFor="username">Username: </label>
-        <input name="username" autoFocus autoComplete="off" autoCorrect="off" autoCapitalize="off" spellCheck="false" type="text" value={newUsername} onChange={handleChange} placeholder="mrmudkips" />
-        <input type="submit" value="Login" />
-      </form>
+      return <div>
+        {hasLocal ? <form onSubmit={handleSubmit}>
+          <label htmlFor="username">Username: </label>
+          <input name="username" autoFocus autoComplete="off" autoCorrect="off" autoCapitalize="off" spellCheck="false" type="text" value={newUsername} onChange={handleChange} placeholder="mrmudkips" />
+          <label htmlFor="password">Password: </label>
+          <input name="password" type="password" value={password} onChange={handlePasswordChange} />
+          <label htmlFor="setup_token">Setup token (if an admin gave you one): </label>
+          <input name="setup_token" type="text" autoComplete="off" value={setupToken} onChange={handleSetupTokenChange} />
+          <input type="submit" value="Login" />
+        </form> : null}
+        {redirectProviders.map(p =>
+          <a key={p.id} href={`/api/login/${p.id}`}>Log in with {p.id}</a>
+        )}
+        {error ? <div className="error">{error}</div> : null}
+      </div>
     }
   }
 
   return (
     <div className="App">
-      {body(cookies.username)}
+      {body()}
     </div>
   );
 }
 
-export default withCookies(App);
+export default App;
@@ -1,4 +1,7 @@
-import { ToServerMessage, ToClientMessage, LoginMessage } from './Messages';
+import {
+  ToServerMessage, ToClientMessage, LoginMessage, HelloMessage, ResumeMessage,
+  isSessionMessage, isResumeFailedMessage
+} from './Messages';
 
 const MIN_TIMEOUT = 2_000;
 const MAX_TIMEOUT = 60_000;
@@ -6,17 +9,17 @@ const MAX_TIMEOUT = 60_000;
 export class ChatSocket {
   private url: string;
   private ws: WebSocket;
-  private username: string;
   private next_delay: number;
   private reconnect_timeout: NodeJS.Timeout | undefined;
+  // lets us pick up where we left off if the connection drops
+  private session_token: string | undefined;
 
   onmessage?: (message: ToClientMessage) => void;
   buffer: ToServerMessage[] = [];
 
-  constructor(url: string, username: string) {
+  constructor(url: string) {
     this.url = url;
     this.ws = this.connect();
-    this.username = username;
     this.next_delay = MIN_TIMEOUT;
   }
 
@@ -28,16 +31,25 @@ export class ChatSocket {
 
     this.ws = new WebSocket(this.url);
     this.ws.onmessage = (event: MessageEvent) => {
-      if (this.onmessage) {
-        const parsed = JSON.parse(event.data);
-        console.debug("got message", parsed);
-        this.onmessage(parsed as ToClientMessage);
+      const parsed = JSON.parse(event.data) as ToClientMessage;
+      console.debug("got message", parsed);
+      if (isSessionMessage(parsed)) {
+        this.session_token = parsed.token;
+      } else if (isResumeFailedMessage(parsed)) {
+        console.info("Unable to resume session:", parsed.message);
+        this.session_token = undefined;
+        this.send(new LoginMessage());
+      } else if (this.onmessage) {
+        this.onmessage(parsed);
       }
     }
     this.ws.onerror = (event: Event) => {
       console.error("websocket error", event);
     }
     this.ws.onclose = (event: CloseEvent) => {
+      if (event.reason) {
+        console.error("websocket closed by server:", event.reason);
+      }
       this.reconnect_timeout = setTimeout(() => this.connect(), this.next_delay);
       this.next_delay = Math.min(this.next_delay * 2, MAX_TIMEOUT);
     }
@@ -45,7 +57,12 @@ export class ChatSocket {
       console.debug("websocket open");
       this.next_delay = MIN_TIMEOUT;
 
-      this.send(new LoginMessage(this.username));
+      this.send(new HelloMessage());
+      if (this.session_token) {
+        this.send(new ResumeMessage(this.session_token));
+      } else {
+        this.send(new LoginMessage());
+      }
 
       const toSend = this.buffer;
       this.buffer = [];
@@ -1,5 +1,5 @@
 import React from 'react';
-import MonacoEditor from 'react-monaco-editor';
+import MonacoEditor, { MonacoDiffEditor } from 'react-monaco-editor';
 import './Editor.css';
 
 export type EditFile = {
@@ -7,6 +7,14 @@ export type EditFile = {
   content: string
 }
 
+export type ShowDiff = {
+  name: string,
+  from: number,
+  to: number,
+  original: string,
+  content: string
+}
+
 type SaveCallback = () => void;
 
 type ChangeCallback = (content: string) => void;
@@ -45,4 +53,25 @@ const Editor = (props: {editFile: EditFile, onSave: SaveCallback, onChange: Chan
     );
 }
 
+export const DiffView = (props: {diff: ShowDiff, onClose: CloseCallback}) => {
+    const { diff, onClose } = props;
+
+    return (
+      <div className="Editor">
+        <div className="header">
+          <h2>Changes to <strong>{diff.name}</strong> from revision {diff.from} to {diff.to}</h2>
+          <button className="close" onClick={onClose}>Close</button>
+        </div>
+        <MonacoDiffEditor
+          height="100%"
+          language="lua"
+          theme="vs-dark"
+          original={diff.original}
+          value={diff.content}
+          options={{ readOnly: true }}
+        />
+      </div>
+    );
+}
+
 export default Editor;
@@ -1,8 +1,10 @@
 import React, { useState, useEffect } from 'react';
 import ChatHistory from './ChatHistory';
-import { ToClientMessage, isTellMessage, isBacklogMessage, ChatRowContent, CommandMessage, ReloadCodeMessage, isLogMessage, SaveFileMessage, isEditFileMessage } from './Messages';
+import { ToClientMessage, isTellMessage, isBacklogMessage, ChatRowContent, CommandMessage, ReloadCodeMessage, isLogMessage, SaveFileMessage, isEditFileMessage, isHistoryMessage, LoadHistoryMessage, isHelloResponseMessage, isErrorMessage, isPanelMessage, isPanelChangesMessage, isPromptMessage, isPromptClosedMessage, PromptResponseMessage, CancelPromptMessage, CompleteMessage, isCompletionsMessage, isShowDiffMessage } from './Messages';
 import { ChatSocket } from './ChatSocket';
-import Editor, { EditFile } from './Editor';
+import Editor, { DiffView, EditFile, ShowDiff } from './Editor';
+import Panels, { PanelValues } from './Panels';
+import Prompt, { PromptSpec } from './Prompt';
 import './InteractionPane.css';
 
 const InteractionPane = (props: {username: string}) => {
@@ -11,19 +13,68 @@ const InteractionPane = (props: {username: string}) => {
   const [rows, setRows] = useState([] as ChatRowContent[]);
   const [socket, setSocket] = useState(null as ChatSocket | null);
   const [editFile, setEditFile] = useState(null as EditFile | null);
+  const [showDiff, setShowDiff] = useState(null as ShowDiff | null);
+  const [hasMoreHistory, setHasMoreHistory] = useState(true);
+  const [panels, setPanels] = useState({} as PanelValues);
+  const [prompts, setPrompts] = useState([] as PromptSpec[]);
+  const [completions, setCompletions] = useState([] as string[]);
 
   const mainInputRef:any = React.createRef();
 
   useEffect(() => {
     const loc = document.location;
     const protocol = loc.protocol === 'https:' ? 'wss' : 'ws';
-    let s = new ChatSocket(`${protocol}://${loc.host}/api/socket`, props.username);
+    let s = new ChatSocket(`${protocol}://${loc.host}/api/socket`);
     s.onmessage = (message: ToClientMessage) => {
+      if (isPanelMessage(message)) {
+        setPanels((prev) => {
+          const next = { ...prev };
+          if (message.value === null) {
+            delete next[message.name];
+          } else {
+            next[message.name] = message.value;
+          }
+          return next;
+        });
+        return;
+      } else if (isPromptMessage(message)) {
+        setPrompts((prev) => prev.some(p => p.id === message.id) ? prev : prev.concat([message]));
+        return;
+      } else if (isPromptClosedMessage(message)) {
+        setPrompts((prev) => prev.filter(p => p.id !== message.id));
+        return;
+      } else if (isCompletionsMessage(message)) {
+        // completions are replacements for the whole input; we drop any for text that's since changed
+        setText((prev) => {
+          if (prev !== message.text) {
+            return prev;
+          }
+          if (message.completions.length === 1) {
+            setCompletions([]);
+            return message.completions[0];
+          }
+          setCompletions(message.completions);
+          return prev;
+        });
+        return;
+      } else if (isPanelChangesMessage(message)) {
+        setPanels((prev) => {
+          const panel = { ...prev[message.name], ...message.set };
+          message.removed.forEach(key => delete panel[key]);
+          return { ...prev, [message.name]: panel };
+        });
+        return;
+      }
+
       setRows((prev) => {
         if (isTellMessage(message)) {
           return prev.concat([message.content]);
         } else if (isBacklogMessage(message)) {
+          setHasMoreHistory(true);
           return message.history;
+        } else if (isHistoryMessage(message)) {
+          setHasMoreHistory(message.has_more);
+          return message.history.concat(prev);
         } else if (isLogMessage(message)) {
           if (message.level === "error") {
             console.error(message.message);
@@ -31,9 +82,18 @@ const InteractionPane = (props: {username: string}) => {
             console.log(message.message);
           }
           return prev;
+        } else if (isHelloResponseMessage(message)) {
+          console.debug("server hello", message);
+          return prev;
+        } else if (isErrorMessage(message)) {
+          console.error("server error:", message.message);
+          return prev;
         } else if (isEditFileMessage(message)) {
           setEditFile(message);
           return prev;
+        } else if (isShowDiffMessage(message)) {
+          setShowDiff(message);
+          return prev;
         } else {
           console.error("Unrecognized message", message);
           return prev;
@@ -49,10 +109,12 @@ const InteractionPane = (props: {username: string}) => {
     socket!.send(new CommandMessage(text));
     setLastText(text);
     setText("");
+    setCompletions([]);
   }
 
   const handleChange = (event: React.ChangeEvent<HTMLInputElement>) => {
     setText(event.target.value);
+    setCompletions([]);
     event.preventDefault();
   }
 
@@ -60,6 +122,22 @@ const InteractionPane = (props: {username: string}) => {
     socket!.send(new ReloadCodeMessage())
   }
 
+  const handleLoadHistory = () => {
+    if (rows.length > 0) {
+      socket!.send(new LoadHistoryMessage(rows[0].id))
+    }
+  }
+
+  const handlePromptSubmit = (id: string, answers: any) => {
+    socket!.send(new PromptResponseMessage(id, answers));
+    setPrompts((prev) => prev.filter(p => p.id !== id));
+  }
+
+  const handlePromptCancel = (id: string) => {
+    socket!.send(new CancelPromptMessage(id));
+    setPrompts((prev) => prev.filter(p => p.id !== id));
+  }
+
   const handleEditSave = () => {
     if (editFile) {
       socket!.send(new SaveFileMessage(editFile.name, editFile.content))
@@ -77,14 +155,29 @@ const InteractionPane = (props: {username: string}) => {
     mainInputRef.current.focus();
   }
 
+  const handleDiffClose = () => {
+    setShowDiff(null);
+    mainInputRef.current.focus();
+  }
+
   const handleKeyDown = (e: React.KeyboardEvent<HTMLInputElement>) => {
     if (e.keyCode === 38) {
       // up arrow
       setText(lastText);
       e.preventDefault();
+    } else if (e.keyCode === 9 && socket) {
+      // tab
+      socket.send(new CompleteMessage(text, e.currentTarget.selectionStart || text.length));
+      e.preventDefault();
     }
   }
 
+  const handleCompletionClick = (completion: string) => {
+    setText(completion);
+    setCompletions([]);
+    mainInputRef.current.focus();
+  }
+
   const handleHistoryClick = (e: React.MouseEvent) => {
     // if the user clicks the chat history, focus the input box, UNLESS
     // the user is trying to select text!
@@ -95,7 +188,18 @@ const InteractionPane = (props: {username: string}) => {
 
   return (
     <div className="InteractionPane">
-      <ChatHistory rows={rows} onClick={handleHistoryClick} /> 
+      <div className="main-area">
+        <ChatHistory rows={rows} onClick={handleHistoryClick} />
+        <Panels panels={panels} />
+      </div>
+      {prompts.length > 0 &&
+        <Prompt key={prompts[0].id} prompt={prompts[0]}
+          onSubmit={(answers) => handlePromptSubmit(prompts[0].id, answers)}
+          onCancel={() => handlePromptCancel(prompts[0].id)} />}
+      {completions.length > 0 &&
+        <div className="completions">
+          {completions.map(c => <button key={c} onClick={() => handleCompletionClick(c)}>{c}</button>)}
+        </div>}
       <form className="input-bar" onSubmit={handleSubmit}>
         <div>&gt;&emsp;</div>
         <input className="mainInput" autoFocus type="text" value={text} onChange={handleChange} onKeyDown={handleKeyDown} ref={mainInputRef} />
@@ -103,9 +207,11 @@ const InteractionPane = (props: {username: string}) => {
       </form>
       <div className="tool-bar">
         <button onClick={handleReload}>Reload System Code</button>
+        <button onClick={handleLoadHistory} disabled={!hasMoreHistory || rows.length === 0}>Load Older History</button>
       </div>
 
-      {editFile && <Editor editFile={editFile} onSave={handleEditSave} onChange={handleEditChange} onClose={handleEditClose} /> }
+      {showDiff && <DiffView diff={showDiff} onClose={handleDiffClose} /> }
+      {!showDiff && editFile && <Editor editFile={editFile} onSave={handleEditSave} onChange={handleEditChange} onClose={handleEditClose} /> }
     </div>
   );
 }
@@ -7,14 +7,33 @@ export class ToServerMessage {
   }
 }
 
-export class LoginMessage extends ToServerMessage {
-  username: string;
-  user_type: string;
+// Bump when the server's PROTOCOL_VERSION changes
+export const PROTOCOL_VERSION = 1;
+
+export class HelloMessage extends ToServerMessage {
+  version: number;
+  capabilities: string[];
 
-  constructor(username: string) {
+  constructor() {
+    super("Hello")
+    this.version = PROTOCOL_VERSION;
+    this.capabilities = ["html", "editor", "panels", "prompts", "completions"];
+  }
+}
+
+// Logs in as whoever the session cookie (from /api/login) says we are
+export class LoginMessage extends ToServerMessage {
+  constructor() {
     super("Login")
-    this.username = username;
-    this.user_type = 'user';
+  }
+}
+
+export class ResumeMessage extends ToServerMessage {
+  token: string;
+
+  constructor(token: string) {
+    super("Resume")
+    this.token = token;
   }
 }
 
@@ -46,12 +65,99 @@ export class SaveFileMessage extends ToServerMessage {
   }
 }
 
+export class LoadHistoryMessage extends ToServerMessage {
+  before: string;
+
+  constructor(before: string) {
+    super("LoadHistory")
+    this.before = before;
+  }
+}
+
+export class PromptResponseMessage extends ToServerMessage {
+  id: string;
+  answers: any;
+
+  constructor(id: string, answers: any) {
+    super("PromptResponse")
+    this.id = id;
+    this.answers = answers;
+  }
+}
+
+export class CancelPromptMessage extends ToServerMessage {
+  id: string;
+
+  constructor(id: string) {
+    super("CancelPrompt")
+    this.id = id;
+  }
+}
+
+export class CompleteMessage extends ToServerMessage {
+  text: string;
+  cursor: number;
+
+  constructor(text: string, cursor: number) {
+    super("Complete")
+    this.text = text;
+    this.cursor = cursor;
+  }
+}
+
 // From Server
 export type ToClientMessage = { type: string; };
+export type HelloResponseMessage = { type: string, version: number, capabilities: [string] };
+export type ErrorMessage = { type: string, message: string };
+export type SessionMessage = { type: string, token: string };
+export type PromptMessage = { type: string, id: string, spec: any };
+export type PromptClosedMessage = { type: string, id: string };
+export type CompletionsMessage = { type: string, text: string, cursor: number, completions: [string] };
+export type PanelMessage = { type: string, name: string, value: any };
+export type PanelChangesMessage = { type: string, name: string, set: { [key: string]: any }, removed: [string] };
+export type ResumeFailedMessage = { type: string, message: string };
 export type TellMessage = { type: string, content: ChatRowContent };
 export type BacklogMessage = { type: string, history: [ChatRowContent] };
+export type HistoryMessage = { type: string, history: [ChatRowContent], has_more: boolean };
 export type LogMessage = { type: string, message: string, level: string };
 export type EditFileMessage = { type: string, name: string, content: string };
+export type ShowDiffMessage = { type: string, name: string, from: number, to: number, original: string, content: string };
+
+export function isHelloResponseMessage(m: ToClientMessage): m is HelloResponseMessage {
+  return m.type === "Hello";
+}
+
+export function isErrorMessage(m: ToClientMessage): m is ErrorMessage {
+  return m.type === "Error";
+}
+
+export function isPromptMessage(m: ToClientMessage): m is PromptMessage {
+  return m.type === "Prompt";
+}
+
+export function isPromptClosedMessage(m: ToClientMessage): m is PromptClosedMessage {
+  return m.type === "PromptClosed";
+}
+
+export function isCompletionsMessage(m: ToClientMessage): m is CompletionsMessage {
+  return m.type === "Completions";
+}
+
+export function isPanelMessage(m: ToClientMessage): m is PanelMessage {
+  return m.type === "Panel";
+}
+
+export function isPanelChangesMessage(m: ToClientMessage): m is PanelChangesMessage {
+  return m.type === "PanelChanges";
+}
+
+export function isSessionMessage(m: ToClientMessage): m is SessionMessage {
+  return m.type === "Session";
+}
+
+export function isResumeFailedMessage(m: ToClientMessage): m is ResumeFailedMessage {
+  return m.type === "ResumeFailed";
+}
 
 export function isTellMessage(m: ToClientMessage): m is TellMessage {
   return m.type === "Tell";
@@ -61,6 +167,10 @@ export function isBacklogMessage(m: ToClientMessage): m is BacklogMessage {
   return m.type === "Backlog";
 }
 
+export function isHistoryMessage(m: ToClientMessage): m is HistoryMessage {
+  return m.type === "History";
+}
+
 export function isLogMessage(m: ToClientMessage): m is LogMessage {
   return m.type === "Log";
 }
@@ -69,4 +179,8 @@ export function isEditFileMessage(m: ToClientMessage): m is EditFileMessage {
   return m.type === "EditFile";
 }
 
+export function isShowDiffMessage(m: ToClientMessage): m is ShowDiffMessage {
+  return m.type === "ShowDiff";
+}
+
 export type ChatRowContent = { id: string, text: string } | { id: string, html: string };
\ No newline at end of file
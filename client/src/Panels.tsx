@@ -0,0 +1,41 @@
+import React from 'react';
+import './Panels.css';
+
+// Panels are whatever Lua passed to orisa.set_panel: dicts arrive as objects,
+// other tables as lists of [key, value] pairs.
+export type PanelValues = { [name: string]: any };
+
+const Value = (props: { value: any }) => {
+  const value = props.value;
+  if (Array.isArray(value)) {
+    // a Lua list is a table with keys 1..n; we only need the values
+    return <ul>
+      {value.map(([key, item], i) => <li key={i}><Value value={item} /></li>)}
+    </ul>;
+  } else if (value !== null && typeof value === 'object') {
+    return <dl>
+      {Object.keys(value).sort().map(key => <React.Fragment key={key}>
+        <dt>{key}</dt>
+        <dd><Value value={value[key]} /></dd>
+      </React.Fragment>)}
+    </dl>;
+  } else {
+    return <span>{String(value)}</span>;
+  }
+}
+
+const Panels = (props: { panels: PanelValues }) => {
+  const names = Object.keys(props.panels).sort();
+  if (names.length === 0) {
+    return null;
+  }
+
+  return <div className="Panels">
+    {names.map(name => <div className="Panel" key={name}>
+      <h3>{name}</h3>
+      <Value value={props.panels[name]} />
+    </div>)}
+  </div>;
+}
+
+export default Panels;
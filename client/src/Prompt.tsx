@@ -0,0 +1,90 @@
+import React, { useState } from 'react';
+import './Prompt.css';
+
+// The spec passed to orisa.prompt; see the README.
+export type PromptSpec = {
+  id: string,
+  spec: any
+}
+
+type Field = {
+  name: string,
+  label?: string,
+  type?: string, // text (default), number, checkbox or choice
+  choices?: any,
+  default?: any
+}
+
+type Answers = { [name: string]: any };
+
+// Lua lists arrive as [key, value] pairs; dicts as objects
+function asList(value: any): any[] {
+  if (Array.isArray(value)) {
+    return value.map(([key, item]) => item);
+  } else if (value !== null && typeof value === 'object') {
+    return Object.values(value);
+  } else {
+    return [];
+  }
+}
+
+function initialAnswers(fields: Field[]): Answers {
+  const answers: Answers = {};
+  fields.forEach(field => {
+    if (field.default !== undefined) {
+      answers[field.name] = field.default;
+    } else if (field.type === 'checkbox') {
+      answers[field.name] = false;
+    } else if (field.type === 'choice') {
+      answers[field.name] = asList(field.choices)[0];
+    } else {
+      answers[field.name] = "";
+    }
+  });
+  return answers;
+}
+
+const Prompt = (props: { prompt: PromptSpec, onSubmit: (answers: Answers) => void, onCancel: () => void }) => {
+  const spec = props.prompt.spec || {};
+  const fields = asList(spec.fields) as Field[];
+  const [answers, setAnswers] = useState(() => initialAnswers(fields));
+
+  const set = (name: string, value: any) => {
+    setAnswers(prev => ({ ...prev, [name]: value }));
+  }
+
+  const handleSubmit = (event: React.FormEvent) => {
+    event.preventDefault();
+    props.onSubmit(answers);
+  }
+
+  const input = (field: Field) => {
+    switch (field.type) {
+      case 'checkbox':
+        return <input type="checkbox" checked={!!answers[field.name]} onChange={e => set(field.name, e.target.checked)} />;
+      case 'number':
+        return <input type="number" value={answers[field.name]} onChange={e => set(field.name, Number(e.target.value))} />;
+      case 'choice':
+        return <select value={answers[field.name]} onChange={e => set(field.name, e.target.value)}>
+          {asList(field.choices).map(choice => <option key={choice} value={choice}>{choice}</option>)}
+        </select>;
+      default:
+        return <input type="text" value={answers[field.name]} onChange={e => set(field.name, e.target.value)} />;
+    }
+  }
+
+  return <form className="Prompt" onSubmit={handleSubmit}>
+    <h3>{spec.title || "Question"}</h3>
+    {spec.text ? <p>{spec.text}</p> : null}
+    {fields.map(field => <label key={field.name}>
+      <span>{field.label || field.name}</span>
+      {input(field)}
+    </label>)}
+    <div className="buttons">
+      <input type="submit" value={spec.submit || "OK"} />
+      <button type="button" onClick={props.onCancel}>Cancel</button>
+    </div>
+  </form>;
+}
+
+export default Prompt;
@@ -1,5 +1,5 @@
 const proxy = require("http-proxy-middleware")
 
 module.exports = app => {
-  app.use(proxy("/api/socket", {target: "http://localhost:8080", ws: true}))
+  app.use(proxy("/api", {target: "http://localhost:8080", ws: true}))
 }
\ No newline at end of file
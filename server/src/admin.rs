@@ -0,0 +1,438 @@
+use crate::chat::AppState;
+use crate::lua::{PackageReference, SerializableValue};
+use crate::object::types::GameTime;
+use crate::util::ResultAnyError;
+use crate::world::audit::{AuditAction, AuditContext, AuditQuery, AUDIT_PAGE_SIZE};
+use crate::world::query::{Filter, MAX_QUERY_RESULTS};
+use crate::world::quotas::QuotaOverride;
+use crate::world::snapshots;
+use crate::world::Id;
+use crate::world::{World, WorldRef};
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// How long password setup tokens last, in seconds (a day).
+const PASSWORD_SETUP_EXPIRY: u64 = 60 * 60 * 24;
+
+/// Privileged operations, available to users listed in `ORISA_ADMINS`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command")]
+pub enum AdminCommand {
+  IssueToken {
+    username: String,
+    user_type: Option<String>, // defaults to "bot"
+  },
+  RevokeToken {
+    username: String,
+    token_id: Option<String>, // revokes all tokens if missing
+  },
+  ListServiceAccounts {},
+  // Gives an existing user a one-time token to set their local password with,
+  // e.g. because they were created before passwords or forgot theirs
+  IssuePasswordSetup {
+    username: String,
+  },
+  Stats {},
+  // Finds objects by their attrs; see world/query.rs for the filter language
+  QueryObjects {
+    filter: Value,
+    limit: Option<usize>,
+  },
+  // Shows a user's quota and how much of it they're using
+  GetQuota {
+    username: String,
+  },
+  // Overrides a user's quota; leave both out to go back to the default
+  SetQuota {
+    username: String,
+    objects: Option<usize>,
+    package_bytes: Option<usize>,
+  },
+  // Gives an object (e.g. "#12") to a user, or to nobody
+  SetOwner {
+    object: String,
+    username: Option<String>,
+  },
+  // Searches the audit log, newest first
+  SearchAuditLog(AuditSearch),
+  // Lists the snapshots of the world in the state directory, newest first
+  ListSnapshots {},
+  // Shows how the world would change if it were rolled back to a snapshot
+  PreviewRollback {
+    snapshot: String,
+  },
+  // Saves the world as a new snapshot, then swaps in an earlier one; everyone is disconnected
+  Rollback {
+    snapshot: String,
+  },
+  // Commits a live package (e.g. "jder/live.lamp") to the system code as system.<name>
+  // (by default its own name, e.g. system.lamp), optionally pushing it, then reloads
+  // code and changes objects of the live package's kind to the system one
+  PromotePackage {
+    package: String,
+    name: Option<String>,
+    message: Option<String>,
+    push: Option<bool>,
+    overwrite: Option<bool>, // needed to replace an existing system package
+  },
+}
+
+/// Which audit log entries to show: those changing or made by an object's
+/// code (e.g. "#12"), made on behalf of a user, or within a range of game time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditSearch {
+  object: Option<String>,
+  username: Option<String>,
+  since: Option<u64>,
+  until: Option<u64>,
+  limit: Option<usize>, // at most AUDIT_PAGE_SIZE
+}
+
+/// Runs an admin's command. Most run with the world locked, but promoting a
+/// package commits (and maybe pushes) on a blocking thread without it, since
+/// that can be slow.
+pub async fn run(world_ref: WorldRef, admin: Id, command: AdminCommand) -> ResultAnyError<Value> {
+  match command {
+    AdminCommand::PromotePackage {
+      package,
+      name,
+      message,
+      push,
+      overwrite,
+    } => promote_package(world_ref, admin, package, name, message, push, overwrite).await,
+    command => world_ref.write(|world| run_locked(world, admin, command)),
+  }
+}
+
+fn run_locked(world: &mut World, admin: Id, command: AdminCommand) -> ResultAnyError<Value> {
+  match command {
+    AdminCommand::IssueToken {
+      username,
+      user_type,
+    } => {
+      let user_type = user_type.unwrap_or("bot".to_string());
+      // usernames and user types end up in package names, so they have to be valid there
+      PackageReference::new(&format!("{}/live.{}", username, user_type))?;
+
+      let state = world.get_state_mut();
+      if state.get_all_users().contains_key(&username) && !state.is_service_account(&username) {
+        return Err(format!("{} is already a regular user", username).into());
+      }
+
+      let account = state.get_or_create_service_account(&username, &user_type);
+      let token = account.issue_token();
+      Ok(json!({
+        "username": username,
+        "user_type": account.user_type,
+        "token": token,
+      }))
+    }
+    AdminCommand::RevokeToken { username, token_id } => {
+      let account = world
+        .get_state_mut()
+        .service_account_mut(&username)
+        .ok_or_else(|| format!("No service account {}", username))?;
+      match &token_id {
+        Some(token_id) => {
+          if !account.revoke_token(token_id) {
+            return Err(format!("No token {} for {}", token_id, username).into());
+          }
+        }
+        None => account.revoke_all_tokens(),
+      }
+      world.disconnect_bots(&username, token_id.as_ref().map(|t| t.as_str()));
+      Ok(json!({ "username": username, "revoked": true }))
+    }
+    AdminCommand::ListServiceAccounts {} => Ok(Value::Array(
+      world
+        .get_state()
+        .get_service_accounts()
+        .iter()
+        .map(|(username, account)| {
+          json!({
+            "username": username,
+            "user_type": account.user_type,
+            "token_ids": account.token_ids().collect::<Vec<&str>>(),
+          })
+        })
+        .collect(),
+    )),
+    AdminCommand::IssuePasswordSetup { username } => {
+      let state = world.get_state_mut();
+      if !state.get_all_users().contains_key(&username) || state.is_service_account(&username) {
+        return Err(format!("No user {} who could log in with a password", username).into());
+      }
+      if let Some(provider) = state.login_provider(&username) {
+        if provider != "local" {
+          return Err(format!("{} logs in with {}", username, provider).into());
+        }
+      }
+
+      let token = state.issue_password_setup(&username, PASSWORD_SETUP_EXPIRY);
+      Ok(json!({
+        "username": username,
+        "setup_token": token,
+        "expires_in": PASSWORD_SETUP_EXPIRY,
+      }))
+    }
+    AdminCommand::QueryObjects { filter, limit } => {
+      let filter = Filter::parse(&SerializableValue::from(filter))?;
+      let limit = limit.unwrap_or(MAX_QUERY_RESULTS).min(MAX_QUERY_RESULTS);
+      let state = world.get_state();
+      Ok(Value::Array(
+        state
+          .query_objects(&filter, limit)
+          .into_iter()
+          .map(|id| {
+            json!({
+              "id": id.to_string(),
+              "kind": state.kind(id).ok().map(|k| k.to_string()),
+              "name": state.get_attr(id, "name").ok().flatten(),
+            })
+          })
+          .collect(),
+      ))
+    }
+    AdminCommand::GetQuota { username } => quota_report(world, &username),
+    AdminCommand::SetQuota {
+      username,
+      objects,
+      package_bytes,
+    } => {
+      world.get_state_mut().set_quota_override(
+        &username,
+        QuotaOverride {
+          objects,
+          package_bytes,
+        },
+      );
+      quota_report(world, &username)
+    }
+    AdminCommand::SetOwner { object, username } => {
+      let id = parse_id(&object)?;
+      let state = world.get_state_mut();
+      let owner = match username {
+        Some(username) => Some(
+          *state
+            .get_all_users()
+            .get(&username)
+            .ok_or_else(|| format!("No user {}", username))?,
+        ),
+        None => None,
+      };
+      state.set_owner(id, owner)?;
+      Ok(json!({ "object": object, "owner": owner.map(|o| o.to_string()) }))
+    }
+    AdminCommand::SearchAuditLog(search) => search_audit_log(world, search),
+    AdminCommand::ListSnapshots {} => Ok(serde_json::to_value(snapshots::list()?)?),
+    AdminCommand::PreviewRollback { snapshot } => {
+      let state = World::read_snapshot(snapshots::open(&snapshot)?)?;
+      Ok(json!({
+        "snapshot": snapshot,
+        "diff": world.get_state().diff(&state),
+      }))
+    }
+    AdminCommand::Rollback { snapshot } => {
+      // read it first, so a bad snapshot leaves everything as it was
+      let state = World::read_snapshot(snapshots::open(&snapshot)?)?;
+      let diff = world.get_state().diff(&state);
+      let kept = snapshots::save(world)?;
+      log::warn!(
+        "Rolling back to {}; the previous state is in {}",
+        snapshot,
+        kept
+      );
+
+      world.restore(state);
+      world.audit(
+        AuditContext::user(admin),
+        AuditAction::Rollback {
+          snapshot: snapshot.clone(),
+          kept: kept.clone(),
+        },
+      );
+      world.flush_audit_log(true);
+      snapshots::save_current(world)?;
+      Ok(json!({
+        "snapshot": snapshot,
+        "kept": kept,
+        "diff": diff,
+      }))
+    }
+    // see run; its git work is done without the world locked
+    AdminCommand::PromotePackage { .. } => {
+      Err("PromotePackage can't run with the world locked".into())
+    }
+    AdminCommand::Stats {} => Ok(json!({
+      "online": world.get_presence().online().count(),
+      "rate_limits": world.get_rate_limits(),
+      "rate_limit_stats": world.get_rate_stats(),
+    })),
+  }
+}
+
+async fn promote_package(
+  world_ref: WorldRef,
+  admin: Id,
+  package: String,
+  name: Option<String>,
+  message: Option<String>,
+  push: Option<bool>,
+  overwrite: Option<bool>,
+) -> ResultAnyError<Value> {
+  let live = PackageReference::new(&package)?;
+  if !live.is_live_package() {
+    return Err(format!("{} is not a live package", package).into());
+  }
+  let system = PackageReference::for_system(name.as_deref().unwrap_or(live.package()))?;
+  let (content, exists, promoted_by, lua_host) = world_ref.read(|world| {
+    let state = world.get_state();
+    (
+      state.get_live_package_content(live.clone()).cloned(),
+      world
+        .get_lua_host()
+        .filesystem_package_to_buf(&system)
+        .is_ok(),
+      state.username(admin).unwrap_or_else(|| admin.to_string()),
+      world.get_lua_host().clone(),
+    )
+  });
+  let content = content.ok_or_else(|| format!("No live package {}", package))?;
+  if exists && !overwrite.unwrap_or(false) {
+    return Err(format!("{} already exists; set overwrite to replace it", system).into());
+  }
+
+  // the code is the package user's, so they're the author; git needs an email, which
+  // we don't have, so this is just something recognizable
+  let username = live.user().to_string();
+  let message = format!(
+    "{}\n\nPromoted from {} by {}",
+    message.unwrap_or_else(|| format!("Promote {} to {}", live, system)),
+    live,
+    promoted_by
+  );
+  let committed = system.clone();
+  let (commit, pushed) = web::block(move || {
+    let author = git2::Signature::now(&username, &format!("{}@orisa", username))?;
+    let commit = lua_host
+      .commit_system_package(&committed, &content, &message, &author)?
+      .to_string();
+
+    // the commit is made either way, so a failed push is reported rather than undoing it;
+    // reloads only fast-forward, so they won't lose it before it's pushed
+    let pushed = if push.unwrap_or(false) {
+      Some(lua_host.push().map_err(|e| e.to_string()))
+    } else {
+      None
+    };
+    Ok::<_, git2::Error>((commit, pushed))
+  })
+  .await
+  .map_err(|e| format!("Unable to commit {}: {}", system, e))?;
+  log::info!("Committed {} as {} in {}", live, system, commit);
+
+  let changed = world_ref.write(|world| {
+    world.reload_code();
+    let changed = world.get_state_mut().change_kind(&live, &system);
+    world.audit(
+      AuditContext::user(admin),
+      AuditAction::PromotePackage {
+        package: live.to_string(),
+        system: system.to_string(),
+        commit: commit.clone(),
+      },
+    );
+    changed
+  });
+  Ok(json!({
+    "package": live.to_string(),
+    "system": system.to_string(),
+    "commit": commit,
+    "push": match pushed {
+      None => Value::Null,
+      Some(Ok(result)) => json!(result),
+      Some(Err(e)) => json!({ "error": e }),
+    },
+    "objects": changed.len(),
+  }))
+}
+
+fn search_audit_log(world: &World, search: AuditSearch) -> ResultAnyError<Value> {
+  let user = match search.username {
+    Some(username) => Some(
+      *world
+        .get_state()
+        .get_all_users()
+        .get(&username)
+        .ok_or_else(|| format!("No user {}", username))?,
+    ),
+    None => None,
+  };
+  let query = AuditQuery {
+    object: search.object.as_ref().map(|o| parse_id(o)).transpose()?,
+    user,
+    since: search.since.map(|t| GameTime::default() + t),
+    until: search.until.map(|t| GameTime::default() + t),
+    limit: search.limit.unwrap_or(AUDIT_PAGE_SIZE).min(AUDIT_PAGE_SIZE),
+  };
+  Ok(Value::Array(
+    world
+      .search_audit_log(&query)
+      .into_iter()
+      .map(|entry| entry.to_json())
+      .collect(),
+  ))
+}
+
+/// The audit log search over HTTP, e.g. `/api/audit?object=%2312&since=3600`.
+pub async fn audit_log(
+  id: Identity,
+  search: web::Query<AuditSearch>,
+  data: web::Data<AppState>,
+) -> HttpResponse {
+  let username = match id.identity() {
+    Some(username) => username,
+    None => return HttpResponse::Unauthorized().json(json!({ "error": "Not logged in" })),
+  };
+  data.world_ref.read(|world| {
+    let is_admin = world
+      .get_state()
+      .get_all_users()
+      .get(&username)
+      .map_or(false, |user| world.is_admin(*user));
+    if !is_admin {
+      return HttpResponse::Forbidden()
+        .json(json!({ "error": "Only admins can see the audit log" }));
+    }
+    match search_audit_log(world, search.into_inner()) {
+      Ok(entries) => HttpResponse::Ok().json(entries),
+      Err(e) => HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+    }
+  })
+}
+
+fn quota_report(world: &World, username: &str) -> ResultAnyError<Value> {
+  let state = world.get_state();
+  let id = state
+    .get_all_users()
+    .get(username)
+    .ok_or_else(|| format!("No user {}", username))?;
+  Ok(json!({
+    "username": username,
+    "quota": state.quota(username),
+    "objects": state.owned_object_count(*id),
+    "package_bytes": state.live_package_bytes(username),
+    "package_history_bytes": state.package_history_bytes(username),
+  }))
+}
+
+fn parse_id(id: &str) -> ResultAnyError<Id> {
+  if id.starts_with('#') {
+    if let Ok(index) = id[1..].parse() {
+      return Ok(Id(index));
+    }
+  }
+  Err(format!("Invalid object id {}", id).into())
+}
@@ -0,0 +1,126 @@
+use super::{is_valid_username, AuthFuture, AuthProvider};
+use crate::util::ResultAnyError;
+use crate::world::WorldRef;
+use actix_web::web;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+const PROVIDER_ID: &str = "local";
+
+/// Usernames and passwords kept in the world state. Logging in with a new username
+/// creates it with that password; users who already exist without one (or who've
+/// forgotten theirs) set it with a `setup_token` from an admin's `IssuePasswordSetup`.
+pub struct LocalPasswordProvider;
+
+impl AuthProvider for LocalPasswordProvider {
+  fn id(&self) -> &str {
+    PROVIDER_ID
+  }
+
+  fn authenticate(&self, world_ref: WorldRef, params: HashMap<String, String>) -> AuthFuture {
+    Box::pin(check_password(world_ref, params))
+  }
+}
+
+async fn check_password(
+  world_ref: WorldRef,
+  params: HashMap<String, String>,
+) -> ResultAnyError<String> {
+  let username = params
+    .get("username")
+    .map(|u| u.trim().to_string())
+    .unwrap_or_default();
+  let password = params.get("password").cloned().unwrap_or_default();
+  let setup_token = params.get("setup_token").cloned();
+
+  if !is_valid_username(&username) {
+    return Err("Incorrect username or password".into());
+  }
+
+  // hashing is deliberately slow, so we don't do it while holding the world
+  let (existing, user_exists, claimable) = world_ref.read(|world| {
+    let state = world.get_state();
+    (
+      state.password_hash(&username).map(|h| h.to_string()),
+      state.get_all_users().contains_key(&username),
+      !state.is_service_account(&username)
+        && state
+          .login_provider(&username)
+          .map_or(true, |p| p == PROVIDER_ID),
+    )
+  });
+  if !claimable {
+    return Err(format!("{} logs in another way", username).into());
+  }
+
+  match (existing, setup_token) {
+    (Some(hash), None) => {
+      if verify_password(hash, password).await? {
+        Ok(username)
+      } else {
+        Err("Incorrect username or password".into())
+      }
+    }
+    (None, None) if user_exists => Err(
+      format!(
+        "{} has no password yet; ask an admin for a setup token",
+        username
+      )
+      .into(),
+    ),
+    (_, setup_token) => {
+      if password.len() < MIN_PASSWORD_LENGTH {
+        return Err(
+          format!(
+            "Passwords must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+          )
+          .into(),
+        );
+      }
+
+      let hash = hash_password(password).await?;
+      world_ref.write(|world| {
+        let state = world.get_state_mut();
+        let allowed = match &setup_token {
+          Some(token) => state.accepts_password_setup(&username, token),
+          // only a brand new user; someone else may have got there first
+          None => {
+            state.password_hash(&username).is_none()
+              && !state.get_all_users().contains_key(&username)
+          }
+        };
+        if !allowed {
+          Err("Incorrect username, password or setup token".into())
+        } else if !state.claim_username(&username, PROVIDER_ID) {
+          Err(format!("{} logs in another way", username).into())
+        } else {
+          log::info!("Setting password for {}", username);
+          state.set_password_hash(&username, hash);
+          Ok(username)
+        }
+      })
+    }
+  }
+}
+
+async fn hash_password(password: String) -> ResultAnyError<String> {
+  web::block(move || {
+    let salt = Uuid::new_v4();
+    argon2::hash_encoded(
+      password.as_bytes(),
+      salt.as_bytes(),
+      &argon2::Config::default(),
+    )
+  })
+  .await
+  .map_err(|e| format!("Unable to hash password: {}", e).into())
+}
+
+async fn verify_password(hash: String, password: String) -> ResultAnyError<bool> {
+  web::block(move || argon2::verify_encoded(&hash, password.as_bytes()))
+    .await
+    .map_err(|e| format!("Unable to check password: {}", e).into())
+}
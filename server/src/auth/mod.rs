@@ -0,0 +1,528 @@
+mod local;
+mod oidc;
+
+use crate::chat::AppState;
+use crate::util::ResultAnyError;
+use crate::world::rate_limit::{LoginAttempts, TOO_MANY_LOGIN_ATTEMPTS_MESSAGE};
+use crate::world::WorldRef;
+use actix_identity::{CookieIdentityPolicy, Identity, IdentityService};
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use actix_web::http::header;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const COOKIE_NAME: &str = "orisa-session";
+
+/// Remembers a login started with a redirect-based provider; see `PendingLogin`.
+const LOGIN_COOKIE_NAME: &str = "orisa-login";
+const LOGIN_COOKIE_PATH: &str = "/api/login";
+
+/// How long someone has to finish logging in at a redirect-based provider.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long a token from `/api/telnet_token` can be used to log in a MUD client.
+const TELNET_TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// How long a login lasts, in seconds.
+const COOKIE_MAX_AGE: i64 = 60 * 60 * 24 * 30;
+
+/// The cookie key needs at least this many bytes.
+const MIN_KEY_LENGTH: usize = 32;
+
+/// The key used to sign (and encrypt) session cookies, from `ORISA_SESSION_KEY`.
+/// Without one we make up a key, so logins don't survive a restart.
+pub fn session_key() -> Vec<u8> {
+  match env::var("ORISA_SESSION_KEY") {
+    Ok(key) if key.len() >= MIN_KEY_LENGTH => key.into_bytes(),
+    Ok(_) => {
+      log::error!(
+        "ORISA_SESSION_KEY must be at least {} bytes; using a random key",
+        MIN_KEY_LENGTH
+      );
+      random_key()
+    }
+    Err(_) => {
+      log::warn!("No ORISA_SESSION_KEY set; using a random key");
+      random_key()
+    }
+  }
+}
+
+fn random_key() -> Vec<u8> {
+  let mut key = Uuid::new_v4().as_bytes().to_vec();
+  key.extend_from_slice(Uuid::new_v4().as_bytes());
+  key
+}
+
+fn secure_cookies() -> bool {
+  env::var("ORISA_SECURE_COOKIES")
+    .map(|v| v == "1" || v == "true")
+    .unwrap_or(false)
+}
+
+pub fn identity_service(key: &[u8]) -> IdentityService<CookieIdentityPolicy> {
+  IdentityService::new(
+    CookieIdentityPolicy::new(key)
+      .name(COOKIE_NAME)
+      .path("/")
+      .max_age(COOKIE_MAX_AGE)
+      .same_site(SameSite::Lax)
+      .secure(secure_cookies()),
+  )
+}
+
+fn now_seconds() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+/// A login we sent the browser off to a redirect-based provider for. It's kept in a
+/// signed cookie, so only the browser which started the login can finish it; otherwise
+/// another site could send someone to our callback with its own login's state, logging
+/// them in as the attacker. The nonce is checked by the provider against what the
+/// identity provider says it was sent.
+struct PendingLogin {
+  provider: String,
+  state: String,
+  nonce: String,
+  started: u64, // seconds since the epoch
+}
+
+impl PendingLogin {
+  fn new(provider: &str) -> PendingLogin {
+    PendingLogin {
+      provider: provider.to_string(),
+      state: Uuid::new_v4().to_simple().to_string(),
+      nonce: Uuid::new_v4().to_simple().to_string(),
+      started: now_seconds(),
+    }
+  }
+
+  // provider ids, states and nonces never contain dots
+  fn to_cookie_value(&self) -> String {
+    format!(
+      "{}.{}.{}.{}",
+      self.provider, self.state, self.nonce, self.started
+    )
+  }
+
+  fn from_cookie_value(value: &str) -> Option<PendingLogin> {
+    let mut parts = value.splitn(4, '.');
+    Some(PendingLogin {
+      provider: parts.next()?.to_string(),
+      state: parts.next()?.to_string(),
+      nonce: parts.next()?.to_string(),
+      started: parts.next()?.parse().ok()?,
+    })
+  }
+
+  fn expired(&self) -> bool {
+    now_seconds().saturating_sub(self.started) > LOGIN_TIMEOUT.as_secs()
+  }
+}
+
+fn login_cookie(value: String) -> Cookie<'static> {
+  Cookie::build(LOGIN_COOKIE_NAME, value)
+    .path(LOGIN_COOKIE_PATH)
+    .http_only(true)
+    .same_site(SameSite::Lax) // so it's sent when the provider redirects back
+    .secure(secure_cookies())
+    .finish()
+}
+
+/// Usernames end up in package names and telnet prompts, so we keep them simple.
+pub fn is_valid_username(username: &str) -> bool {
+  !username.is_empty()
+    && username
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Protects cookie-authenticated requests (including websocket upgrades, which
+/// browsers don't restrict) from other sites: if there's an Origin, it must be
+/// this server or one listed in `ORISA_ALLOWED_ORIGINS`.
+pub fn origin_allowed(req: &HttpRequest, allowed_origins: &HashSet<String>) -> bool {
+  let origin = match req.headers().get(header::ORIGIN).map(|o| o.to_str()) {
+    None => return true, // not from a browser, so it can't be forged by another site
+    Some(Ok(origin)) => origin,
+    Some(Err(_)) => return false,
+  };
+
+  if allowed_origins.contains(origin) {
+    return true;
+  }
+
+  let origin_host = origin.splitn(2, "://").nth(1).unwrap_or("");
+  let allowed = origin_host == req.connection_info().host();
+  if !allowed {
+    log::warn!("Refusing request from origin {}", origin);
+  }
+  allowed
+}
+
+pub type AuthFuture = Pin<Box<dyn Future<Output = ResultAnyError<String>>>>;
+
+/// A way of finding out which orisa user someone is.
+/// Providers either take credentials directly (POSTed to /api/login) or
+/// redirect the browser elsewhere to log in and get sent back to
+/// /api/login/{id}/callback.
+pub trait AuthProvider: Send + Sync {
+  /// Used in URLs and remembered for each user; see `State::claim_username`.
+  fn id(&self) -> &str;
+
+  fn redirects(&self) -> bool {
+    false
+  }
+
+  /// For redirect-based providers, where to send the browser to log in, passing
+  /// along `state` and `nonce`.
+  fn start(&self, _state: &str, _nonce: &str) -> Option<String> {
+    None
+  }
+
+  /// Checks the credentials (the POSTed fields, or the query the browser was
+  /// sent back with), resolving to the username they identify. For redirect-based
+  /// providers, we've already checked the query's state, and add the login's `nonce`.
+  fn authenticate(&self, world_ref: WorldRef, params: HashMap<String, String>) -> AuthFuture;
+}
+
+/// The providers enabled by `ORISA_AUTH_PROVIDERS` (comma-separated; "local" by default).
+pub struct AuthProviders {
+  providers: Vec<Arc<dyn AuthProvider>>,
+  cookie_key: Key, // signs the cookies for logins in progress
+  telnet_tokens: Mutex<HashMap<String, (String, Instant)>>, // token -> username, when issued
+  login_attempts: Mutex<LoginAttempts>,
+}
+
+impl AuthProviders {
+  pub async fn configure(session_key: &[u8]) -> AuthProviders {
+    let mut providers: Vec<Arc<dyn AuthProvider>> = vec![];
+    let names = env::var("ORISA_AUTH_PROVIDERS").unwrap_or("local".to_string());
+    for name in names.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+      match name {
+        "local" => providers.push(Arc::new(local::LocalPasswordProvider)),
+        "oidc" => match oidc::OidcProvider::discover().await {
+          Ok(provider) => providers.push(Arc::new(provider)),
+          // we still start so people using other providers can play
+          Err(e) => log::error!("Unable to set up OpenID Connect: {}", e),
+        },
+        other => log::error!("Unknown auth provider {}", other),
+      }
+    }
+    log::info!(
+      "Auth providers: {:?}",
+      providers.iter().map(|p| p.id()).collect::<Vec<&str>>()
+    );
+    AuthProviders {
+      providers,
+      cookie_key: Key::from_master(session_key),
+      telnet_tokens: Mutex::new(HashMap::new()),
+      login_attempts: Mutex::new(LoginAttempts::default()),
+    }
+  }
+
+  /// Logs in with a username and password from outside the web client (e.g. a MUD
+  /// client connected from `address`), if the local provider is enabled.
+  pub async fn login_with_password(
+    &self,
+    world_ref: WorldRef,
+    username: &str,
+    password: &str,
+    address: &str,
+  ) -> Result<String, String> {
+    let provider = self
+      .get("local")
+      .ok_or("Logging in with a password isn't enabled")?;
+    let mut params = HashMap::new();
+    params.insert("username".to_string(), username.to_string());
+    params.insert("password".to_string(), password.to_string());
+    self
+      .check_credentials(world_ref, provider, params, Some(address))
+      .await
+  }
+
+  /// Authenticates with a provider which takes credentials, unless the username
+  /// or address has got them wrong too often lately.
+  async fn check_credentials(
+    &self,
+    world_ref: WorldRef,
+    provider: Arc<dyn AuthProvider>,
+    params: HashMap<String, String>,
+    address: Option<&str>,
+  ) -> Result<String, String> {
+    let username = params.get("username").cloned().unwrap_or_default();
+    if !self
+      .login_attempts
+      .lock()
+      .unwrap()
+      .allows(&username, address)
+    {
+      log::warn!(
+        "Refusing login for {} from {:?} after too many failures",
+        username,
+        address
+      );
+      return Err(TOO_MANY_LOGIN_ATTEMPTS_MESSAGE.to_string());
+    }
+
+    let result = authenticate(world_ref, provider, params).await;
+    if result.is_err() {
+      self
+        .login_attempts
+        .lock()
+        .unwrap()
+        .record_failure(&username, address);
+    }
+    result
+  }
+
+  /// Issues a one-time token `username` can log in with elsewhere (e.g. in a MUD client)
+  /// instead of a password.
+  fn issue_telnet_token(&self, username: &str) -> String {
+    let token = Uuid::new_v4().to_simple().to_string();
+    let mut tokens = self.telnet_tokens.lock().unwrap();
+    tokens.retain(|_, (_, issued)| issued.elapsed() < TELNET_TOKEN_LIFETIME);
+    tokens.insert(token.clone(), (username.to_string(), Instant::now()));
+    token
+  }
+
+  /// Uses up a token from `issue_telnet_token`, returning whether it was for `username`.
+  pub fn take_telnet_token(&self, username: &str, token: &str) -> bool {
+    let mut tokens = self.telnet_tokens.lock().unwrap();
+    tokens.retain(|_, (_, issued)| issued.elapsed() < TELNET_TOKEN_LIFETIME);
+    match tokens.get(token) {
+      Some((token_username, _)) if token_username == username => {
+        tokens.remove(token);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  fn signed_login_cookie(&self, login: &PendingLogin) -> Cookie<'static> {
+    let mut jar = CookieJar::new();
+    jar
+      .signed(&self.cookie_key)
+      .add(login_cookie(login.to_cookie_value()));
+    jar.get(LOGIN_COOKIE_NAME).unwrap().clone()
+  }
+
+  /// The login this browser started, if its cookie is intact and not too old.
+  fn pending_login(&self, req: &HttpRequest) -> Option<PendingLogin> {
+    let mut jar = CookieJar::new();
+    jar.add_original(req.cookie(LOGIN_COOKIE_NAME)?);
+    let cookie = jar.signed(&self.cookie_key).get(LOGIN_COOKIE_NAME)?;
+    PendingLogin::from_cookie_value(cookie.value()).filter(|login| !login.expired())
+  }
+
+  fn get(&self, id: &str) -> Option<Arc<dyn AuthProvider>> {
+    self.providers.iter().find(|p| p.id() == id).cloned()
+  }
+}
+
+/// Authenticates with the provider, checking the username is one they may use.
+async fn authenticate(
+  world_ref: WorldRef,
+  provider: Arc<dyn AuthProvider>,
+  params: HashMap<String, String>,
+) -> Result<String, String> {
+  let username = provider
+    .authenticate(world_ref.clone(), params)
+    .await
+    .map_err(|e| e.to_string())?;
+
+  if !is_valid_username(&username) {
+    return Err(format!(
+      "{} isn't a valid username; usernames may only contain letters, numbers, - and _",
+      username
+    ));
+  }
+
+  world_ref.write(|world| {
+    let state = world.get_state_mut();
+    if state.is_service_account(&username) {
+      Err(format!(
+        "{} is a service account and must log in with a token",
+        username
+      ))
+    } else if !state.claim_username(&username, provider.id()) {
+      Err(format!("{} logs in another way", username))
+    } else {
+      Ok(())
+    }
+  })?;
+
+  log::info!("{} logged in with {}", username, provider.id());
+  Ok(username)
+}
+
+/// Authenticates with the provider and remembers who they are in the session cookie.
+async fn complete_login(
+  id: &Identity,
+  data: &AppState,
+  provider: Arc<dyn AuthProvider>,
+  params: HashMap<String, String>,
+) -> Result<String, String> {
+  let username = authenticate(data.world_ref.clone(), provider, params).await?;
+  id.remember(username.clone());
+  Ok(username)
+}
+
+/// Logs in with credentials, e.g. `{"provider": "local", "username": ..., "password": ...}`.
+/// Every login here is checked by a provider which takes credentials; there's no
+/// default provider, and redirect-based ones only accept their own callback.
+pub async fn login(
+  req: HttpRequest,
+  id: Identity,
+  data: web::Data<AppState>,
+  body: web::Json<HashMap<String, String>>,
+) -> HttpResponse {
+  if !origin_allowed(&req, &data.allowed_origins) {
+    return HttpResponse::Forbidden().json(json!({ "error": "Origin not allowed" }));
+  }
+
+  let mut params = body.into_inner();
+  let provider_id = match params.remove("provider") {
+    Some(provider_id) => provider_id,
+    None => return HttpResponse::BadRequest().json(json!({ "error": "No provider given" })),
+  };
+  let provider = match data.auth_providers.get(&provider_id) {
+    Some(provider) if !provider.redirects() => provider,
+    _ => {
+      return HttpResponse::NotFound().json(json!({
+        "error": format!("No auth provider {} which takes credentials", provider_id)
+      }))
+    }
+  };
+
+  // the address may come from X-Forwarded-For, since we're usually behind a proxy;
+  // the limit on each username holds either way
+  let address = req.connection_info().remote().map(|remote| {
+    remote
+      .parse::<SocketAddr>()
+      .map(|a| a.ip().to_string())
+      .unwrap_or_else(|_| remote.to_string())
+  });
+  let result = data
+    .auth_providers
+    .check_credentials(data.world_ref.clone(), provider, params, address.as_deref())
+    .await;
+  match result {
+    Ok(username) => {
+      id.remember(username.clone());
+      HttpResponse::Ok().json(json!({ "username": username }))
+    }
+    Err(e) => HttpResponse::Forbidden().json(json!({ "error": e })),
+  }
+}
+
+/// Sends the browser off to log in with a redirect-based provider.
+pub async fn start_login(path: web::Path<String>, data: web::Data<AppState>) -> HttpResponse {
+  let login = PendingLogin::new(&path);
+  let url = data
+    .auth_providers
+    .get(&path)
+    .and_then(|p| p.start(&login.state, &login.nonce));
+  match url {
+    Some(url) => HttpResponse::Found()
+      .header(header::LOCATION, url)
+      .cookie(data.auth_providers.signed_login_cookie(&login))
+      .finish(),
+    None => HttpResponse::NotFound().json(json!({
+      "error": format!("No redirect-based auth provider {}", path)
+    })),
+  }
+}
+
+/// Where redirect-based providers send the browser back to.
+pub async fn login_callback(
+  req: HttpRequest,
+  path: web::Path<String>,
+  id: Identity,
+  data: web::Data<AppState>,
+  query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+  let provider = match data.auth_providers.get(&path) {
+    Some(provider) => provider,
+    None => return HttpResponse::NotFound().body(format!("No auth provider {}", path)),
+  };
+
+  let mut params = query.into_inner();
+  let result = match data.auth_providers.pending_login(&req) {
+    Some(login) if login.provider == provider.id() && params.get("state") == Some(&login.state) => {
+      params.insert("nonce".to_string(), login.nonce);
+      complete_login(&id, &data, provider, params).await
+    }
+    _ => Err("Login expired or wasn't started in this browser; try again".to_string()),
+  };
+
+  // each login can only be finished once
+  let removal = login_cookie(String::new());
+  match result {
+    Ok(_) => HttpResponse::Found()
+      .header(header::LOCATION, "/")
+      .del_cookie(&removal)
+      .finish(),
+    Err(e) => HttpResponse::Forbidden()
+      .del_cookie(&removal)
+      .body(format!("Unable to log in: {}", e)),
+  }
+}
+
+/// Lists the enabled providers, so the client knows how to offer logging in.
+pub async fn providers(data: web::Data<AppState>) -> HttpResponse {
+  HttpResponse::Ok().json(
+    data
+      .auth_providers
+      .providers
+      .iter()
+      .map(|p| json!({ "id": p.id(), "redirect": p.redirects() }))
+      .collect::<Vec<_>>(),
+  )
+}
+
+pub async fn logout(req: HttpRequest, id: Identity, data: web::Data<AppState>) -> HttpResponse {
+  if !origin_allowed(&req, &data.allowed_origins) {
+    return HttpResponse::Forbidden().json(json!({ "error": "Origin not allowed" }));
+  }
+
+  id.forget();
+  HttpResponse::Ok().json(json!({}))
+}
+
+/// Issues a one-time token for logging in a MUD client as the logged-in user,
+/// entered in place of their password; see telnet.rs.
+pub async fn telnet_token(
+  req: HttpRequest,
+  id: Identity,
+  data: web::Data<AppState>,
+) -> HttpResponse {
+  if !origin_allowed(&req, &data.allowed_origins) {
+    return HttpResponse::Forbidden().json(json!({ "error": "Origin not allowed" }));
+  }
+
+  match id.identity() {
+    Some(username) => HttpResponse::Ok().json(json!({
+      "token": data.auth_providers.issue_telnet_token(&username),
+      "expires_in": TELNET_TOKEN_LIFETIME.as_secs(),
+    })),
+    None => HttpResponse::Unauthorized().json(json!({ "error": "Not logged in" })),
+  }
+}
+
+pub async fn me(id: Identity) -> HttpResponse {
+  match id.identity() {
+    Some(username) => HttpResponse::Ok().json(json!({ "username": username })),
+    None => HttpResponse::Unauthorized().json(json!({ "error": "Not logged in" })),
+  }
+}
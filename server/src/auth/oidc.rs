@@ -0,0 +1,454 @@
+use super::{is_valid_username, now_seconds, AuthFuture, AuthProvider};
+use crate::util::ResultAnyError;
+use crate::world::WorldRef;
+use futures::future;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+
+/// Logs in with an OpenID Connect identity provider, using the authorization
+/// code flow. Configured with:
+/// * ORISA_OIDC_ISSUER: e.g. https://accounts.example.com (or a mock issuer on
+///   localhost, the only place plain http is allowed)
+/// * ORISA_OIDC_CLIENT_ID and ORISA_OIDC_CLIENT_SECRET
+/// * ORISA_OIDC_REDIRECT_URL: e.g. https://orisa.example.com/api/login/oidc/callback
+/// * ORISA_OIDC_USERNAME_CLAIM: the claim to make the username from (preferred_username by default)
+///
+/// Each identity (issuer and subject) gets a username the first time it logs in, and
+/// keeps it even if the claim changes; names already taken aren't handed out again.
+pub struct OidcProvider {
+  issuer: String,
+  client_id: String,
+  client_secret: String,
+  redirect_url: String,
+  username_claim: String,
+  authorization_endpoint: String,
+  token_endpoint: String,
+  userinfo_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+  authorization_endpoint: String,
+  token_endpoint: String,
+  userinfo_endpoint: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+  access_token: String,
+  id_token: String,
+}
+
+/// What the ID token has to say for us to accept it.
+struct Expected {
+  issuer: String,
+  client_id: String,
+  nonce: String,
+}
+
+fn required_var(name: &str) -> ResultAnyError<String> {
+  env::var(name).map_err(|_| format!("{} must be set", name).into())
+}
+
+/// We trust what the issuer's endpoints tell us because we reach them over TLS,
+/// so plain http is only allowed for a mock issuer on this machine.
+fn check_secure(url: &str) -> ResultAnyError<()> {
+  if url.starts_with("https://") {
+    return Ok(());
+  }
+  let local = ["localhost", "127.0.0.1", "[::1]"].iter().any(|host| {
+    let rest = url.trim_start_matches("http://");
+    url.starts_with("http://")
+      && rest.starts_with(host)
+      && rest[host.len()..]
+        .chars()
+        .next()
+        .map_or(true, |c| c == ':' || c == '/')
+  });
+  if local {
+    Ok(())
+  } else {
+    Err(format!("{} must be https (or http on localhost, for testing)", url).into())
+  }
+}
+
+impl OidcProvider {
+  /// Reads the configuration and fetches the issuer's endpoints.
+  pub async fn discover() -> ResultAnyError<OidcProvider> {
+    let issuer = required_var("ORISA_OIDC_ISSUER")?;
+    check_secure(&issuer)?;
+    let url = format!(
+      "{}/.well-known/openid-configuration",
+      issuer.trim_end_matches('/')
+    );
+
+    let mut response = awc::Client::default()
+      .get(&url)
+      .send()
+      .await
+      .map_err(|e| format!("Unable to fetch {}: {}", url, e))?;
+    if !response.status().is_success() {
+      return Err(format!("Unable to fetch {}: {}", url, response.status()).into());
+    }
+    let discovery: Discovery = response
+      .json()
+      .await
+      .map_err(|e| format!("Invalid discovery document at {}: {}", url, e))?;
+    check_secure(&discovery.token_endpoint)?;
+    if let Some(userinfo_endpoint) = &discovery.userinfo_endpoint {
+      check_secure(userinfo_endpoint)?;
+    }
+
+    log::info!("Using OpenID Connect issuer {}", issuer);
+    Ok(OidcProvider {
+      issuer,
+      client_id: required_var("ORISA_OIDC_CLIENT_ID")?,
+      client_secret: required_var("ORISA_OIDC_CLIENT_SECRET")?,
+      redirect_url: required_var("ORISA_OIDC_REDIRECT_URL")?,
+      username_claim: env::var("ORISA_OIDC_USERNAME_CLAIM")
+        .unwrap_or("preferred_username".to_string()),
+      authorization_endpoint: discovery.authorization_endpoint,
+      token_endpoint: discovery.token_endpoint,
+      userinfo_endpoint: discovery
+        .userinfo_endpoint
+        .ok_or("The issuer has no userinfo endpoint")?,
+    })
+  }
+}
+
+impl AuthProvider for OidcProvider {
+  fn id(&self) -> &str {
+    "oidc"
+  }
+
+  fn redirects(&self) -> bool {
+    true
+  }
+
+  fn start(&self, state: &str, nonce: &str) -> Option<String> {
+    let query = serde_urlencoded::to_string(&[
+      ("response_type", "code"),
+      ("client_id", self.client_id.as_str()),
+      ("redirect_uri", self.redirect_url.as_str()),
+      ("scope", "openid profile email"),
+      ("state", state),
+      ("nonce", nonce),
+    ])
+    .ok()?;
+    let separator = if self.authorization_endpoint.contains('?') {
+      '&'
+    } else {
+      '?'
+    };
+    Some(format!(
+      "{}{}{}",
+      self.authorization_endpoint, separator, query
+    ))
+  }
+
+  fn authenticate(&self, world_ref: WorldRef, params: HashMap<String, String>) -> AuthFuture {
+    if let Some(error) = params.get("error") {
+      return failed(format!("The identity provider said: {}", error));
+    }
+
+    // the future outlives &self, so it gets its own copies
+    let form = vec![
+      ("grant_type".to_string(), "authorization_code".to_string()),
+      (
+        "code".to_string(),
+        params.get("code").cloned().unwrap_or_default(),
+      ),
+      ("redirect_uri".to_string(), self.redirect_url.clone()),
+      ("client_id".to_string(), self.client_id.clone()),
+      ("client_secret".to_string(), self.client_secret.clone()),
+    ];
+    let token_endpoint = self.token_endpoint.clone();
+    let userinfo_endpoint = self.userinfo_endpoint.clone();
+    let expected = Expected {
+      issuer: self.issuer.clone(),
+      client_id: self.client_id.clone(),
+      nonce: params.get("nonce").cloned().unwrap_or_default(),
+    };
+    let issuer = self.issuer.clone();
+    let username_claim = self.username_claim.clone();
+    Box::pin(async move {
+      let claims = fetch_claims(token_endpoint, userinfo_endpoint, expected, form).await?;
+      username_for(world_ref, &issuer, &username_claim, &claims)
+    })
+  }
+}
+
+fn failed(message: String) -> AuthFuture {
+  let result: ResultAnyError<String> = Err(message.into());
+  Box::pin(future::ready(result))
+}
+
+/// Exchanges the code (in `form`) for tokens, checks the ID token is for the login
+/// we started, then gets the user's claims with the access token. The identity
+/// comes from userinfo, which has to be about the same subject as the ID token.
+async fn fetch_claims(
+  token_endpoint: String,
+  userinfo_endpoint: String,
+  expected: Expected,
+  form: Vec<(String, String)>,
+) -> ResultAnyError<HashMap<String, Value>> {
+  let client = awc::Client::default();
+  let mut response = client
+    .post(&token_endpoint)
+    .send_form(&form)
+    .await
+    .map_err(|e| format!("Token request failed: {}", e))?;
+  if !response.status().is_success() {
+    return Err(format!("Token request failed: {}", response.status()).into());
+  }
+  let token: TokenResponse = response
+    .json()
+    .await
+    .map_err(|e| format!("Invalid token response: {}", e))?;
+  let subject = check_id_token(&token.id_token, &expected, now_seconds())?;
+
+  let mut response = client
+    .get(&userinfo_endpoint)
+    .bearer_auth(&token.access_token)
+    .send()
+    .await
+    .map_err(|e| format!("Userinfo request failed: {}", e))?;
+  if !response.status().is_success() {
+    return Err(format!("Userinfo request failed: {}", response.status()).into());
+  }
+  let claims: HashMap<String, Value> = response
+    .json()
+    .await
+    .map_err(|e| format!("Invalid userinfo response: {}", e))?;
+  if claims.get("sub").and_then(|s| s.as_str()) != Some(subject.as_str()) {
+    return Err("The userinfo response is about someone else".into());
+  }
+  Ok(claims)
+}
+
+/// The username this identity logs in as, giving it one made from `username_claim`
+/// if it's new. Different people can have claims which map to the same username, so
+/// we never give an identity a username which is already taken.
+fn username_for(
+  world_ref: WorldRef,
+  issuer: &str,
+  username_claim: &str,
+  claims: &HashMap<String, Value>,
+) -> ResultAnyError<String> {
+  let subject = claims
+    .get("sub")
+    .and_then(|c| c.as_str())
+    .ok_or("No sub claim for this user")?;
+  let claim = claims
+    .get(username_claim)
+    .and_then(|c| c.as_str())
+    .ok_or_else(|| format!("No {} claim for this user", username_claim))?;
+  let username = username_from_claim(claim);
+  if !is_valid_username(&username) {
+    return Err(format!("Unable to make a username from {}", claim).into());
+  }
+
+  world_ref.write(|world| {
+    let state = world.get_state_mut();
+    if let Some(existing) = state.identity_username(issuer, subject) {
+      return Ok(existing.to_string());
+    }
+    if state.username_taken(&username) {
+      return Err(
+        format!(
+          "The username {} is already taken; ask an admin for help",
+          username
+        )
+        .into(),
+      );
+    }
+    log::info!("Giving {} at {} the username {}", subject, issuer, username);
+    state.link_identity(issuer, subject, &username);
+    Ok(username)
+  })
+}
+
+/// Checks an ID token was issued by our issuer, for us, for this login, and
+/// hasn't expired, returning its subject. We got it straight from the token
+/// endpoint, which `check_secure` makes sure is https, so (as the OpenID Connect
+/// spec allows) we don't check its signature.
+fn check_id_token(id_token: &str, expected: &Expected, now: u64) -> ResultAnyError<String> {
+  let claims = id_token
+    .split('.')
+    .nth(1)
+    .and_then(|payload| base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok())
+    .and_then(|json| serde_json::from_slice::<HashMap<String, Value>>(&json).ok())
+    .ok_or("The ID token couldn't be read")?;
+  let claim = |name: &str| claims.get(name).and_then(|c| c.as_str());
+
+  if claim("iss").map(|i| i.trim_end_matches('/')) != Some(expected.issuer.trim_end_matches('/')) {
+    return Err("The ID token is from a different issuer".into());
+  }
+  let audience = match claims.get("aud") {
+    Some(Value::String(aud)) => aud == &expected.client_id,
+    Some(Value::Array(auds)) => auds
+      .iter()
+      .any(|a| a.as_str() == Some(expected.client_id.as_str())),
+    _ => false,
+  };
+  if !audience {
+    return Err("The ID token is for a different client".into());
+  }
+  if claims
+    .get("exp")
+    .and_then(|e| e.as_u64())
+    .map_or(true, |exp| exp <= now)
+  {
+    return Err("The ID token has expired".into());
+  }
+  if expected.nonce.is_empty() || claim("nonce") != Some(expected.nonce.as_str()) {
+    return Err("The ID token wasn't issued for this login".into());
+  }
+  claim("sub")
+    .map(|s| s.to_string())
+    .ok_or_else(|| "The ID token has no subject".into())
+}
+
+/// Maps e.g. "Jane.Doe@example.com" to "jane_doe".
+fn username_from_claim(claim: &str) -> String {
+  claim
+    .split('@')
+    .next()
+    .unwrap_or("")
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+        c.to_ascii_lowercase()
+      } else {
+        '_'
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::test::{start, TestServer};
+  use actix_web::{web, App, HttpResponse};
+  use serde_json::json;
+  use std::sync::Mutex;
+
+  const CLIENT_ID: &str = "orisa";
+  const ISSUER: &str = "http://localhost:9000";
+
+  fn id_token(claims: Value) -> String {
+    format!(
+      "e30.{}.signature",
+      base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+    )
+  }
+
+  fn claims(nonce: &str, aud: Value) -> Value {
+    json!({
+      "iss": ISSUER,
+      "aud": aud,
+      "exp": now_seconds() + 60,
+      "nonce": nonce,
+      "sub": "alice-at-issuer",
+    })
+  }
+
+  fn expected() -> Expected {
+    Expected {
+      issuer: ISSUER.to_string(),
+      client_id: CLIENT_ID.to_string(),
+      nonce: "n0nce".to_string(),
+    }
+  }
+
+  #[test]
+  fn id_token_checks() {
+    let check = |claims: Value| check_id_token(&id_token(claims), &expected(), now_seconds());
+    assert_eq!(
+      check(claims("n0nce", json!(CLIENT_ID))).unwrap(),
+      "alice-at-issuer"
+    );
+    assert!(check(claims("n0nce", json!(["other", CLIENT_ID]))).is_ok());
+
+    assert!(check(claims("other", json!(CLIENT_ID))).is_err());
+    assert!(check(claims("n0nce", json!("other"))).is_err());
+    let mut expired = claims("n0nce", json!(CLIENT_ID));
+    expired["exp"] = json!(now_seconds() - 1);
+    assert!(check(expired).is_err());
+    let mut elsewhere = claims("n0nce", json!(CLIENT_ID));
+    elsewhere["iss"] = json!("https://evil.example.com");
+    assert!(check(elsewhere).is_err());
+    assert!(check_id_token("garbage", &expected(), now_seconds()).is_err());
+  }
+
+  #[test]
+  fn only_local_issuers_may_use_http() {
+    assert!(check_secure("https://accounts.example.com").is_ok());
+    assert!(check_secure("http://localhost:9000/token").is_ok());
+    assert!(check_secure("http://127.0.0.1").is_ok());
+    assert!(check_secure("http://accounts.example.com").is_err());
+    assert!(check_secure("http://localhost.example.com").is_err());
+  }
+
+  /// A mock issuer whose token endpoint hands out an ID token with `claims`.
+  fn mock_issuer(claims: Value) -> TestServer {
+    let claims = web::Data::new(Mutex::new(claims));
+    start(move || {
+      App::new()
+        .app_data(claims.clone())
+        .route(
+          "/token",
+          web::post().to(|claims: web::Data<Mutex<Value>>| {
+            let id_token = id_token(claims.lock().unwrap().clone());
+            async move {
+              HttpResponse::Ok().json(json!({ "access_token": "access", "id_token": id_token }))
+            }
+          }),
+        )
+        .route(
+          "/userinfo",
+          web::get().to(|| async {
+            HttpResponse::Ok().json(json!({
+              "sub": "alice-at-issuer",
+              "preferred_username": "Alice@example.com",
+            }))
+          }),
+        )
+    })
+  }
+
+  async fn log_in(issuer: &TestServer) -> ResultAnyError<HashMap<String, Value>> {
+    fetch_claims(
+      issuer.url("/token"),
+      issuer.url("/userinfo"),
+      expected(),
+      vec![("code".to_string(), "code".to_string())],
+    )
+    .await
+  }
+
+  #[actix_rt::test]
+  async fn logs_in_with_a_mock_issuer() {
+    let issuer = mock_issuer(claims("n0nce", json!(CLIENT_ID)));
+    let claims = log_in(&issuer).await.unwrap();
+    assert_eq!(
+      claims.get("preferred_username").and_then(|c| c.as_str()),
+      Some("Alice@example.com")
+    );
+  }
+
+  #[actix_rt::test]
+  async fn mock_issuer_with_the_wrong_nonce() {
+    let issuer = mock_issuer(claims("someone else's", json!(CLIENT_ID)));
+    assert!(log_in(&issuer).await.is_err());
+  }
+
+  #[actix_rt::test]
+  async fn mock_issuer_with_the_wrong_audience() {
+    let issuer = mock_issuer(claims("n0nce", json!("another client")));
+    assert!(log_in(&issuer).await.is_err());
+  }
+}
@@ -0,0 +1,288 @@
+use crate::chat::{ChatRowContent, ClientConnection, Disconnect, ToClientMessage};
+use crate::lua::SerializableValue;
+use crate::telnet::html_to_text;
+use crate::world::accounts;
+use crate::world::rate_limit::{
+  InputKind, Limiter, Verdict, DISCONNECTED_MESSAGE, THROTTLED_MESSAGE,
+};
+use crate::world::{Id, World, WorldRef};
+use actix::io::{FramedWrite, WriteHandler};
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+use tokio::io::{split, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+/// Bumped whenever FromBot/ToBot change incompatibly.
+pub const BOT_PROTOCOL_VERSION: u32 = 1;
+
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Bots which haven't authenticated by now are disconnected.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Lines sent by bots; each is a single JSON object.
+/// The first must be `Auth`, with a token issued by an admin for a service account.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+enum FromBot {
+  Auth {
+    version: u32,
+    token: String,
+  },
+  SendMessage {
+    name: String,
+    payload: serde_json::Value,
+  },
+  Command {
+    text: String,
+  },
+}
+
+/// Lines sent to bots; unlike the web protocol, these never contain HTML.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+enum ToBot {
+  Welcome {
+    version: u32,
+    id: String,
+    username: String,
+  },
+  Event {
+    name: String,
+    payload: SerializableValue,
+  },
+  Tell {
+    text: String,
+  },
+  Log {
+    level: String,
+    message: String,
+  },
+  Error {
+    message: String,
+  },
+}
+
+/// Accepts JSON-lines connections from service accounts (bots).
+pub async fn listen(world_ref: WorldRef, address: String) -> io::Result<()> {
+  let mut listener = TcpListener::bind(address.as_str()).await?;
+  log::info!("Listening for bot connections on {}", address);
+
+  actix_rt::spawn(async move {
+    loop {
+      match listener.accept().await {
+        Ok((stream, peer)) => {
+          log::info!("Bot connection from {}", peer);
+          BotSession::start_for(stream, world_ref.clone());
+        }
+        Err(e) => log::error!("Failed accepting bot connection: {}", e),
+      }
+    }
+  });
+
+  Ok(())
+}
+
+pub struct BotSession {
+  world_ref: WorldRef,
+  self_id: Option<Id>,
+  writer: FramedWrite<WriteHalf<TcpStream>, LinesCodec>,
+  limiter: Limiter,
+}
+
+impl Actor for BotSession {
+  type Context = Context<Self>;
+
+  fn started(&mut self, ctx: &mut Self::Context) {
+    ctx.run_later(AUTH_TIMEOUT, |actor, _ctx| {
+      if actor.self_id.is_none() {
+        actor.fail("Timed out waiting for Auth");
+      }
+    });
+  }
+
+  fn stopped(&mut self, ctx: &mut Self::Context) {
+    if let Some(id) = self.self_id {
+      // we use try_write here because the world could be gone if we're tearing down
+      self
+        .world_ref
+        .try_write(|world| world.logout(id, ClientConnection::Bot(ctx.address())));
+      log::info!("BotSession stopped for id {}", id);
+    }
+  }
+}
+
+impl BotSession {
+  fn start_for(stream: TcpStream, world_ref: WorldRef) -> Addr<BotSession> {
+    BotSession::create(move |ctx| {
+      let (read, write) = split(stream);
+      BotSession::add_stream(
+        FramedRead::new(read, LinesCodec::new_with_max_length(MAX_LINE_LENGTH)),
+        ctx,
+      );
+      let limiter = world_ref.read(|world| Limiter::for_connection(world.get_rate_limits()));
+      BotSession {
+        world_ref,
+        self_id: None,
+        writer: FramedWrite::new(write, LinesCodec::new(), ctx),
+        limiter,
+      }
+    })
+  }
+
+  fn handle_line(&mut self, line: &str, ctx: &mut Context<Self>) {
+    let message = match serde_json::from_str::<FromBot>(line) {
+      Ok(message) => message,
+      Err(e) => {
+        self.send(&ToBot::Error {
+          message: format!("Unable to parse message: {}", e),
+        });
+        self.with_rate_limit(InputKind::Invalid, |_world| ());
+        return;
+      }
+    };
+
+    match (message, self.self_id) {
+      (FromBot::Auth { version, token }, None) => self.handle_auth(version, &token, ctx),
+      (FromBot::Auth { .. }, Some(_)) => self.send(&ToBot::Error {
+        message: "Already authenticated".to_string(),
+      }),
+      (_, None) => self.fail("Expected Auth first"),
+      (FromBot::SendMessage { name, payload }, Some(id)) => match serde_json::from_value(payload) {
+        Ok(payload) => self.with_rate_limit(InputKind::SendMessage, |world| {
+          world.user_command(id, &name, payload)
+        }),
+        Err(e) => self.send(&ToBot::Error {
+          message: format!("Invalid payload: {}", e),
+        }),
+      },
+      (FromBot::Command { text }, Some(id)) => {
+        self.with_rate_limit(InputKind::Command, |world| world.command(id, &text))
+      }
+    }
+  }
+
+  /// Runs `body` with the world unless we've been sending too fast.
+  fn with_rate_limit<F>(&mut self, kind: InputKind, body: F)
+  where
+    F: FnOnce(&mut World),
+  {
+    let id = self.self_id;
+    let world_ref = self.world_ref.clone();
+    let verdict = world_ref.write(|world| {
+      let verdict = world.check_rate(id, &mut self.limiter, kind);
+      if let Verdict::Allow = verdict {
+        body(world);
+      }
+      verdict
+    });
+
+    match verdict {
+      Verdict::Allow => (),
+      Verdict::Throttle => self.send(&ToBot::Error {
+        message: THROTTLED_MESSAGE.to_string(),
+      }),
+      Verdict::Disconnect => self.fail(DISCONNECTED_MESSAGE),
+    }
+  }
+
+  fn handle_auth(&mut self, version: u32, token: &str, ctx: &mut Context<Self>) {
+    if version != BOT_PROTOCOL_VERSION {
+      self.fail(&format!(
+        "Unsupported protocol version {}; this server speaks version {}",
+        version, BOT_PROTOCOL_VERSION
+      ));
+      return;
+    }
+
+    let world_ref = self.world_ref.clone();
+    let account = world_ref.read(|world| world.get_state().authenticate_service_token(token));
+    match account {
+      None => self.fail("Invalid token"),
+      Some((username, user_type)) => {
+        let id = world_ref.write(|world| {
+          let id = world
+            .get_state_mut()
+            .get_or_create_user(&username, &user_type);
+          // bots don't get a backlog
+          world.login(id, None, ClientConnection::Bot(ctx.address()));
+          world.register_bot_token(
+            id,
+            &username,
+            accounts::token_id(token),
+            ClientConnection::Bot(ctx.address()),
+          );
+          id
+        });
+        self.self_id = Some(id);
+        self.send(&ToBot::Welcome {
+          version: BOT_PROTOCOL_VERSION,
+          id: id.to_string(),
+          username,
+        });
+      }
+    }
+  }
+
+  fn send(&mut self, message: &ToBot) {
+    match serde_json::to_string(message) {
+      Ok(line) => self.writer.write(line),
+      Err(e) => log::error!("Error serializing message for bot: {}", e),
+    }
+  }
+
+  /// Reports an error and hangs up once it's been written.
+  fn fail(&mut self, message: &str) {
+    self.send(&ToBot::Error {
+      message: message.to_string(),
+    });
+    self.writer.close();
+  }
+}
+
+impl Handler<Disconnect> for BotSession {
+  type Result = ();
+
+  fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) {
+    // the world has already forgotten this connection
+    self.self_id = None;
+    self.fail(&msg.reason);
+  }
+}
+
+impl WriteHandler<LinesCodecError> for BotSession {}
+
+impl StreamHandler<Result<String, LinesCodecError>> for BotSession {
+  fn handle(&mut self, line: Result<String, LinesCodecError>, ctx: &mut Self::Context) {
+    match line {
+      Ok(line) => self.handle_line(line.trim(), ctx),
+      Err(e) => {
+        log::error!("Failed reading from bot: {}", e);
+        ctx.stop();
+      }
+    }
+  }
+}
+
+impl Handler<ToClientMessage> for BotSession {
+  type Result = ();
+
+  fn handle(&mut self, msg: ToClientMessage, _ctx: &mut Self::Context) {
+    let message = match msg {
+      ToClientMessage::Tell { content } => ToBot::Tell {
+        text: match content {
+          ChatRowContent::TextContent { text, .. } => text,
+          ChatRowContent::HtmlContent { html, .. } => html_to_text(&html),
+        },
+      },
+      ToClientMessage::Event { name, payload } => ToBot::Event { name, payload },
+      ToClientMessage::Log { level, message } => ToBot::Log { level, message },
+      // bots have no use for history, editors or admin results
+      _ => return,
+    };
+    self.send(&message)
+  }
+}
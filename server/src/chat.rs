@@ -1,19 +1,68 @@
+use crate::admin::{self, AdminCommand};
+use crate::auth::AuthProviders;
+use crate::bot::BotSession;
 use crate::lua::SerializableValue;
-use crate::object::types::Message;
-use crate::world::{Id, WorldRef};
-use actix::{Actor, AsyncContext, Handler, Message as ActixMessage, StreamHandler};
+use crate::sanitize::sanitize_html;
+use crate::telnet::{html_to_text, TelnetSession};
+use crate::world::audit::{AuditAction, AuditContext};
+use crate::world::history::HISTORY_PAGE_SIZE;
+use crate::world::rate_limit::{
+  InputKind, Limiter, Verdict, DISCONNECTED_MESSAGE, THROTTLED_MESSAGE,
+};
+use crate::world::{Id, World, WorldRef};
+use actix::{
+  Actor, ActorFuture, Addr, AsyncContext, Handler, Message as ActixMessage, StreamHandler,
+  WrapFuture,
+};
 use actix_web::web;
 use actix_web_actors::ws;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::Duration;
 use uuid::Uuid;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Bumped whenever ToServerMessage/ToClientMessage change incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest client protocol version we still accept.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features a client can declare in its Hello:
+/// * html: can render HtmlContent (otherwise we send text)
+/// * editor: can handle EditFile and ShowDiff
+/// * events: wants structured Event messages
+/// * panels: can show Panel/PanelChanges (e.g. in a sidebar)
+/// * prompts: can show Prompts and send PromptResponse/CancelPrompt
+const CAPABILITIES: &[&str] = &[
+  "html",
+  "editor",
+  "events",
+  "panels",
+  "prompts",
+  "completions",
+];
+
+/// How long the user's Lua code gets to come up with completions.
+const COMPLETION_BUDGET: Duration = Duration::from_millis(50);
+
+/// How long we wait for completions (e.g. if the world is busy) before giving up.
+const COMPLETION_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MAX_COMPLETIONS: usize = 50;
+
 pub struct ChatSocket {
   app_data: web::Data<AppState>,
+  username: String, // from the session cookie
   self_id: Option<Id>,
+  capabilities: Option<HashSet<String>>, // None until we've had a Hello
+  limiter: Limiter,
+  session_token: Option<String>,
+  panels: HashMap<String, SerializableValue>, // as last sent to this client
+  completing: bool,
+  next_completion: Option<(String, usize)>, // the latest request made while completing
 }
 
 impl Actor for ChatSocket {
@@ -26,27 +75,38 @@ impl Actor for ChatSocket {
 
   fn stopped(&mut self, ctx: &mut Self::Context) {
     if let Some(id) = self.self_id {
+      let connection = ClientConnection::Web(ctx.address());
       // we use try_write here because the world could be gone if we're tearing down
-      self.app_data.world_ref.try_write(|world| {
-        world.remove_chat_connection(id, ctx.address());
-        world.send_message(Message {
-          target: self.id(),
-          original_user: Some(self.id()),
-          immediate_sender: self.id(),
-          name: "disconnected".to_string(),
-          payload: SerializableValue::Nil,
-        })
-      });
+      match self.session_token.take() {
+        Some(token) => self
+          .app_data
+          .world_ref
+          .try_write(|world| world.suspend(id, connection, &token)),
+        None => self
+          .app_data
+          .world_ref
+          .try_write(|world| world.logout(id, connection)),
+      };
       log::info!("ChatSocket stopped for id {}", id);
     }
   }
 }
 
 impl ChatSocket {
-  pub fn new(data: web::Data<AppState>) -> ChatSocket {
+  pub fn new(data: web::Data<AppState>, username: String) -> ChatSocket {
+    let limiter = data
+      .world_ref
+      .read(|world| Limiter::for_connection(world.get_rate_limits()));
     ChatSocket {
       app_data: data,
+      username,
       self_id: None,
+      capabilities: None,
+      limiter,
+      session_token: None,
+      panels: HashMap::new(),
+      completing: false,
+      next_completion: None,
     }
   }
 
@@ -56,92 +116,410 @@ impl ChatSocket {
     ctx: &mut ws::WebsocketContext<Self>,
   ) -> Result<(), serde_json::error::Error> {
     let message: ToServerMessage = serde_json::from_str(&text)?;
-    log::info!("Got message {:?}", message);
+    log::info!("Got {} message", message.kind());
     match message {
-      ToServerMessage::Login {
-        username,
-        user_type,
-      } => self.handle_login(&username, &user_type, ctx),
-      ToServerMessage::Command { text } => {
-        let mut payload = HashMap::new();
-        payload.insert(
-          "message".to_string(),
-          SerializableValue::String(text.to_string()),
-        );
-        self.handle_user_command("command", SerializableValue::Dict(payload))
+      ToServerMessage::Hello {
+        version,
+        capabilities,
+      } => self.handle_hello(version, capabilities, ctx),
+      _ if self.capabilities.is_none() => {
+        self.close_with_reason(ws::CloseCode::Policy, "Expected Hello first", ctx)
       }
-      ToServerMessage::SendMessage { name, payload } => {
-        self.handle_user_command(&name, serde_json::from_value(payload)?)
+      ToServerMessage::Login {} => self.handle_login(ctx),
+      ToServerMessage::Resume { token } => self.handle_resume(&token, ctx),
+      ToServerMessage::Command { text } => {
+        self.with_rate_limit(InputKind::Command, ctx, |world, id| {
+          world.command(id, &text)
+        });
       }
+      ToServerMessage::SendMessage { name, payload } => self.handle_user_command(
+        InputKind::SendMessage,
+        &name,
+        serde_json::from_value(payload)?,
+        ctx,
+      ),
       ToServerMessage::ReloadCode {} => self.handle_reload(ctx),
+      ToServerMessage::Admin { command } => self.handle_admin(command, ctx),
+      ToServerMessage::LoadHistory { before, limit } => {
+        self.handle_load_history(&before, limit.unwrap_or(HISTORY_PAGE_SIZE), ctx)
+      }
+      ToServerMessage::PromptResponse { id, answers } => {
+        self.handle_prompt_response(&id, Some(serde_json::from_value(answers)?), ctx)
+      }
+      ToServerMessage::CancelPrompt { id } => self.handle_prompt_response(&id, None, ctx),
+      ToServerMessage::Complete { text, cursor } => self.handle_complete(text, cursor, ctx),
       ToServerMessage::SaveFile { name, content } => {
         // TODO: this needs way nicer syntax
         let mut payload = HashMap::new();
         payload.insert("name".to_string(), SerializableValue::String(name));
         payload.insert("content".to_string(), SerializableValue::String(content));
 
-        self.handle_user_command("save_file", SerializableValue::Dict(payload))
+        self.handle_user_command(
+          InputKind::SaveFile,
+          "save_file",
+          SerializableValue::Dict(payload),
+          ctx,
+        )
       }
     }
 
     Ok(())
   }
 
-  fn handle_login(
+  fn handle_hello(
     &mut self,
-    username: &str,
-    user_type: &str,
+    version: u32,
+    capabilities: Vec<String>,
     ctx: &mut ws::WebsocketContext<Self>,
   ) {
+    if self.capabilities.is_some() {
+      self.send_error("Already said Hello", ctx);
+      return;
+    }
+
+    if version < MIN_PROTOCOL_VERSION || version > PROTOCOL_VERSION {
+      self.close_with_reason(
+        ws::CloseCode::Unsupported,
+        &format!(
+          "Unsupported protocol version {}; this server supports {} to {}",
+          version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ),
+        ctx,
+      );
+      return;
+    }
+
+    let capabilities: HashSet<String> = capabilities
+      .into_iter()
+      .filter(|c| CAPABILITIES.contains(&c.as_str()))
+      .collect();
+
+    self
+      .send_to_client(
+        &ToClientMessage::Hello {
+          version: PROTOCOL_VERSION,
+          capabilities: capabilities.iter().cloned().collect(),
+        },
+        ctx,
+      )
+      .unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
+    self.capabilities = Some(capabilities);
+  }
+
+  fn handle_login(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+    let username = self.username.clone();
+    let username = username.as_str();
     let world_ref = self.app_data.world_ref.clone();
-    world_ref.write(|world| {
-      let id = world
-        .get_state_mut()
-        .get_or_create_user(username, user_type);
+    if world_ref.read(|world| world.get_state().is_service_account(username)) {
+      self
+        .send_to_client(
+          &ToClientMessage::Log {
+            level: "error".to_string(),
+            message: format!(
+              "{} is a service account and must log in with a token",
+              username
+            ),
+          },
+          ctx,
+        )
+        .unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
+      return;
+    }
 
-      if let Some(existing_id) = self.self_id {
-        world.remove_chat_connection(existing_id, ctx.address());
+    let (token, history) = world_ref.write(|world| {
+      let id = world.get_state_mut().get_or_create_user(username, "user");
+
+      if let Some(token) = self.session_token.take() {
+        world.end_session(&token);
       }
 
-      world.register_chat_connect(id, ctx.address());
+      // Tells queued while the user was away are sent as live tells through the
+      // connection we register here, so they arrive after the backlog we send
+      // directly below; the backlog is taken before they're delivered, so it
+      // doesn't include them too.
+      let history = world.login(id, self.self_id, ClientConnection::Web(ctx.address()));
       self.self_id = Some(id);
+      (world.start_session(id), history)
+    });
+
+    self.session_token = Some(token.clone());
+    self
+      .send_to_client(&ToClientMessage::Session { token }, ctx)
+      .and_then(|_| self.send_to_client(&ToClientMessage::Backlog { history }, ctx))
+      .unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
+  }
+
+  /// Picks up a session whose connection dropped, instead of logging in again.
+  fn handle_resume(&mut self, token: &str, ctx: &mut ws::WebsocketContext<Self>) {
+    if self.self_id.is_some() {
+      self.send_error("Already logged in", ctx);
+      return;
+    }
+
+    let username = self.username.clone();
+    let resumed = self.app_data.world_ref.write(|world| {
+      // the session has to belong to whoever the cookie says we are
+      let id = *world.get_state().get_all_users().get(&username)?;
+      world
+        .resume(token, id, ClientConnection::Web(ctx.address()))
+        .map(|(token, missed)| (id, token, missed))
+    });
+
+    let result = match resumed {
+      None => self.send_to_client(
+        &ToClientMessage::ResumeFailed {
+          message: "Unable to resume session; log in again".to_string(),
+        },
+        ctx,
+      ),
+      Some((id, token, missed)) => {
+        self.self_id = Some(id);
+        self.session_token = Some(token.clone());
+        self
+          .send_to_client(&ToClientMessage::Session { token }, ctx)
+          .and_then(|_| {
+            missed
+              .into_iter()
+              .map(|message| self.deliver(message, ctx))
+              .collect()
+          })
+      }
+    };
+    result.unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
+  }
+
+  fn handle_load_history(
+    &mut self,
+    before: &str,
+    limit: usize,
+    ctx: &mut ws::WebsocketContext<Self>,
+  ) {
+    let page = self.with_rate_limit(InputKind::LoadHistory, ctx, |world, id| {
+      world.history_page(id, Some(before), limit)
     });
-    self.handle_user_command("connected", SerializableValue::Nil);
+
+    if let Some((history, has_more)) = page {
+      self
+        .send_to_client(&ToClientMessage::History { history, has_more }, ctx)
+        .unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
+    }
   }
 
-  fn handle_user_command(&self, name: &str, payload: SerializableValue) {
+  fn handle_user_command(
+    &mut self,
+    kind: InputKind,
+    name: &str,
+    payload: SerializableValue,
+    ctx: &mut ws::WebsocketContext<Self>,
+  ) {
+    self.with_rate_limit(kind, ctx, |world, id| world.user_command(id, name, payload));
+  }
+
+  /// Answers (or cancels, if `answers` is None) a prompt.
+  fn handle_prompt_response(
+    &mut self,
+    prompt_id: &str,
+    answers: Option<SerializableValue>,
+    ctx: &mut ws::WebsocketContext<Self>,
+  ) {
+    let found = self.with_rate_limit(InputKind::SendMessage, ctx, |world, id| {
+      world.respond_to_prompt(id, prompt_id, answers)
+    });
+    if found == Some(false) {
+      self.send_error("That prompt has already been closed", ctx);
+    }
+  }
+
+  /// Asks the user's object (with a "complete" query) how to finish `text`.
+  /// This runs in the background so it never holds up other input; we only
+  /// have one request out at a time and skip any superseded while it runs.
+  fn handle_complete(&mut self, text: String, cursor: usize, ctx: &mut ws::WebsocketContext<Self>) {
+    if self.completing {
+      self.next_completion = Some((text, cursor));
+      return;
+    }
+
+    let mut payload = HashMap::new();
+    payload.insert("text".to_string(), SerializableValue::String(text.clone()));
+    payload.insert(
+      "cursor".to_string(),
+      SerializableValue::Integer(cursor as i64),
+    );
+    let request = self.with_rate_limit(InputKind::Complete, ctx, |world, id| {
+      world.query_user(
+        id,
+        "complete",
+        SerializableValue::Dict(payload),
+        COMPLETION_BUDGET,
+      )
+    });
+    let request = match request {
+      Some(request) => request.timeout(COMPLETION_TIMEOUT),
+      None => return,
+    };
+
+    self.completing = true;
+    ctx.spawn(request.into_actor(self).map(move |result, act, ctx| {
+      act.completing = false;
+      let completions = match result {
+        Ok(Ok(value)) => completions_from(value),
+        Ok(Err(e)) => {
+          log::warn!("Completion failed: {}", e);
+          vec![]
+        }
+        Err(e) => {
+          log::warn!("Completion didn't finish: {}", e);
+          vec![]
+        }
+      };
+      act
+        .send_to_client(
+          &ToClientMessage::Completions {
+            text,
+            cursor,
+            completions,
+          },
+          ctx,
+        )
+        .unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
+
+      if let Some((text, cursor)) = act.next_completion.take() {
+        act.handle_complete(text, cursor, ctx);
+      }
+    }));
+  }
+
+  /// Runs `body` with the world and our id, unless we've been sending
+  /// too fast (in which case it returns None).
+  fn with_rate_limit<F, R>(
+    &mut self,
+    kind: InputKind,
+    ctx: &mut ws::WebsocketContext<Self>,
+    body: F,
+  ) -> Option<R>
+  where
+    F: FnOnce(&mut World, Id) -> R,
+  {
     if self.self_id.is_none() {
-      log::warn!("Got command when had no id")
-    } else {
-      self.app_data.world_ref.write(|world| {
-        world.send_message(Message {
-          target: self.id(),
-          original_user: Some(self.id()),
-          immediate_sender: self.id(),
-          name: name.to_string(),
-          payload: payload,
-        })
-      })
+      log::warn!("Got command when had no id");
+      return None;
     }
+
+    let id = self.id();
+    let world_ref = self.app_data.world_ref.clone();
+    let (verdict, result) = world_ref.write(|world| {
+      let verdict = world.check_rate(Some(id), &mut self.limiter, kind);
+      let result = match verdict {
+        Verdict::Allow => Some(body(world, id)),
+        _ => None,
+      };
+      (verdict, result)
+    });
+
+    self.handle_verdict(verdict, ctx);
+    result
   }
 
-  fn handle_reload(&self, ctx: &mut ws::WebsocketContext<Self>) {
-    let message = match self
-      .app_data
-      .world_ref
-      .write(|world| world.pull_and_reload_code())
-    {
-      Err(e) => format!("Failed to reload: {}", e),
-      Ok(message) => format!("Reloaded code: {}", message),
+  /// Counts a frame we couldn't handle against our limits, so a client can't
+  /// flood us with them.
+  fn throttle_invalid(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+    let id = self.self_id;
+    let world_ref = self.app_data.world_ref.clone();
+    let verdict =
+      world_ref.write(|world| world.check_rate(id, &mut self.limiter, InputKind::Invalid));
+    self.handle_verdict(verdict, ctx);
+  }
+
+  fn handle_verdict(&mut self, verdict: Verdict, ctx: &mut ws::WebsocketContext<Self>) {
+    match verdict {
+      Verdict::Allow => (),
+      Verdict::Throttle => self
+        .send_to_client(
+          &ToClientMessage::Log {
+            level: "warn".to_string(),
+            message: THROTTLED_MESSAGE.to_string(),
+          },
+          ctx,
+        )
+        .unwrap_or_else(|e| log::error!("Error writing to client: {}", e)),
+      Verdict::Disconnect => {
+        self.close_with_reason(ws::CloseCode::Policy, DISCONNECTED_MESSAGE, ctx)
+      }
+    }
+  }
+
+  fn handle_reload(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+    let lua_host = self.with_rate_limit(InputKind::ReloadCode, ctx, |world, _id| {
+      world.get_lua_host().clone()
+    });
+    let lua_host = match lua_host {
+      Some(lua_host) => lua_host,
+      None => return,
     };
-    self
-      .send_to_client(
-        &ToClientMessage::Tell {
-          content: ChatRowContent::new(&message),
+
+    // fetching can be slow, so it happens on a blocking thread without the world locked
+    let fetch = web::block(move || lua_host.fetch());
+    ctx.spawn(fetch.into_actor(self).map(|result, act, ctx| {
+      let message = match result {
+        Err(e) => format!("Failed to reload: {}", e),
+        Ok(message) => {
+          let id = act.id();
+          act.app_data.world_ref.write(|world| {
+            world.reload_code();
+            world.audit(AuditContext::user(id), AuditAction::ReloadCode {});
+          });
+          format!("Reloaded code: {}", message)
+        }
+      };
+      act
+        .send_to_client(
+          &ToClientMessage::Tell {
+            content: ChatRowContent::new(&message),
+          },
+          ctx,
+        )
+        .unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
+    }));
+  }
+
+  fn handle_admin(&mut self, command: AdminCommand, ctx: &mut ws::WebsocketContext<Self>) {
+    let response = if self.self_id.is_none() {
+      ToClientMessage::Log {
+        level: "error".to_string(),
+        message: "You must log in first".to_string(),
+      }
+    } else {
+      let is_admin = self.with_rate_limit(InputKind::Admin, ctx, |world, id| world.is_admin(id));
+      match is_admin {
+        None => return,
+        Some(false) => ToClientMessage::Log {
+          level: "error".to_string(),
+          message: "Only admins can do that".to_string(),
         },
-        ctx,
-      )
-      .unwrap();
+        Some(true) => {
+          // not in with_rate_limit, since some commands do slow work without the world
+          // locked; we answer once it's done
+          let run = admin::run(self.app_data.world_ref.clone(), self.id(), command);
+          ctx.spawn(run.into_actor(self).map(|result, act, ctx| {
+            let response = match result {
+              Ok(result) => ToClientMessage::AdminResponse { result },
+              Err(e) => ToClientMessage::Log {
+                level: "error".to_string(),
+                message: format!("Admin command failed: {}", e),
+              },
+            };
+            act
+              .send_to_client(&response, ctx)
+              .unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
+          }));
+          return;
+        }
+      }
+    };
+
+    self
+      .send_to_client(&response, ctx)
+      .unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
   }
 
   fn id(&self) -> Id {
@@ -153,11 +531,137 @@ impl ChatSocket {
     message: &ToClientMessage,
     ctx: &mut ws::WebsocketContext<Self>,
   ) -> Result<(), serde_json::error::Error> {
-    let s = serde_json::to_string(message)?;
-    ctx.text(s);
+    if let Some(message) = self.adapt_for_client(message) {
+      let s = serde_json::to_string(&message)?;
+      ctx.text(s);
+    }
     Ok(())
   }
 
+  /// Sends a message from the world, reducing panels to what's changed since we last sent them.
+  fn deliver(
+    &mut self,
+    message: ToClientMessage,
+    ctx: &mut ws::WebsocketContext<Self>,
+  ) -> Result<(), serde_json::error::Error> {
+    match message {
+      ToClientMessage::Panel { name, value } => match self.panel_update(name, value) {
+        Some(update) => self.send_to_client(&update, ctx),
+        None => Ok(()),
+      },
+      other => self.send_to_client(&other, ctx),
+    }
+  }
+
+  fn panel_update(&mut self, name: String, value: SerializableValue) -> Option<ToClientMessage> {
+    if !self.has_capability("panels") {
+      return None;
+    }
+
+    let previous = if let SerializableValue::Nil = value {
+      self.panels.remove(&name)
+    } else {
+      self.panels.insert(name.clone(), value.clone())
+    };
+
+    match (previous, value) {
+      (Some(ref old), ref new) if old == new => None,
+      (None, SerializableValue::Nil) => None,
+      (Some(SerializableValue::Dict(old)), SerializableValue::Dict(new)) => {
+        let removed = old
+          .keys()
+          .filter(|k| !new.contains_key(*k))
+          .cloned()
+          .collect();
+        let set = new
+          .into_iter()
+          .filter(|(k, v)| old.get(k) != Some(v))
+          .collect();
+        Some(ToClientMessage::PanelChanges { name, set, removed })
+      }
+      (_, value) => Some(ToClientMessage::Panel { name, value }),
+    }
+  }
+
+  fn has_capability(&self, capability: &str) -> bool {
+    self
+      .capabilities
+      .as_ref()
+      .map(|c| c.contains(capability))
+      .unwrap_or(false)
+  }
+
+  /// Downgrades (or drops) messages this client has said it can't handle.
+  fn adapt_for_client(&self, message: &ToClientMessage) -> Option<ToClientMessage> {
+    let html = self.has_capability("html");
+    let rows = |rows: &Vec<ChatRowContent>| -> Vec<ChatRowContent> {
+      rows
+        .iter()
+        .map(|r| if html { r.clone() } else { r.to_text() })
+        .collect()
+    };
+
+    match message {
+      ToClientMessage::Tell { content } if !html => Some(ToClientMessage::Tell {
+        content: content.to_text(),
+      }),
+      ToClientMessage::Backlog { history } => Some(ToClientMessage::Backlog {
+        history: rows(history),
+      }),
+      ToClientMessage::History { history, has_more } => Some(ToClientMessage::History {
+        history: rows(history),
+        has_more: *has_more,
+      }),
+      ToClientMessage::EditFile { name, .. } | ToClientMessage::ShowDiff { name, .. }
+        if !self.has_capability("editor") =>
+      {
+        Some(ToClientMessage::Log {
+          level: "warn".to_string(),
+          message: format!("This client can't edit files, so can't open {}", name),
+        })
+      }
+      ToClientMessage::Event { .. } if !self.has_capability("events") => None,
+      ToClientMessage::Prompt { .. } | ToClientMessage::PromptClosed { .. }
+        if !self.has_capability("prompts") =>
+      {
+        None
+      }
+      other => Some(other.clone()),
+    }
+  }
+
+  fn send_error(&self, message: &str, ctx: &mut ws::WebsocketContext<Self>) {
+    self
+      .send_to_client(
+        &ToClientMessage::Error {
+          message: message.to_string(),
+        },
+        ctx,
+      )
+      .unwrap_or_else(|e| log::error!("Error writing to client: {}", e));
+  }
+
+  fn close_with_reason(
+    &mut self,
+    code: ws::CloseCode,
+    reason: &str,
+    ctx: &mut ws::WebsocketContext<Self>,
+  ) {
+    log::warn!("Closing connection: {}", reason);
+    // a connection we closed deliberately shouldn't be resumed
+    if let Some(token) = self.session_token.take() {
+      self
+        .app_data
+        .world_ref
+        .write(|world| world.end_session(&token));
+    }
+    ctx.close(Some(ws::CloseReason {
+      code,
+      description: Some(reason.to_string()),
+    }));
+    ctx.stop();
+  }
+
   fn start_ping(&mut self, ctx: &mut ws::WebsocketContext<ChatSocket>) {
     ctx.run_interval(HEARTBEAT_INTERVAL, |_actor, ctx| {
       ctx.ping(&[]);
@@ -170,13 +674,63 @@ impl Handler<ToClientMessage> for ChatSocket {
 
   fn handle(&mut self, msg: ToClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
     self
-      .send_to_client(&msg, ctx)
+      .deliver(msg, ctx)
       .unwrap_or_else(|e| log::error!("Error writing to client: {}", e))
   }
 }
 
+impl Handler<Disconnect> for ChatSocket {
+  type Result = ();
+
+  fn handle(&mut self, msg: Disconnect, ctx: &mut ws::WebsocketContext<Self>) {
+    // the world has already forgotten this connection
+    self.self_id = None;
+    self.close_with_reason(ws::CloseCode::Restart, &msg.reason, ctx)
+  }
+}
+
+/// A live connection to a client, of whichever flavour.
+#[derive(Clone, PartialEq)]
+pub enum ClientConnection {
+  Web(Addr<ChatSocket>),
+  Telnet(Addr<TelnetSession>),
+  Bot(Addr<BotSession>),
+}
+
+impl ClientConnection {
+  pub fn do_send(&self, message: ToClientMessage) {
+    match self {
+      ClientConnection::Web(addr) => addr.do_send(message),
+      ClientConnection::Telnet(addr) => addr.do_send(message),
+      ClientConnection::Bot(addr) => addr.do_send(message),
+    }
+  }
+
+  pub fn disconnect(&self, reason: &str) {
+    let message = Disconnect {
+      reason: reason.to_string(),
+    };
+    match self {
+      ClientConnection::Web(addr) => addr.do_send(message),
+      ClientConnection::Telnet(addr) => addr.do_send(message),
+      ClientConnection::Bot(addr) => addr.do_send(message),
+    }
+  }
+}
+
+/// Tells a connection to hang up, e.g. because the world was rolled back.
+pub struct Disconnect {
+  pub reason: String,
+}
+
+impl ActixMessage for Disconnect {
+  type Result = ();
+}
+
 pub struct AppState {
   pub world_ref: WorldRef,
+  pub allowed_origins: HashSet<String>, // besides our own; see auth::origin_allowed
+  pub auth_providers: Arc<AuthProviders>, // shared with telnet
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -187,6 +741,13 @@ pub enum ChatRowContent {
 }
 
 impl ChatRowContent {
+  pub fn id(&self) -> &str {
+    match self {
+      ChatRowContent::TextContent { id, .. } => id,
+      ChatRowContent::HtmlContent { id, .. } => id,
+    }
+  }
+
   pub fn new(text: &str) -> ChatRowContent {
     return ChatRowContent::TextContent {
       id: Uuid::new_v4().to_string(),
@@ -194,21 +755,123 @@ impl ChatRowContent {
     };
   }
 
+  /// The same row, with any HTML rendered as plain text.
+  pub fn to_text(&self) -> ChatRowContent {
+    match self {
+      ChatRowContent::TextContent { .. } => self.clone(),
+      ChatRowContent::HtmlContent { id, html } => ChatRowContent::TextContent {
+        id: id.clone(),
+        text: html_to_text(html),
+      },
+    }
+  }
+
+  /// Sanitizes the HTML according to the server's policy, so this is safe
+  /// to use with HTML from arbitrary (user-written) Lua.
   pub fn new_html(html: &str) -> ChatRowContent {
+    ChatRowContent::new_trusted_html(&sanitize_html(html))
+  }
+
+  /// Only for HTML we trust completely: generated by system packages
+  /// or already sanitized.
+  pub fn new_trusted_html(html: &str) -> ChatRowContent {
     return ChatRowContent::HtmlContent {
       id: Uuid::new_v4().to_string(),
       html: html.to_string(),
     };
   }
+
+  /// The row as HTML, escaping it if it's text.
+  pub fn html(&self) -> String {
+    match self {
+      ChatRowContent::TextContent { text, .. } => text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;"),
+      ChatRowContent::HtmlContent { html, .. } => html.clone(),
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum ToClientMessage {
-  Tell { content: ChatRowContent },
-  Backlog { history: Vec<ChatRowContent> },
-  Log { level: String, message: String },
-  EditFile { name: String, content: String },
+  Hello {
+    version: u32,
+    capabilities: Vec<String>, // the subset of the client's we support
+  },
+  Error {
+    message: String,
+  },
+  Tell {
+    content: ChatRowContent,
+  },
+  Backlog {
+    history: Vec<ChatRowContent>,
+  },
+  // Older rows, to prepend to what the client has
+  History {
+    history: Vec<ChatRowContent>,
+    has_more: bool,
+  },
+  Log {
+    level: String,
+    message: String,
+  },
+  EditFile {
+    name: String,
+    content: String,
+  },
+  // Two revisions of a live package to compare; revision 0 is empty
+  ShowDiff {
+    name: String,
+    from: usize,
+    to: usize,
+    original: String,
+    content: String,
+  },
+  // Structured data for clients which understand it (e.g. bots) rather than HTML
+  Event {
+    name: String,
+    payload: SerializableValue,
+  },
+  AdminResponse {
+    result: serde_json::Value,
+  },
+  // Sent after Login or Resume; the token can be used to Resume if the connection drops
+  Session {
+    token: String,
+  },
+  // Resume didn't work (e.g. the session expired), so the client should Login
+  ResumeFailed {
+    message: String,
+  },
+  // Replaces a panel's whole value; nil removes the panel
+  Panel {
+    name: String,
+    value: SerializableValue,
+  },
+  // Changes to a panel whose value is a dict
+  PanelChanges {
+    name: String,
+    set: HashMap<String, SerializableValue>,
+    removed: Vec<String>,
+  },
+  // A question (from orisa.prompt) for the client to show until answered or closed
+  Prompt {
+    id: String,
+    spec: SerializableValue,
+  },
+  // The prompt was answered elsewhere, cancelled or timed out
+  PromptClosed {
+    id: String,
+  },
+  // Replacements for the input in a Complete request (which is echoed back)
+  Completions {
+    text: String,
+    cursor: usize,
+    completions: Vec<String>,
+  },
 }
 
 impl ActixMessage for ToClientMessage {
@@ -218,9 +881,16 @@ impl ActixMessage for ToClientMessage {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 enum ToServerMessage {
-  Login {
-    username: String,
-    user_type: String, // eg "user" or "group" or whatever -- will become $username/live.$user_type
+  // Must be the first message on a connection
+  Hello {
+    version: u32,
+    capabilities: Vec<String>,
+  },
+  // Logs in as the user from the session cookie; see auth.rs
+  Login {},
+  // Instead of Login, to continue a session whose connection dropped
+  Resume {
+    token: String,
   },
   Command {
     text: String,
@@ -234,6 +904,46 @@ enum ToServerMessage {
     name: String,
     content: String,
   },
+  LoadHistory {
+    before: String, // id of the oldest row the client has
+    limit: Option<usize>,
+  },
+  PromptResponse {
+    id: String,
+    answers: serde_json::Value,
+  },
+  CancelPrompt {
+    id: String,
+  },
+  // Asks for completions of the input; cursor is a character offset into text
+  Complete {
+    text: String,
+    cursor: usize,
+  },
+  Admin {
+    command: AdminCommand,
+  },
+}
+
+impl ToServerMessage {
+  /// For logging: messages can carry session tokens, prompt answers and the like,
+  /// which don't belong in the logs.
+  fn kind(&self) -> &'static str {
+    match self {
+      ToServerMessage::Hello { .. } => "Hello",
+      ToServerMessage::Login {} => "Login",
+      ToServerMessage::Resume { .. } => "Resume",
+      ToServerMessage::Command { .. } => "Command",
+      ToServerMessage::SendMessage { .. } => "SendMessage",
+      ToServerMessage::ReloadCode {} => "ReloadCode",
+      ToServerMessage::SaveFile { .. } => "SaveFile",
+      ToServerMessage::LoadHistory { .. } => "LoadHistory",
+      ToServerMessage::PromptResponse { .. } => "PromptResponse",
+      ToServerMessage::CancelPrompt { .. } => "CancelPrompt",
+      ToServerMessage::Complete { .. } => "Complete",
+      ToServerMessage::Admin { .. } => "Admin",
+    }
+  }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSocket {
@@ -243,9 +953,43 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatSocket {
       Ok(ws::Message::Text(text)) => {
         if let Err(e) = self.handle_message(&text, ctx) {
           log::error!("Failed handling message from user: {}", e);
+          self.send_error(&format!("Invalid message: {}", e), ctx);
+          self.throttle_invalid(ctx);
         }
       }
+      Ok(ws::Message::Binary(_)) => {
+        self.send_error("Binary messages aren't supported", ctx);
+        self.throttle_invalid(ctx);
+      }
+      Ok(ws::Message::Close(reason)) => {
+        ctx.close(reason);
+        ctx.stop();
+      }
+      Err(e) => {
+        log::error!("Websocket protocol error: {}", e);
+        ctx.stop();
+      }
       _ => (),
     }
   }
 }
+
+/// The strings in a list returned by a "complete" query, in order.
+fn completions_from(value: SerializableValue) -> Vec<String> {
+  let mut items: Vec<(i64, String)> = match value {
+    SerializableValue::Table(pairs) => pairs
+      .into_iter()
+      .filter_map(|pair| match pair {
+        (SerializableValue::Integer(i), SerializableValue::String(s)) => Some((i, s)),
+        _ => None,
+      })
+      .collect(),
+    _ => vec![],
+  };
+  items.sort_by_key(|(i, _)| *i);
+  items
+    .into_iter()
+    .map(|(_, s)| s)
+    .take(MAX_COMPLETIONS)
+    .collect()
+}
@@ -1,4 +1,4 @@
-use crate::repo::Repo;
+use crate::repo::{self, Repo};
 use crate::util::*;
 use core::convert::TryFrom;
 use git2;
@@ -12,10 +12,13 @@ use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 #[derive(Clone)]
 pub struct LuaHost {
   root: PathBuf,
   repo: Option<Repo>,
+  // Git work can happen without the world locked, so it takes turns here instead
+  git_lock: Arc<Mutex<()>>,
 }
 
 impl LuaHost {
@@ -159,6 +162,7 @@ impl LuaHost {
     Ok(LuaHost {
       root: canonical_root.clone(),
       repo,
+      git_lock: Arc::new(Mutex::new(())),
     })
   }
 
@@ -169,7 +173,38 @@ impl LuaHost {
     Ok(v)
   }
 
+  /// Writes a system package into the code directory and commits it.
+  pub fn commit_system_package(
+    &self,
+    reference: &PackageReference,
+    content: &str,
+    message: &str,
+    author: &git2::Signature,
+  ) -> Result<git2::Oid, git2::Error> {
+    if reference.package_root() != PackageReference::system_package_root() {
+      return Err(git2::Error::from_str(&format!(
+        "Package {} is not a system package",
+        reference
+      )));
+    }
+
+    let path = self.root.join(format!("{}.lua", reference.package()));
+    let _git = self.git_lock.lock().unwrap();
+    repo::commit_file(&path, content.as_bytes(), message, author)
+  }
+
+  pub fn push(&self) -> Result<String, git2::Error> {
+    let _git = self.git_lock.lock().unwrap();
+    match self.repo {
+      Some(ref repo) => repo.push(),
+      None => Err(git2::Error::from_str(
+        "Not configured with a remote for system code",
+      )),
+    }
+  }
+
   pub fn fetch(&self) -> Result<String, git2::Error> {
+    let _git = self.git_lock.lock().unwrap();
     self
       .repo
       .as_ref()
@@ -178,7 +213,7 @@ impl LuaHost {
   }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum SerializableValue {
   Nil,
@@ -243,6 +278,31 @@ impl<'lua> rlua::FromLua<'lua> for SerializableValue {
   }
 }
 
+// Unlike deserializing, this turns JSON arrays into Lua-style lists.
+impl From<serde_json::Value> for SerializableValue {
+  fn from(value: serde_json::Value) -> SerializableValue {
+    match value {
+      serde_json::Value::Null => SerializableValue::Nil,
+      serde_json::Value::Bool(b) => SerializableValue::Boolean(b),
+      serde_json::Value::Number(n) => match n.as_i64() {
+        Some(i) => SerializableValue::Integer(i),
+        None => SerializableValue::Number(n.as_f64().unwrap_or(std::f64::NAN)),
+      },
+      serde_json::Value::String(s) => SerializableValue::String(s),
+      serde_json::Value::Array(items) => SerializableValue::Table(
+        items
+          .into_iter()
+          .enumerate()
+          .map(|(i, item)| (SerializableValue::Integer(i as i64 + 1), item.into()))
+          .collect(),
+      ),
+      serde_json::Value::Object(fields) => {
+        SerializableValue::Dict(fields.into_iter().map(|(k, v)| (k, v.into())).collect())
+      }
+    }
+  }
+}
+
 impl<'lua> rlua::ToLua<'lua> for SerializableValue {
   fn to_lua(self, lua: rlua::Context<'lua>) -> rlua::Result<rlua::Value<'lua>> {
     match self {
@@ -1,25 +1,33 @@
+mod admin;
+mod auth;
+mod bot;
 mod chat;
 mod lua;
 mod object;
 mod repo;
+mod sanitize;
+mod telnet;
 mod util;
 mod world;
 
+use crate::auth::AuthProviders;
 use crate::chat::{AppState, ChatSocket};
 use crate::util::ResultAnyError;
-use crate::world::{World, WorldRef};
+use crate::world::rate_limit::RateLimits;
+use crate::world::{snapshots, World, WorldRef};
 use actix::clock::Duration;
 use actix::prelude::*;
+use actix_identity::Identity;
 use actix_web::middleware::Logger;
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web_actors::ws;
-use chrono::prelude::*;
 use futures::executor;
 use listenfd::ListenFd;
 use log::info;
+use std::collections::HashSet;
 use std::env;
-use std::fs::{copy, rename, File};
-use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 
 #[macro_use]
@@ -35,10 +43,18 @@ async fn index() -> impl Responder {
 async fn socket(
   req: HttpRequest,
   stream: web::Payload,
+  id: Identity,
   data: web::Data<AppState>,
-) -> impl Responder {
-  let res = ws::start(ChatSocket::new(data.clone()), &req, stream);
-  res
+) -> Result<HttpResponse, actix_web::Error> {
+  if !auth::origin_allowed(&req, &data.allowed_origins) {
+    return Ok(HttpResponse::Forbidden().finish());
+  }
+
+  // the user comes from the session cookie set by /api/login
+  match id.identity() {
+    Some(username) => ws::start(ChatSocket::new(data.clone(), username), &req, stream),
+    None => Ok(HttpResponse::Unauthorized().finish()),
+  }
 }
 
 fn main() -> Result<(), std::io::Error> {
@@ -68,27 +84,11 @@ fn main() -> Result<(), std::io::Error> {
   res
 }
 
-fn world_load_path() -> PathBuf {
-  let state_dir_env = env::var("ORISA_STATE_DIRECTORY").unwrap_or("state".to_string());
-  let state_dir = Path::new(&state_dir_env);
-  state_dir.join("world.json").to_path_buf()
-}
-
 fn save_world(world_ref: WorldRef) -> ResultAnyError<()> {
-  let state_dir_env = env::var("ORISA_STATE_DIRECTORY").unwrap_or("state".to_string());
-  let state_dir = Path::new(&state_dir_env);
-  let temp_path = state_dir.join("world-out.json");
-  let file = File::create(&temp_path)?;
-  world_ref.read(|w| w.save(file))?;
-
-  let now = Utc::now().to_rfc3339();
-  copy(
-    temp_path.clone(),
-    state_dir.join(format!("world-{}.json", now)),
-  )?;
-
-  let final_path = state_dir.join("world.json");
-  rename(temp_path, final_path)?;
+  world_ref.write(|w| {
+    w.flush_audit_log(true);
+    snapshots::save(w)
+  })?;
   Ok(())
 }
 
@@ -117,7 +117,15 @@ fn build_world() -> Result<(Arc<RwLock<Option<World>>>, WorldRef), std::io::Erro
     }
   };
 
-  let path = world_load_path();
+  let admins = env::var("ORISA_ADMINS")
+    .unwrap_or("".to_string())
+    .split(',')
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+    .collect::<HashSet<String>>();
+  log::info!("Admins: {:?}", admins);
+
+  let path = snapshots::current_path();
   let read = if path.exists() {
     Some(File::open(path).expect("Error opening world"))
   } else {
@@ -125,22 +133,67 @@ fn build_world() -> Result<(Arc<RwLock<Option<World>>>, WorldRef), std::io::Erro
   };
 
   Ok(
-    World::new(&arbiter, &Path::new(&code_dir_env), git_config, read).expect("error loading world"),
+    World::new(
+      &arbiter,
+      &Path::new(&code_dir_env),
+      git_config,
+      admins,
+      RateLimits::load(),
+      read,
+    )
+    .expect("error loading world"),
   )
 }
 
 async fn run_server(world_ref: WorldRef) -> Result<(), std::io::Error> {
+  let allowed_origins = env::var("ORISA_ALLOWED_ORIGINS")
+    .unwrap_or("".to_string())
+    .split(',')
+    .map(|s| s.trim().to_string())
+    .filter(|s| !s.is_empty())
+    .collect::<HashSet<String>>();
+
+  let session_key = auth::session_key();
+
+  let auth_providers = Arc::new(AuthProviders::configure(&session_key).await);
   let data = web::Data::new(AppState {
     world_ref: world_ref.clone(),
+    allowed_origins,
+    auth_providers: auth_providers.clone(),
   });
+  sanitize::load_policy();
+
+  // MUD clients are off unless asked for
+  match env::var("ORISA_TELNET_ADDRESS") {
+    Ok(telnet_address) => telnet::listen(world_ref.clone(), auth_providers, telnet_address).await?,
+    Err(_) => log::info!("Not listening for telnet connections"),
+  }
+
+  // likewise bots, whose tokens go over the connection in the clear
+  match env::var("ORISA_BOT_ADDRESS") {
+    Ok(bot_address) => bot::listen(world_ref.clone(), bot_address).await?,
+    Err(_) => log::info!("Not listening for bot connections"),
+  }
 
   let mut listenfd = ListenFd::from_env();
 
   let mut server = HttpServer::new(move || {
     App::new()
       .app_data(data.clone())
+      .wrap(auth::identity_service(&session_key))
       .wrap(Logger::default())
       .route("/", web::get().to(index))
+      .route("/api/login", web::post().to(auth::login))
+      .route("/api/login/{provider}", web::get().to(auth::start_login))
+      .route(
+        "/api/login/{provider}/callback",
+        web::get().to(auth::login_callback),
+      )
+      .route("/api/auth_providers", web::get().to(auth::providers))
+      .route("/api/logout", web::post().to(auth::logout))
+      .route("/api/me", web::get().to(auth::me))
+      .route("/api/telnet_token", web::post().to(auth::telnet_token))
+      .route("/api/audit", web::get().to(admin::audit_log))
       .route("/api/socket", web::get().to(socket))
   })
   .shutdown_timeout(1)
@@ -2,11 +2,64 @@ use crate::chat::{ChatRowContent, ToClientMessage};
 use crate::lua::*;
 use crate::object::executor::ExecutionState as S;
 use crate::object::types::*;
+use crate::world::audit::AuditAction;
+use crate::world::names::{Rank, Scope};
+use crate::world::query::{Filter, MAX_QUERY_RESULTS};
 use rlua;
 use rlua::ExternalResult;
 use rlua::ToLua;
 use std::collections::HashMap;
 
+fn subscribe(_lua_ctx: rlua::Context, (target, event): (Id, String)) -> rlua::Result<()> {
+  let id = S::get_id();
+  S::with_world_state_mut(|s| {
+    if !s.may_subscribe(id, target, &event) {
+      return Err(rlua::Error::external(format!(
+        "Can't subscribe to {} on {}: it's not in the same room and doesn't publish it publicly",
+        event, target
+      )));
+    }
+    Ok(s.subscribe(target, &event, id)?)
+  })
+}
+
+fn unsubscribe(_lua_ctx: rlua::Context, (target, event): (Id, String)) -> rlua::Result<bool> {
+  let id = S::get_id();
+  S::with_world_state_mut(|s| Ok(s.unsubscribe(target, &event, id)))
+}
+
+/// Sends `event` (as the message name) to everything subscribed to it on this
+/// object, returning how many there were.
+fn publish(
+  _lua_ctx: rlua::Context,
+  (event, payload): (String, SerializableValue),
+) -> rlua::Result<usize> {
+  let id = S::get_id();
+  let original_user = S::get_original_user();
+  S::with_world_mut(|w| {
+    let subscribers = w.get_state_mut().subscribers(id, &event);
+    for subscriber in subscribers.iter() {
+      w.send_message(Message {
+        target: *subscriber,
+        original_user,
+        immediate_sender: id,
+        name: event.clone(),
+        payload: payload.clone(),
+      });
+    }
+    Ok(subscribers.len())
+  })
+}
+
+fn query_objects(
+  _lua_ctx: rlua::Context,
+  (filter, limit): (SerializableValue, Option<usize>),
+) -> rlua::Result<Vec<Id>> {
+  let filter = Filter::parse(&filter).to_lua_err()?;
+  let limit = limit.unwrap_or(MAX_QUERY_RESULTS).min(MAX_QUERY_RESULTS);
+  Ok(S::with_world_state(|w| w.query_objects(&filter, limit)))
+}
+
 fn get_children(_lua_ctx: rlua::Context, object_id: Id) -> rlua::Result<Vec<Id>> {
   Ok(S::with_world_state(|w| {
     w.children(object_id).collect::<Vec<Id>>()
@@ -17,6 +70,43 @@ fn get_parent(_lua_ctx: rlua::Context, object_id: Id) -> rlua::Result<Option<Id>
   Ok(S::with_world_state(|w| w.parent(object_id))?)
 }
 
+/// Returns a list of `{id, name, rank}` for objects (in the scope, relative to
+/// this one) named like `text`, best first.
+fn find_objects(
+  _lua_ctx: rlua::Context,
+  (scope, text): (String, String),
+) -> rlua::Result<Vec<SerializableValue>> {
+  let scope: Scope = scope.parse().to_lua_err()?;
+  S::with_world_state(|w| {
+    let matches = w.find_objects(S::get_id(), scope, &text)?;
+    Ok(
+      matches
+        .into_iter()
+        .map(|m| {
+          let mut result = HashMap::new();
+          result.insert(
+            "id".to_string(),
+            SerializableValue::String(m.id.to_string()),
+          );
+          if let Ok(Some(name)) = w.get_attr(m.id, "name") {
+            result.insert("name".to_string(), name);
+          }
+          let rank = match m.rank {
+            Rank::Exact => "exact",
+            Rank::Prefix => "prefix",
+            Rank::Words => "words",
+          };
+          result.insert(
+            "rank".to_string(),
+            SerializableValue::String(rank.to_string()),
+          );
+          SerializableValue::Dict(result)
+        })
+        .collect(),
+    )
+  })
+}
+
 fn send(
   _lua_ctx: rlua::Context,
   (object_id, name, payload): (Id, String, SerializableValue),
@@ -75,31 +165,141 @@ fn query(
   })
 }
 
-fn send_user_tell_html(_lua_ctx: rlua::Context, message: String) -> rlua::Result<()> {
-  S::with_world_mut(|w| {
-    Ok(w.send_client_message(
-      S::get_id(),
-      ToClientMessage::Tell {
-        content: ChatRowContent::new_html(&message),
-      },
+/// HTML is sanitized so it can't attack other users' browsers, even from system code,
+/// since that often passes along what users typed. System code can send HTML it
+/// built itself unsanitized by asking for `trusted`.
+fn html_content(html: &str, trusted: bool) -> rlua::Result<ChatRowContent> {
+  if !trusted {
+    return Ok(ChatRowContent::new_html(html));
+  }
+
+  let kind = S::with_world_state(|s| s.kind(S::get_id()))?;
+  if kind.package_root() == PackageReference::system_package_root().to_string() {
+    Ok(ChatRowContent::new_trusted_html(html))
+  } else {
+    Err(rlua::Error::external(
+      "Only system code can send trusted HTML",
     ))
+  }
+}
+
+fn is_trusted(options: &Option<rlua::Table>) -> rlua::Result<bool> {
+  match options {
+    None => Ok(false),
+    Some(t) => Ok(t.get::<_, Option<bool>>("trusted")?.unwrap_or(false)),
+  }
+}
+
+/// How long durable tells wait for an offline user by default (a week).
+const DEFAULT_DURABLE_TELL_EXPIRY: u64 = 60 * 60 * 24 * 7;
+
+fn send_user_tell_html(
+  _lua_ctx: rlua::Context,
+  (message, options): (String, Option<rlua::Table>),
+) -> rlua::Result<()> {
+  let trusted = is_trusted(&options)?;
+  let (durable, expires_in) = match options {
+    None => (false, None),
+    Some(t) => (
+      t.get::<_, Option<bool>>("durable")?.unwrap_or(false),
+      t.get::<_, Option<u64>>("expires_in")?,
+    ),
+  };
+
+  let content = html_content(&message, trusted)?;
+  S::with_world_mut(|w| {
+    if durable {
+      Ok(w.send_durable_tell(
+        S::get_id(),
+        content,
+        expires_in.unwrap_or(DEFAULT_DURABLE_TELL_EXPIRY),
+      ))
+    } else {
+      Ok(w.send_tell(S::get_id(), content))
+    }
   })
 }
 
-fn send_user_backlog_html(_lua_ctx: rlua::Context, messages: Vec<String>) -> rlua::Result<()> {
+fn count_pending_tells(_lua_ctx: rlua::Context, id: Id) -> rlua::Result<usize> {
+  Ok(S::with_world_state(|s| {
+    s.username(id)
+      .map(|username| s.count_pending_tells(&username))
+      .unwrap_or(0)
+  }))
+}
+
+fn send_user_backlog_html(
+  _lua_ctx: rlua::Context,
+  (messages, options): (Vec<String>, Option<rlua::Table>),
+) -> rlua::Result<()> {
+  let trusted = is_trusted(&options)?;
+  let history = messages
+    .iter()
+    .map(|s| html_content(s, trusted))
+    .collect::<rlua::Result<Vec<_>>>()?;
   S::with_world_mut(|w| {
+    w.seed_history(S::get_id(), history);
+    Ok(())
+  })
+}
+
+fn send_user_event(
+  _lua_ctx: rlua::Context,
+  (name, payload): (String, SerializableValue),
+) -> rlua::Result<()> {
+  S::with_world_mut(|w| {
+    Ok(w.send_client_message(S::get_id(), ToClientMessage::Event { name, payload }))
+  })
+}
+
+/// How long users have to answer prompts by default, in seconds.
+const DEFAULT_PROMPT_TIMEOUT: u64 = 5 * 60;
+
+/// Longer timeouts are cut down to this (an hour).
+const MAX_PROMPT_TIMEOUT: u64 = 60 * 60;
+
+/// Asks `user` a question (see README for `spec`), returning the prompt's id.
+/// The answer comes back to this object as a `prompt_response` message.
+/// Only the user who caused this message, or a user in the same room as this
+/// object (or in this object), can be asked.
+fn prompt(_lua_ctx: rlua::Context, (user, spec): (Id, SerializableValue)) -> rlua::Result<String> {
+  let id = S::get_id();
+  let original_user = S::get_original_user();
+  let allowed = S::with_world_state(|s| -> rlua::Result<bool> {
+    if s.username(user).is_none() {
+      return Err(rlua::Error::external(format!("{} isn't a user", user)));
+    }
+    let room = s.parent(user)?;
     Ok(
-      w.send_client_message(
-        S::get_id(),
-        ToClientMessage::Backlog {
-          history: messages
-            .iter()
-            .map(|s| ChatRowContent::new_html(s))
-            .collect(),
-        },
-      ),
+      original_user == Some(user)
+        || room == Some(id)
+        || (room.is_some() && room == s.parent(id)?),
     )
-  })
+  })?;
+  if !allowed {
+    return Err(rlua::Error::external(format!(
+      "Can only prompt the user who sent this message or users in the same room, not {}",
+      user
+    )));
+  }
+
+  let timeout = match &spec {
+    SerializableValue::Dict(fields) => match fields.get("timeout") {
+      Some(SerializableValue::Integer(t)) if *t > 0 => *t as u64,
+      Some(SerializableValue::Number(t)) if *t > 0.0 => *t as u64,
+      _ => DEFAULT_PROMPT_TIMEOUT,
+    },
+    _ => DEFAULT_PROMPT_TIMEOUT,
+  };
+  let timeout = timeout.min(MAX_PROMPT_TIMEOUT);
+  S::with_world_mut(|w| Ok(w.prompt(id, user, spec, timeout)))
+}
+
+fn set_panel(
+  _lua_ctx: rlua::Context,
+  (name, value): (String, SerializableValue),
+) -> rlua::Result<()> {
+  S::with_world_mut(|w| Ok(w.set_panel(S::get_id(), &name, value)))
 }
 
 fn send_user_edit_file(
@@ -117,6 +317,20 @@ fn send_user_edit_file(
   })
 }
 
+fn is_connected(_lua_ctx: rlua::Context, id: Id) -> rlua::Result<bool> {
+  Ok(S::with_world(|w| w.is_connected(id)))
+}
+
+fn get_idle_seconds(_lua_ctx: rlua::Context, id: Id) -> rlua::Result<Option<u64>> {
+  Ok(S::with_world(|w| {
+    w.get_presence().idle_time(id).map(|idle| idle.as_secs())
+  }))
+}
+
+fn list_online(_lua_ctx: rlua::Context, _: ()) -> rlua::Result<Vec<Id>> {
+  Ok(S::with_world(|w| w.get_presence().online().collect()))
+}
+
 fn get_username(_lua_ctx: rlua::Context, id: Id) -> rlua::Result<Option<String>> {
   Ok(S::with_world_state(|w| w.username(id)))
 }
@@ -159,9 +373,16 @@ fn set_attr(
     // Someday we might relax this given capabilities and probably containment (for concurrency)
     Err(rlua::Error::external("Can only set your own attrs."))
   } else {
+    let context = S::audit_context();
     Ok(
-      S::with_world_state_mut(|s| Ok(s.set_attr(id, key.clone(), value)?))?
-        .unwrap_or(SerializableValue::Nil),
+      S::with_world_mut(|w| {
+        let old = w.get_state_mut().set_attr(id, key.clone(), value.clone())?;
+        if old.as_ref() != Some(&value) {
+          w.audit(context, AuditAction::SetAttr { object: id, key });
+        }
+        Ok(old)
+      })?
+      .unwrap_or(SerializableValue::Nil),
     )
   }
 }
@@ -201,19 +422,45 @@ fn get_package_content(_lua_ctx: rlua::Context, name: String) -> rlua::Result<Op
 
 fn send_save_package_content(
   _lua_ctx: rlua::Context,
-  (name, content): (String, String),
-) -> rlua::Result<()> {
+  (name, content, message): (String, String, Option<String>),
+) -> rlua::Result<Option<usize>> {
+  save_live_package(name, content, message)
+}
+
+/// Saves one of the running object's user's live packages as a new revision.
+fn save_live_package(
+  name: String,
+  content: String,
+  message: Option<String>,
+) -> rlua::Result<Option<usize>> {
   let destination_package = PackageReference::new(&name).to_lua_err()?;
   let id = S::get_id();
 
   if Some(destination_package.user().to_string()) == S::with_world_state(|w| w.username(id))
     && destination_package.is_live_package()
   {
-    S::with_world_state_mut(|s| {
-      Ok(s.set_live_package_content(PackageReference::new(&name).to_lua_err()?, content))
-    })?;
-    // TODO: reload only this package
-    S::with_world_mut(|w| Ok(w.reload_code()))
+    let context = S::audit_context();
+    let author = S::get_original_user().unwrap_or(id);
+    S::with_world_mut(|w| {
+      let bytes = content.len();
+      let revision = w.get_state_mut().set_live_package_content(
+        destination_package,
+        content,
+        Some(author),
+        message,
+      )?;
+      w.audit(
+        context,
+        AuditAction::SavePackage {
+          package: name,
+          bytes,
+          revision,
+        },
+      );
+      // TODO: reload only this package
+      w.reload_code();
+      Ok(revision)
+    })
   } else {
     Err(rlua::Error::external(
       "You can only write to live packages named $username/live.something",
@@ -221,6 +468,140 @@ fn send_save_package_content(
   }
 }
 
+/// Only the package's user (as the running object's user or the original user)
+/// and admins can see its history, since old revisions may have code that's
+/// since been taken out.
+fn check_history_access(package: &PackageReference) -> rlua::Result<()> {
+  let id = S::get_id();
+  let user = S::get_original_user();
+  let allowed = S::with_world(|w| {
+    let state = w.get_state();
+    [Some(id), user]
+      .iter()
+      .filter_map(|u| u.and_then(|u| state.username(u)))
+      .any(|username| username == package.user())
+      || user.map_or(false, |u| w.is_admin(u))
+  });
+  if allowed {
+    Ok(())
+  } else {
+    Err(rlua::Error::external(format!(
+      "Only {} can see the history of {}",
+      package.user(),
+      package
+    )))
+  }
+}
+
+fn get_package_history(
+  _lua_ctx: rlua::Context,
+  name: String,
+) -> rlua::Result<Vec<SerializableValue>> {
+  let package = PackageReference::new(&name).to_lua_err()?;
+  check_history_access(&package)?;
+  S::with_world_state(|s| {
+    let now = s.get_current_time();
+    let revisions = match s.package_history(&package) {
+      Some(history) => history.revisions(),
+      None => return Ok(vec![]),
+    };
+    Ok(
+      revisions
+        .map(|r| {
+          let mut info = HashMap::new();
+          info.insert(
+            "revision".to_string(),
+            SerializableValue::Integer(r.number as i64),
+          );
+          if let Some(author) = r.author {
+            info.insert(
+              "author".to_string(),
+              SerializableValue::String(author.to_string()),
+            );
+          }
+          info.insert(
+            "age".to_string(),
+            SerializableValue::Integer((now - r.time) as i64),
+          );
+          info.insert(
+            "saved_at".to_string(),
+            SerializableValue::String(r.saved_at.clone()),
+          );
+          if let Some(message) = &r.message {
+            info.insert(
+              "message".to_string(),
+              SerializableValue::String(message.clone()),
+            );
+          }
+          info.insert(
+            "bytes".to_string(),
+            SerializableValue::Integer(r.content.len() as i64),
+          );
+          SerializableValue::Dict(info)
+        })
+        .collect(),
+    )
+  })
+}
+
+fn package_revision(name: &str, revision: usize) -> rlua::Result<String> {
+  let package = PackageReference::new(name).to_lua_err()?;
+  S::with_world_state(|s| {
+    s.package_history(&package)
+      .and_then(|h| h.get(revision))
+      .map(|r| r.content.clone())
+      .ok_or_else(|| rlua::Error::external(format!("{} has no revision {}", name, revision)))
+  })
+}
+
+/// Saves an earlier revision's code as a new revision, so the revert can itself be undone.
+fn revert_package(
+  _lua_ctx: rlua::Context,
+  (name, revision, message): (String, usize, Option<String>),
+) -> rlua::Result<Option<usize>> {
+  check_history_access(&PackageReference::new(&name).to_lua_err()?)?;
+  let content = package_revision(&name, revision)?;
+  let message = message.unwrap_or_else(|| format!("Revert to revision {}", revision));
+  save_live_package(name, content, Some(message))
+}
+
+/// Shows the user the changes between two revisions (by default, the latest
+/// and the one before it).
+fn send_user_package_diff(
+  _lua_ctx: rlua::Context,
+  (name, from, to): (String, Option<usize>, Option<usize>),
+) -> rlua::Result<()> {
+  let package = PackageReference::new(&name).to_lua_err()?;
+  check_history_access(&package)?;
+  let latest = S::with_world_state(|s| {
+    s.package_history(&package)
+      .and_then(|h| h.latest())
+      .map(|r| r.number)
+  })
+  .ok_or_else(|| rlua::Error::external(format!("{} has no history", name)))?;
+  let to = to.unwrap_or(latest);
+  let from = from.unwrap_or(to.saturating_sub(1));
+
+  let original = if from == 0 {
+    String::new()
+  } else {
+    package_revision(&name, from)?
+  };
+  let content = package_revision(&name, to)?;
+  S::with_world_mut(|w| {
+    Ok(w.send_client_message(
+      S::get_id(),
+      ToClientMessage::ShowDiff {
+        name,
+        from,
+        to,
+        original,
+        content,
+      },
+    ))
+  })
+}
+
 // This is a bit of a special case.
 // We allow creation of an object immediately even though this has side effects
 // visible in the rest of the world. Practically, though, since we create it
@@ -230,9 +611,24 @@ fn create_object(
   _lua_ctx: rlua::Context,
   (parent, kind, created_payload): (Option<Id>, ObjectKind, SerializableValue),
 ) -> rlua::Result<Id> {
+  // objects created by timers and the like belong to whoever owns the creator
+  let creator = S::get_id();
+  let owner = match S::get_original_user() {
+    Some(user) => Some(user),
+    None => S::with_world_state(|s| s.owner(creator))?,
+  };
+  let context = S::audit_context();
   S::with_world_mut(|w| {
-    let id = w.get_state_mut().create_object(kind);
+    let id = w.get_state_mut().create_object(kind.clone(), owner)?;
     w.get_state_mut().move_object(id, parent)?;
+    w.audit(
+      context,
+      AuditAction::CreateObject {
+        object: id,
+        kind,
+        owner,
+      },
+    );
     w.send_message(Message {
       target: id,
       original_user: S::get_original_user(),
@@ -244,6 +640,26 @@ fn create_object(
   })
 }
 
+fn get_owner(_lua_ctx: rlua::Context, id: Id) -> rlua::Result<Option<Id>> {
+  Ok(S::with_world_state(|s| s.owner(id))?)
+}
+
+/// Only the owner (as the original user) can give an object away, and only to
+/// another user; disowning objects is left to admins (see `SetOwner`).
+fn set_owner(_lua_ctx: rlua::Context, (id, owner): (Id, Id)) -> rlua::Result<()> {
+  let user = S::get_original_user();
+  S::with_world_state_mut(|s| {
+    let current = s.owner(id)?;
+    if current.is_none() || current != user {
+      return Err(rlua::Error::external(format!(
+        "Only the owner of {} can give it away",
+        id
+      )));
+    }
+    Ok(s.set_owner(id, Some(owner))?)
+  })
+}
+
 fn get_all_users(_lua_ctx: rlua::Context, _: ()) -> rlua::Result<SerializableValue> {
   S::with_world_state(|w| {
     Ok(SerializableValue::Dict(
@@ -256,11 +672,7 @@ fn get_all_users(_lua_ctx: rlua::Context, _: ()) -> rlua::Result<SerializableVal
 }
 
 fn find_room(a: Id) -> rlua::Result<Id> {
-  let parent = S::with_world_state(|w| w.parent(a))?;
-  match parent {
-    None => Ok(a),
-    Some(p) => find_room(p),
-  }
+  Ok(S::with_world_state(|w| w.room_of(a))?)
 }
 
 fn shares_room(a: Id, b: Id) -> rlua::Result<bool> {
@@ -296,9 +708,17 @@ fn move_object(_lua_ctx: rlua::Context, (child, new_parent): (Id, Option<Id>)) -
   let payload = SerializableValue::Dict(info);
   let original_user = S::get_original_user();
   let id = S::get_id();
+  let context = S::audit_context();
 
   S::with_world_mut(|w| {
     w.get_state_mut().move_object(child, new_parent)?;
+    w.audit(
+      context,
+      AuditAction::MoveObject {
+        object: child,
+        new_parent,
+      },
+    );
     w.send_message(Message {
       target: child,
       original_user: original_user,
@@ -361,7 +781,33 @@ fn print_override<'lua>(
 
 fn set_delay(
   _lua_ctx: rlua::Context,
-  (name, delay, message_name, payload): (Option<String>, f64, String, SerializableValue),
+  (name, delay, message_name, payload, overdue): (
+    Option<String>,
+    f64,
+    String,
+    SerializableValue,
+    Option<OverduePolicy>,
+  ),
+) -> rlua::Result<String> {
+  schedule(None, name, delay, message_name, payload, overdue)
+}
+
+fn send_later(
+  _lua_ctx: rlua::Context,
+  (target, delay, message_name, payload): (Id, f64, String, SerializableValue),
+) -> rlua::Result<String> {
+  schedule(Some(target), None, delay, message_name, payload, None)
+}
+
+// Timers always live on the object which set them (so it can list and clear them),
+// even if they are delivered somewhere else.
+fn schedule(
+  target: Option<Id>,
+  name: Option<String>,
+  delay: f64,
+  message_name: String,
+  payload: SerializableValue,
+  overdue: Option<OverduePolicy>,
 ) -> rlua::Result<String> {
   let id = S::get_id();
   let original_user = S::get_original_user();
@@ -369,6 +815,10 @@ fn set_delay(
     if delay < 1.0 {
       return Err(rlua::Error::external("Delay expected to be > 1 second"));
     }
+    if let Some(target) = target {
+      // make sure the target exists now rather than failing when it fires
+      s.get_state().kind(target)?;
+    }
     let name = name.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
     let now = s.get_state().get_current_time();
 
@@ -381,6 +831,8 @@ fn set_delay(
         original_user,
         message_name,
         payload,
+        overdue: overdue.unwrap_or_default(),
+        target,
       },
     )?;
     Ok(name)
@@ -395,6 +847,52 @@ fn clear_delay(_lua_ctx: rlua::Context, name: String) -> rlua::Result<String> {
   })
 }
 
+fn delay_info(name: &str, timer: &Timer, now: GameTime, id: Id) -> SerializableValue {
+  let mut info = HashMap::new();
+  info.insert(
+    "name".to_string(),
+    SerializableValue::String(name.to_string()),
+  );
+  info.insert(
+    "remaining".to_string(),
+    SerializableValue::Integer((timer.target_time - now) as i64),
+  );
+  info.insert(
+    "message_name".to_string(),
+    SerializableValue::String(timer.message_name.clone()),
+  );
+  info.insert(
+    "target".to_string(),
+    SerializableValue::String(timer.target.unwrap_or(id).to_string()),
+  );
+  info.insert("payload".to_string(), timer.payload.clone());
+  SerializableValue::Dict(info)
+}
+
+fn list_delays(_lua_ctx: rlua::Context, _: ()) -> rlua::Result<SerializableValue> {
+  let id = S::get_id();
+  S::with_world_state(|s| {
+    let now = s.get_current_time();
+    Ok(SerializableValue::Dict(
+      s.list_timers(id)?
+        .map(|(name, timer)| (name.to_string(), delay_info(name, timer, now, id)))
+        .collect(),
+    ))
+  })
+}
+
+fn get_delay(_lua_ctx: rlua::Context, name: String) -> rlua::Result<SerializableValue> {
+  let id = S::get_id();
+  S::with_world_state(|s| {
+    let now = s.get_current_time();
+    Ok(
+      s.get_timer(id, &name)?
+        .map(|timer| delay_info(&name, timer, now, id))
+        .unwrap_or(SerializableValue::Nil),
+    )
+  })
+}
+
 // We currently load packages in 2 flavours:
 // * system.foo, which loads "foo.lua" from the filesystem.
 // * user/live.foo, which loads the local (in-memory) package from the world.
@@ -460,10 +958,17 @@ pub(super) fn register_api(lua_ctx: rlua::Context) -> rlua::Result<()> {
     "send_user_tell_html",
     lua_ctx.create_function(send_user_tell_html)?,
   )?;
+  orisa.set(
+    "count_pending_tells",
+    lua_ctx.create_function(count_pending_tells)?,
+  )?;
   orisa.set(
     "send_user_backlog_html",
     lua_ctx.create_function(send_user_backlog_html)?,
   )?;
+  orisa.set("send_user_event", lua_ctx.create_function(send_user_event)?)?;
+  orisa.set("set_panel", lua_ctx.create_function(set_panel)?)?;
+  orisa.set("prompt", lua_ctx.create_function(prompt)?)?;
   orisa.set(
     "send_user_edit_file",
     lua_ctx.create_function(send_user_edit_file)?,
@@ -472,8 +977,19 @@ pub(super) fn register_api(lua_ctx: rlua::Context) -> rlua::Result<()> {
 
   orisa.set("get_children", lua_ctx.create_function(get_children)?)?;
   orisa.set("get_parent", lua_ctx.create_function(get_parent)?)?;
+  orisa.set("find_objects", lua_ctx.create_function(find_objects)?)?;
+  orisa.set("query_objects", lua_ctx.create_function(query_objects)?)?;
+  orisa.set("subscribe", lua_ctx.create_function(subscribe)?)?;
+  orisa.set("unsubscribe", lua_ctx.create_function(unsubscribe)?)?;
+  orisa.set("publish", lua_ctx.create_function(publish)?)?;
   orisa.set("get_all_users", lua_ctx.create_function(get_all_users)?)?;
   orisa.set("get_username", lua_ctx.create_function(get_username)?)?;
+  orisa.set("is_connected", lua_ctx.create_function(is_connected)?)?;
+  orisa.set(
+    "get_idle_seconds",
+    lua_ctx.create_function(get_idle_seconds)?,
+  )?;
+  orisa.set("list_online", lua_ctx.create_function(list_online)?)?;
   orisa.set("get_kind", lua_ctx.create_function(get_kind)?)?;
   orisa.set("set_state", lua_ctx.create_function(set_state)?)?;
   orisa.set("get_state", lua_ctx.create_function(get_state)?)?;
@@ -489,11 +1005,25 @@ pub(super) fn register_api(lua_ctx: rlua::Context) -> rlua::Result<()> {
     "send_save_package_content",
     lua_ctx.create_function(send_save_package_content)?,
   )?;
+  orisa.set(
+    "get_package_history",
+    lua_ctx.create_function(get_package_history)?,
+  )?;
+  orisa.set("revert_package", lua_ctx.create_function(revert_package)?)?;
+  orisa.set(
+    "send_user_package_diff",
+    lua_ctx.create_function(send_user_package_diff)?,
+  )?;
 
   orisa.set("create_object", lua_ctx.create_function(create_object)?)?;
+  orisa.set("get_owner", lua_ctx.create_function(get_owner)?)?;
+  orisa.set("set_owner", lua_ctx.create_function(set_owner)?)?;
 
   orisa.set("set_delay", lua_ctx.create_function(set_delay)?)?;
   orisa.set("clear_delay", lua_ctx.create_function(clear_delay)?)?;
+  orisa.set("send_later", lua_ctx.create_function(send_later)?)?;
+  orisa.set("list_delays", lua_ctx.create_function(list_delays)?)?;
+  orisa.set("get_delay", lua_ctx.create_function(get_delay)?)?;
 
   globals.set("orisa", orisa)?;
 
@@ -2,11 +2,16 @@ use crate::lua::{LuaHost, PackageReference, SerializableValue};
 use crate::object::api;
 use crate::object::types::Message;
 use crate::world::actor::WorldActor;
+use crate::world::audit::AuditContext;
 use crate::world::state::State as WorldState;
 use crate::world::{Id, World, WorldRef};
 use rlua;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How often (in Lua VM instructions) budgeted code checks the clock.
+const BUDGET_CHECK_INSTRUCTIONS: u32 = 1000;
 
 #[derive(Clone)]
 pub struct ObjectExecutor {
@@ -58,6 +63,40 @@ impl ObjectExecutor {
     })
   }
 
+  /// Like running main as a query, but fails if it takes longer than `budget`.
+  pub fn run_query_with_budget<'a>(
+    &self,
+    actor: &mut WorldActor,
+    message: &'a Message,
+    budget: Duration,
+  ) -> rlua::Result<SerializableValue> {
+    let deadline = Instant::now() + budget;
+    if let Ok(ref lua_state) = self.body.borrow().lua_state {
+      lua_state.set_hook(
+        rlua::HookTriggers {
+          on_calls: false,
+          on_returns: false,
+          every_line: false,
+          every_nth_instruction: Some(BUDGET_CHECK_INSTRUCTIONS),
+        },
+        move |_lua_ctx, _debug| {
+          if Instant::now() > deadline {
+            Err(rlua::Error::external("Ran out of time"))
+          } else {
+            Ok(())
+          }
+        },
+      );
+    }
+
+    let result = self.run_main(actor, message, true);
+
+    if let Ok(ref lua_state) = self.body.borrow().lua_state {
+      lua_state.remove_hook();
+    }
+    result
+  }
+
   pub fn run_for_object<'a, F, T>(
     &self,
     actor: &mut WorldActor,
@@ -186,6 +225,16 @@ impl<'a> ExecutionState<'a> {
   pub(super) fn get_original_user() -> Option<Id> {
     Self::with_state(|s| s.current_message.original_user)
   }
+
+  /// Who to blame in the audit log for changes made by the running code.
+  pub(super) fn audit_context() -> AuditContext {
+    Self::with_state(|s| AuditContext {
+      original_user: s.current_message.original_user,
+      immediate_sender: Some(s.current_message.immediate_sender),
+      object: Some(s.current_message.target),
+      message: Some(s.current_message.name.clone()),
+    })
+  }
 }
 
 scoped_thread_local! {static EXECUTION_STATE: RefCell<ExecutionState>}
@@ -1,5 +1,5 @@
 use crate::lua::{PackageReference, SerializableValue};
-use core::ops::Add;
+use core::ops::{Add, Sub};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
@@ -53,7 +53,7 @@ pub struct Message {
   pub name: String,
   pub payload: SerializableValue,
 }
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Hash, Eq, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, PartialOrd, Ord, Clone, Copy, Hash, Eq, Deserialize, Serialize)]
 pub struct GameTime(u64);
 
 impl<'lua> rlua::ToLua<'lua> for GameTime {
@@ -85,16 +85,101 @@ impl Add<u64> for GameTime {
   }
 }
 
+impl Sub<GameTime> for GameTime {
+  type Output = u64;
+  fn sub(self, rhs: GameTime) -> u64 {
+    self.0.saturating_sub(rhs.0)
+  }
+}
+
 impl Default for GameTime {
   fn default() -> Self {
     return GameTime(0);
   }
 }
 
+/// What to do with a timer whose target time has already passed by the time
+/// we get around to checking it (e.g. one restored from a save).
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverduePolicy {
+  /// Deliver it anyway, as if it were on time.
+  FireLate,
+  /// Deliver it anyway, with the payload wrapped as `{payload = ..., late_by = ...}`
+  /// (`late_by` in seconds).
+  Annotate,
+  /// Like `Annotate`, but overdue timers on the same object with the same target and
+  /// message name are delivered once (the latest one), with a `coalesced` count.
+  Coalesce,
+  /// Don't deliver it; instead send `delay_dropped` to the object.
+  Drop,
+}
+
+impl Default for OverduePolicy {
+  fn default() -> Self {
+    OverduePolicy::FireLate
+  }
+}
+
+impl<'lua> rlua::FromLua<'lua> for OverduePolicy {
+  fn from_lua(
+    value: rlua::Value<'lua>,
+    _lua_ctx: rlua::Context<'lua>,
+  ) -> rlua::Result<OverduePolicy> {
+    if let rlua::Value::String(s) = value {
+      match s.to_str()? {
+        "fire_late" => Ok(OverduePolicy::FireLate),
+        "annotate" => Ok(OverduePolicy::Annotate),
+        "coalesce" => Ok(OverduePolicy::Coalesce),
+        "drop" => Ok(OverduePolicy::Drop),
+        other => Err(rlua::Error::external(format!(
+          "Unknown overdue policy {}; expected fire_late, annotate, coalesce or drop",
+          other
+        ))),
+      }
+    } else {
+      Err(rlua::Error::external(
+        "Expected a string for an overdue policy",
+      ))
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Timer {
   pub target_time: GameTime,
   pub original_user: Option<Id>,
   pub message_name: String,
   pub payload: SerializableValue,
+
+  #[serde(default)]
+  pub overdue: OverduePolicy,
+
+  /// Who receives the message; None means the object which set the timer.
+  #[serde(default)]
+  pub target: Option<Id>,
+}
+
+/// A question put to a user by an object, waiting for their answer.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingPrompt {
+  pub user: Id,
+  pub owner: Id,               // the object which asked, and hears the response
+  pub spec: SerializableValue, // resent if the user reconnects
+  pub expires_at: GameTime,
+}
+
+/// An object listening for an event published by another (the key it's stored under).
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Subscription {
+  pub event: String,
+  pub subscriber: Id,
+}
+
+/// A tell we couldn't deliver because the user wasn't connected,
+/// held until they next log in (or it expires).
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingTell {
+  pub html: String, // already sanitized
+  pub expires_at: GameTime,
 }
@@ -1,4 +1,5 @@
 use git2;
+use std::fs;
 use std::path::{Path, PathBuf};
 
 #[derive(Clone)]
@@ -19,40 +20,57 @@ impl Repo {
     }
   }
 
+  /// Fast-forwards our branch to the remote's. Commits made here (e.g. promoted
+  /// packages which weren't pushed) are never discarded: if the branches have
+  /// diverged, this fails and someone has to merge them by hand.
   pub fn pull_latest(&self) -> Result<String, git2::Error> {
     let repo = git2::Repository::open(&self.root)?;
     let mut remote = repo.find_remote(&self.remote_name)?;
-    let mut callbacks = git2::RemoteCallbacks::new();
-    let mut returned_ssh = false;
-    callbacks.sideband_progress(|msg| {
-      log::info!("Git progress: {}", String::from_utf8_lossy(msg));
-      return true;
-    });
-    callbacks.credentials(|_url, username, _types| {
-      if returned_ssh {
-        Err(git2::Error::from_str("no more users"))
-      } else {
-        returned_ssh = true;
-        git2::Cred::ssh_key_from_agent(username.unwrap_or("git"))
-      }
-    });
     let mut options = git2::FetchOptions::new();
-    options.remote_callbacks(callbacks);
+    options.remote_callbacks(remote_callbacks());
     remote.fetch(&[&self.branch_name], Some(&mut options), None)?;
     let mut branch = repo.find_branch(&self.branch_name, git2::BranchType::Local)?;
     let commit = branch.upstream()?.get().peel_to_commit()?;
+    let local = branch.get().peel_to_commit()?.id();
 
     let description = format!("{} ({})", commit.id(), commit.summary().unwrap_or(""));
 
-    if commit.id() == branch.get().peel_to_commit()?.id() {
+    if commit.id() == local || repo.graph_descendant_of(local, commit.id())? {
       Ok(format!("Already at {}", description))
-    } else {
+    } else if repo.graph_descendant_of(commit.id(), local)? {
       self.move_to(&mut branch, &repo, &commit)?;
 
       Ok(format!("Updated to {}", description))
+    } else {
+      Err(git2::Error::from_str(&format!(
+        "{} has commits which aren't on {}/{}; merge them before pulling {}",
+        self.branch_name, self.remote_name, self.branch_name, description
+      )))
     }
   }
 
+  /// Pushes our branch to the remote we pull from.
+  pub fn push(&self) -> Result<String, git2::Error> {
+    let repo = git2::Repository::open(&self.root)?;
+    let mut remote = repo.find_remote(&self.remote_name)?;
+    let mut callbacks = remote_callbacks();
+    callbacks.push_update_reference(|refname, status| match status {
+      None => Ok(()),
+      Some(status) => Err(git2::Error::from_str(&format!(
+        "Push of {} rejected: {}",
+        refname, status
+      ))),
+    });
+    let mut options = git2::PushOptions::new();
+    options.remote_callbacks(callbacks);
+    let refspec = format!("refs/heads/{0}:refs/heads/{0}", self.branch_name);
+    remote.push(&[refspec.as_str()], Some(&mut options))?;
+    Ok(format!(
+      "Pushed to {}/{}",
+      self.remote_name, self.branch_name
+    ))
+  }
+
   pub fn move_to(
     &self,
     branch: &mut git2::Branch,
@@ -75,3 +93,61 @@ impl Repo {
     Ok(())
   }
 }
+
+fn remote_callbacks<'a>() -> git2::RemoteCallbacks<'a> {
+  let mut callbacks = git2::RemoteCallbacks::new();
+  let mut returned_ssh = false;
+  callbacks.sideband_progress(|msg| {
+    log::info!("Git progress: {}", String::from_utf8_lossy(msg));
+    return true;
+  });
+  callbacks.credentials(move |_url, username, _types| {
+    if returned_ssh {
+      Err(git2::Error::from_str("no more users"))
+    } else {
+      returned_ssh = true;
+      git2::Cred::ssh_key_from_agent(username.unwrap_or("git"))
+    }
+  });
+  callbacks
+}
+
+/// Writes a file in the repository containing `path` and commits it on the current
+/// branch (along with anything else already staged).
+pub fn commit_file(
+  path: &Path,
+  content: &[u8],
+  message: &str,
+  author: &git2::Signature,
+) -> Result<git2::Oid, git2::Error> {
+  let repo = git2::Repository::discover(path.parent().unwrap_or(path))?;
+  let workdir = repo
+    .workdir()
+    .and_then(|w| w.canonicalize().ok())
+    .ok_or_else(|| git2::Error::from_str("The repository has no working directory"))?;
+  let relative = path
+    .strip_prefix(&workdir)
+    .map_err(|_| git2::Error::from_str("The file is outside the repository"))?;
+
+  fs::write(path, content).map_err(|e| git2::Error::from_str(&e.to_string()))?;
+  let mut index = repo.index()?;
+  index.add_path(relative)?;
+  index.write()?;
+  let tree = repo.find_tree(index.write_tree()?)?;
+
+  // an empty repository has no commits to build on
+  let parents = match repo.head() {
+    Ok(head) => vec![head.peel_to_commit()?],
+    Err(_) => vec![],
+  };
+  // the committer is whoever the repository is configured for, if anyone
+  let committer = repo.signature().unwrap_or_else(|_| author.to_owned());
+  repo.commit(
+    Some("HEAD"),
+    author,
+    &committer,
+    message,
+    &tree,
+    &parents.iter().collect::<Vec<&git2::Commit>>(),
+  )
+}
@@ -0,0 +1,125 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::File;
+
+/// Which HTML we let through to browsers from (untrusted) Lua code.
+/// Loaded from the JSON file named by `ORISA_HTML_POLICY` if set; any
+/// missing fields fall back to the defaults below.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct HtmlPolicy {
+  tags: HashSet<String>,
+  /// Attributes allowed on any permitted tag.
+  generic_attributes: HashSet<String>,
+  /// Attributes allowed on specific tags.
+  tag_attributes: HashMap<String, HashSet<String>>,
+  /// Schemes allowed in URLs (href, src, etc.)
+  url_schemes: HashSet<String>,
+}
+
+fn words(items: &str) -> HashSet<String> {
+  items.split_whitespace().map(|s| s.to_string()).collect()
+}
+
+impl Default for HtmlPolicy {
+  fn default() -> HtmlPolicy {
+    let mut tag_attributes = HashMap::new();
+    tag_attributes.insert("a".to_string(), words("href"));
+    tag_attributes.insert("img".to_string(), words("src alt width height"));
+    tag_attributes.insert("td".to_string(), words("colspan rowspan"));
+    tag_attributes.insert("th".to_string(), words("colspan rowspan"));
+
+    HtmlPolicy {
+      tags: words(
+        "a b blockquote br code div em h1 h2 h3 h4 hr i img li ol p pre s small span strong \
+         sub sup table tbody td th thead tr u ul",
+      ),
+      generic_attributes: words("class title"),
+      tag_attributes,
+      url_schemes: words("http https mailto"),
+    }
+  }
+}
+
+impl HtmlPolicy {
+  fn load() -> HtmlPolicy {
+    match env::var("ORISA_HTML_POLICY") {
+      Err(_) => HtmlPolicy::default(),
+      Ok(path) => {
+        let policy = File::open(&path)
+          .map_err(|e| e.to_string())
+          .and_then(|f| serde_json::from_reader(f).map_err(|e| e.to_string()));
+        match policy.and_then(|p: HtmlPolicy| p.validate().map(|_| p)) {
+          Ok(policy) => {
+            log::info!("Loaded HTML policy from {}", path);
+            policy
+          }
+          Err(e) => {
+            // fail closed rather than open: the default is the conservative choice
+            log::error!(
+              "Unable to load HTML policy from {}: {}; using default",
+              path,
+              e
+            );
+            HtmlPolicy::default()
+          }
+        }
+      }
+    }
+  }
+
+  /// Catches policies ammonia would panic on when cleaning.
+  fn validate(&self) -> Result<(), String> {
+    // ammonia always removes these along with their contents
+    for tag in &["script", "style"] {
+      if self.tags.contains(*tag) {
+        return Err(format!("{} tags can't be allowed", tag));
+      }
+    }
+    // we set rel on links ourselves
+    if self.generic_attributes.contains("rel")
+      || self
+        .tag_attributes
+        .values()
+        .any(|attrs| attrs.contains("rel"))
+    {
+      return Err("the rel attribute can't be allowed".to_string());
+    }
+    Ok(())
+  }
+
+  fn builder(&'static self) -> ammonia::Builder<'static> {
+    let mut builder = ammonia::Builder::new();
+    builder
+      .tags(self.tags.iter().map(|s| s.as_str()).collect())
+      .generic_attributes(self.generic_attributes.iter().map(|s| s.as_str()).collect())
+      .tag_attributes(
+        self
+          .tag_attributes
+          .iter()
+          .map(|(tag, attrs)| (tag.as_str(), attrs.iter().map(|s| s.as_str()).collect()))
+          .collect(),
+      )
+      .url_schemes(self.url_schemes.iter().map(|s| s.as_str()).collect())
+      // links always open elsewhere and can't reach back into the game's window
+      .link_rel(Some("noopener noreferrer nofollow"));
+    builder
+  }
+}
+
+lazy_static! {
+  static ref POLICY: HtmlPolicy = HtmlPolicy::load();
+  static ref SANITIZER: ammonia::Builder<'static> = POLICY.builder();
+}
+
+/// Loads the policy now, so problems with it show up in the log at startup.
+pub fn load_policy() {
+  lazy_static::initialize(&SANITIZER);
+}
+
+/// Strips anything not allowed by the HTML policy (scripts, event handlers,
+/// javascript: links and so on) from the given HTML.
+pub fn sanitize_html(html: &str) -> String {
+  SANITIZER.clean(html).to_string()
+}
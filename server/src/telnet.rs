@@ -0,0 +1,542 @@
+use crate::auth::{is_valid_username, AuthProviders};
+use crate::chat::{ChatRowContent, ClientConnection, Disconnect, ToClientMessage};
+use crate::lua::SerializableValue;
+use crate::world::rate_limit::{
+  InputKind, Limiter, Verdict, DISCONNECTED_MESSAGE, THROTTLED_MESSAGE,
+};
+use crate::world::revisions;
+use crate::world::{Id, WorldRef};
+use actix::io::{FramedWrite, WriteHandler};
+use actix::prelude::*;
+use bytes::BytesMut;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{split, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+/// Lines longer than this are cut off rather than buffered indefinitely.
+const MAX_LINE_LENGTH: usize = 4096;
+
+const LOGIN_PROMPT: &str = "Username: ";
+const PASSWORD_PROMPT: &str = "Password (or a token from /api/telnet_token): ";
+
+/// Connections which get the password wrong this many times are hung up on.
+const MAX_LOGIN_ATTEMPTS: usize = 3;
+
+/// Accepts line-based (telnet/raw TCP) connections for classic MUD clients and scripts.
+pub async fn listen(
+  world_ref: WorldRef,
+  auth_providers: Arc<AuthProviders>,
+  address: String,
+) -> io::Result<()> {
+  let mut listener = TcpListener::bind(address.as_str()).await?;
+  log::info!("Listening for telnet connections on {}", address);
+
+  actix_rt::spawn(async move {
+    loop {
+      match listener.accept().await {
+        Ok((stream, peer)) => {
+          log::info!("Telnet connection from {}", peer);
+          TelnetSession::start_for(
+            stream,
+            peer.ip().to_string(),
+            world_ref.clone(),
+            auth_providers.clone(),
+          );
+        }
+        Err(e) => log::error!("Failed accepting telnet connection: {}", e),
+      }
+    }
+  });
+
+  Ok(())
+}
+
+/// The telnet equivalent of a ChatSocket: the first line is the username and the
+/// second their password (or a token issued to them in the web client); every
+/// line after that is a command.
+pub struct TelnetSession {
+  world_ref: WorldRef,
+  auth_providers: Arc<AuthProviders>,
+  self_id: Option<Id>,
+  login: LoginStep,
+  writer: FramedWrite<WriteHalf<TcpStream>, TelnetCodec>,
+  limiter: Limiter,
+  address: String, // the client's IP, for limiting password guesses
+}
+
+/// What we're waiting for before the connection is logged in.
+enum LoginStep {
+  Username,
+  Password { username: String, failures: usize },
+  Checking, // whether the password was right; input meanwhile is ignored
+}
+
+impl Actor for TelnetSession {
+  type Context = Context<Self>;
+
+  fn started(&mut self, _ctx: &mut Self::Context) {
+    self.write("Welcome to orisa!\n");
+    self.write(LOGIN_PROMPT);
+  }
+
+  fn stopped(&mut self, ctx: &mut Self::Context) {
+    if let Some(id) = self.self_id {
+      // we use try_write here because the world could be gone if we're tearing down
+      self
+        .world_ref
+        .try_write(|world| world.logout(id, ClientConnection::Telnet(ctx.address())));
+      log::info!("TelnetSession stopped for id {}", id);
+    }
+  }
+}
+
+impl TelnetSession {
+  fn start_for(
+    stream: TcpStream,
+    address: String,
+    world_ref: WorldRef,
+    auth_providers: Arc<AuthProviders>,
+  ) -> Addr<TelnetSession> {
+    TelnetSession::create(move |ctx| {
+      let (read, write) = split(stream);
+      TelnetSession::add_stream(FramedRead::new(read, TelnetCodec), ctx);
+      let limiter = world_ref.read(|world| Limiter::for_connection(world.get_rate_limits()));
+      TelnetSession {
+        world_ref,
+        auth_providers,
+        self_id: None,
+        login: LoginStep::Username,
+        writer: FramedWrite::new(write, TelnetCodec, ctx),
+        limiter,
+        address,
+      }
+    })
+  }
+
+  fn handle_line(&mut self, line: &str, ctx: &mut Context<Self>) {
+    if self.self_id.is_none() {
+      match std::mem::replace(&mut self.login, LoginStep::Checking) {
+        LoginStep::Username => self.handle_username(line),
+        LoginStep::Password { username, failures } => {
+          self.handle_password(username, failures, line, ctx)
+        }
+        LoginStep::Checking => (),
+      }
+    } else if !line.is_empty() {
+      let id = self.id();
+      let world_ref = self.world_ref.clone();
+      let verdict = world_ref.write(|world| {
+        let verdict = world.check_rate(Some(id), &mut self.limiter, InputKind::Command);
+        if let Verdict::Allow = verdict {
+          world.command(id, line);
+        }
+        verdict
+      });
+
+      match verdict {
+        Verdict::Allow => (),
+        Verdict::Throttle => self.write(&format!("{}\n", THROTTLED_MESSAGE)),
+        Verdict::Disconnect => {
+          self.write(&format!("{}\n", DISCONNECTED_MESSAGE));
+          self.writer.close();
+        }
+      }
+    }
+  }
+
+  fn handle_username(&mut self, username: &str) {
+    self.login = LoginStep::Username;
+    if !is_valid_username(username) {
+      self.write("Usernames may only contain letters, numbers, - and _.\n");
+      self.write(LOGIN_PROMPT);
+      return;
+    }
+
+    if self
+      .world_ref
+      .read(|world| world.get_state().is_service_account(username))
+    {
+      self.write("That is a service account; bots should use the bot protocol.\n");
+      self.write(LOGIN_PROMPT);
+      return;
+    }
+
+    self.login = LoginStep::Password {
+      username: username.to_string(),
+      failures: 0,
+    };
+    self.write(PASSWORD_PROMPT);
+  }
+
+  /// Logs in with a token from the web client, or else checks the password with
+  /// the auth providers (in the background, since that's slow).
+  fn handle_password(
+    &mut self,
+    username: String,
+    failures: usize,
+    password: &str,
+    ctx: &mut Context<Self>,
+  ) {
+    if self.auth_providers.take_telnet_token(&username, password) {
+      self.finish_login(&username, ctx);
+      return;
+    }
+
+    self.login = LoginStep::Checking;
+    let auth_providers = self.auth_providers.clone();
+    let world_ref = self.world_ref.clone();
+    let password = password.to_string();
+    let address = self.address.clone();
+    let check = async move {
+      let result = auth_providers
+        .login_with_password(world_ref, &username, &password, &address)
+        .await;
+      (username, result)
+    };
+
+    ctx.spawn(
+      check
+        .into_actor(self)
+        .map(move |(username, result), act, ctx| match result {
+          Ok(username) => act.finish_login(&username, ctx),
+          Err(e) => {
+            act.write(&format!("{}\n", e));
+            if failures + 1 < MAX_LOGIN_ATTEMPTS {
+              act.login = LoginStep::Password {
+                username,
+                failures: failures + 1,
+              };
+              act.write(PASSWORD_PROMPT);
+            } else {
+              act.writer.close();
+            }
+          }
+        }),
+    );
+  }
+
+  fn finish_login(&mut self, username: &str, ctx: &mut Context<Self>) {
+    let world_ref = self.world_ref.clone();
+    let history = world_ref.write(|world| {
+      let id = world.get_state_mut().get_or_create_user(username, "user");
+      let history = world.login(id, self.self_id, ClientConnection::Telnet(ctx.address()));
+      self.self_id = Some(id);
+      history
+    });
+
+    self.send_to_client(&ToClientMessage::Backlog { history });
+  }
+
+  fn id(&self) -> Id {
+    self.self_id.unwrap()
+  }
+
+  fn write(&mut self, text: &str) {
+    self.writer.write(text.to_string());
+  }
+
+  fn send_to_client(&mut self, message: &ToClientMessage) {
+    let text = render_message(message);
+    self.write(&text);
+  }
+}
+
+impl WriteHandler<io::Error> for TelnetSession {}
+
+impl StreamHandler<Result<String, io::Error>> for TelnetSession {
+  fn handle(&mut self, line: Result<String, io::Error>, ctx: &mut Self::Context) {
+    match line {
+      Ok(line) => self.handle_line(line.trim(), ctx),
+      Err(e) => {
+        log::error!("Failed reading from telnet client: {}", e);
+        ctx.stop();
+      }
+    }
+  }
+}
+
+impl Handler<ToClientMessage> for TelnetSession {
+  type Result = ();
+
+  fn handle(&mut self, msg: ToClientMessage, _ctx: &mut Self::Context) {
+    self.send_to_client(&msg)
+  }
+}
+
+impl Handler<Disconnect> for TelnetSession {
+  type Result = ();
+
+  fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) {
+    // the world has already forgotten this connection
+    self.self_id = None;
+    self.write(&format!("{}\n", msg.reason));
+    self.writer.close();
+  }
+}
+
+/// Splits input into lines (dropping telnet negotiation) and writes text
+/// with telnet-style line endings. Everything we write goes through here, so
+/// this is also where control characters are taken out of it: world text
+/// includes other users' speech, which mustn't be able to send escape sequences
+/// to someone's terminal.
+pub struct TelnetCodec;
+
+const IAC: u8 = 255;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+impl Decoder for TelnetCodec {
+  type Item = String;
+  type Error = io::Error;
+
+  fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, io::Error> {
+    let line = match src.iter().position(|b| *b == b'\n') {
+      Some(end) => src.split_to(end + 1),
+      None if src.len() > MAX_LINE_LENGTH => src.split_to(src.len()),
+      None => return Ok(None),
+    };
+
+    Ok(Some(strip_telnet_commands(&line)))
+  }
+}
+
+impl Encoder for TelnetCodec {
+  type Item = String;
+  type Error = io::Error;
+
+  fn encode(&mut self, text: String, dst: &mut BytesMut) -> Result<(), io::Error> {
+    let text = without_control_characters(&text.replace("\r\n", "\n")).replace('\n', "\r\n");
+    dst.extend_from_slice(&escape_iac(text.as_bytes()));
+    Ok(())
+  }
+}
+
+/// Drops C0 and C1 control characters (ESC among them), except newlines and tabs.
+fn without_control_characters(text: &str) -> String {
+  text
+    .chars()
+    .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+    .collect()
+}
+
+/// A 255 byte would start a telnet command, so it's sent twice to mean itself.
+fn escape_iac(bytes: &[u8]) -> Vec<u8> {
+  let mut result = Vec::with_capacity(bytes.len());
+  for b in bytes {
+    result.push(*b);
+    if *b == IAC {
+      result.push(IAC);
+    }
+  }
+  result
+}
+
+// We don't negotiate any options, so we just drop whatever the client sends.
+fn strip_telnet_commands(line: &[u8]) -> String {
+  let mut result = Vec::with_capacity(line.len());
+  let mut i = 0;
+  while i < line.len() {
+    if line[i] != IAC {
+      result.push(line[i]);
+      i += 1;
+    } else {
+      match line.get(i + 1) {
+        Some(&IAC) => {
+          // escaped 255
+          result.push(IAC);
+          i += 2;
+        }
+        Some(&SB) => {
+          // subnegotiation runs until IAC SE
+          i += 2;
+          while i < line.len() && !(line[i] == IAC && line.get(i + 1) == Some(&SE)) {
+            i += 1;
+          }
+          i += 2;
+        }
+        Some(&command) if command >= 251 => i += 3, // WILL/WONT/DO/DONT + option
+        _ => i += 2,
+      }
+    }
+  }
+  String::from_utf8_lossy(&result).to_string()
+}
+
+fn render_message(message: &ToClientMessage) -> String {
+  match message {
+    ToClientMessage::Tell { content } => format!("{}\n", render_row(content)),
+    ToClientMessage::Backlog { history } | ToClientMessage::History { history, .. } => history
+      .iter()
+      .map(|row| format!("{}\n", render_row(row)))
+      .collect(),
+    ToClientMessage::Log { level, message } => format!("[{}] {}\n", level, message),
+    ToClientMessage::EditFile { name, content } => format!(
+      "--- {} ---\n{}\n--- end of {} (use the web client to edit and save) ---\n",
+      name, content, name
+    ),
+    ToClientMessage::ShowDiff {
+      name,
+      from,
+      to,
+      original,
+      content,
+    } => format!(
+      "--- {} revision {}\n+++ {} revision {}\n{}",
+      name,
+      from,
+      name,
+      to,
+      revisions::diff(original, content)
+    ),
+    ToClientMessage::Error { message } => format!("[error] {}\n", message),
+    ToClientMessage::Prompt { spec, .. } => format!(
+      "[prompt] {} (answer this in the web client)\n",
+      prompt_title(spec)
+    ),
+    // these are for programs, not people
+    ToClientMessage::Hello { .. }
+    | ToClientMessage::Event { .. }
+    | ToClientMessage::Session { .. }
+    | ToClientMessage::Panel { .. }
+    | ToClientMessage::PanelChanges { .. }
+    | ToClientMessage::PromptClosed { .. }
+    | ToClientMessage::Completions { .. }
+    | ToClientMessage::ResumeFailed { .. } => "".to_string(),
+    ToClientMessage::AdminResponse { result } => format!(
+      "{}\n",
+      serde_json::to_string_pretty(result).unwrap_or_else(|e| e.to_string())
+    ),
+  }
+}
+
+fn prompt_title(spec: &SerializableValue) -> String {
+  match spec {
+    SerializableValue::Dict(fields) => match fields.get("title") {
+      Some(SerializableValue::String(title)) => title.clone(),
+      _ => "Question".to_string(),
+    },
+    _ => "Question".to_string(),
+  }
+}
+
+fn render_row(row: &ChatRowContent) -> String {
+  match row {
+    ChatRowContent::TextContent { text, .. } => text.clone(),
+    ChatRowContent::HtmlContent { html, .. } => html_to_text(html),
+  }
+}
+
+/// Renders the HTML we send to web clients as plain text: tags are dropped,
+/// block elements become line breaks, emphasis becomes *bold* or _italic_
+/// and links keep their target after their label.
+pub fn html_to_text(html: &str) -> String {
+  let mut out = String::new();
+  let mut links: Vec<Option<String>> = vec![];
+  let mut rest = html;
+
+  while let Some(start) = rest.find('<') {
+    let end = match rest[start..].find('>') {
+      Some(offset) => start + offset,
+      None => break,
+    };
+    out.push_str(&decode_entities(&rest[..start]));
+    render_tag(&rest[start + 1..end], &mut out, &mut links);
+    rest = &rest[end + 1..];
+  }
+  out.push_str(&decode_entities(rest));
+
+  out.trim_end().to_string()
+}
+
+fn render_tag(tag: &str, out: &mut String, links: &mut Vec<Option<String>>) {
+  let closing = tag.starts_with('/');
+  let body = tag.trim_start_matches('/').trim_end_matches('/');
+  let name = body
+    .split_whitespace()
+    .next()
+    .unwrap_or("")
+    .to_ascii_lowercase();
+
+  match (name.as_str(), closing) {
+    ("br", _) => out.push('\n'),
+    ("p", _) | ("div", _) | ("ul", _) | ("ol", _) | ("tr", _) | ("li", true) => {
+      if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+      }
+    }
+    ("li", false) => {
+      if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+      }
+      out.push_str("- ");
+    }
+    ("b", _) | ("strong", _) => out.push('*'),
+    ("i", _) | ("em", _) => out.push('_'),
+    ("a", false) => links.push(attribute(body, "href").filter(|href| {
+      !href.starts_with('#') && !href.to_ascii_lowercase().starts_with("javascript:")
+    })),
+    ("a", true) => {
+      if let Some(Some(href)) = links.pop() {
+        out.push_str(&format!(" <{}>", href));
+      }
+    }
+    _ => (),
+  }
+}
+
+fn attribute(tag_body: &str, name: &str) -> Option<String> {
+  let lower = tag_body.to_ascii_lowercase();
+  let start = lower.find(&format!("{}=", name))? + name.len() + 1;
+  let value = &tag_body[start..];
+  let value = match value.chars().next()? {
+    quote @ '"' | quote @ '\'' => value[1..].split(quote).next()?,
+    _ => value.split_whitespace().next()?,
+  };
+  Some(decode_entities(value))
+}
+
+fn decode_entities(text: &str) -> String {
+  text
+    .replace("&nbsp;", " ")
+    .replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&quot;", "\"")
+    .replace("&#39;", "'")
+    .replace("&#x27;", "'")
+    .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(text: &str) -> Vec<u8> {
+    let mut dst = BytesMut::new();
+    TelnetCodec.encode(text.to_string(), &mut dst).unwrap();
+    dst.to_vec()
+  }
+
+  #[test]
+  fn control_characters_are_stripped() {
+    assert_eq!(
+      encode("hi \u{1b}[2Jthere\u{9b}31m\u{7}\r\nok\tdone\n"),
+      b"hi [2Jthere31m\r\nok\tdone\r\n".to_vec()
+    );
+    // a lone carriage return could overwrite the line it's on
+    assert_eq!(encode("fake\rreal"), b"fakereal".to_vec());
+  }
+
+  #[test]
+  fn iac_is_doubled() {
+    assert_eq!(escape_iac(&[b'a', IAC, b'b']), vec![b'a', IAC, IAC, b'b']);
+    assert_eq!(escape_iac(b"plain"), b"plain".to_vec());
+  }
+
+  #[test]
+  fn incoming_commands_are_dropped() {
+    let line = [b'h', IAC, 251, 1, b'i', IAC, IAC, b'\n'];
+    assert_eq!(strip_telnet_commands(&line), "hi\u{FFFD}\n");
+  }
+}
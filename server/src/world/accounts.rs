@@ -0,0 +1,152 @@
+use crate::object::types::GameTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// A non-human user (e.g. gamebot) which logs in with API tokens
+/// rather than as a browser user.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServiceAccount {
+  /// Fixed when the account is created; bots can't pick their own kind.
+  pub user_type: String,
+
+  // token id -> hex sha256 of the full token; we never store the token itself
+  tokens: HashMap<String, String>,
+}
+
+impl ServiceAccount {
+  pub fn new(user_type: &str) -> ServiceAccount {
+    ServiceAccount {
+      user_type: user_type.to_string(),
+      tokens: HashMap::new(),
+    }
+  }
+
+  /// Creates a new token, returning it; this is the only time it's available.
+  /// Tokens look like `$id.$secret`, where the id can be used to revoke it later.
+  pub fn issue_token(&mut self) -> String {
+    let token_id = Uuid::new_v4().to_simple().to_string()[..8].to_string();
+    let token = format!("{}.{}", token_id, Uuid::new_v4().to_simple());
+    self.tokens.insert(token_id, hash_token(&token));
+    token
+  }
+
+  pub fn revoke_token(&mut self, token_id: &str) -> bool {
+    self.tokens.remove(token_id).is_some()
+  }
+
+  pub fn revoke_all_tokens(&mut self) {
+    self.tokens.clear()
+  }
+
+  pub fn token_ids(&self) -> impl Iterator<Item = &str> {
+    self.tokens.keys().map(|k| k.as_str())
+  }
+
+  pub fn accepts(&self, token: &str) -> bool {
+    self
+      .tokens
+      .get(token_id(token))
+      .map(|hash| constant_time_eq(hash.as_bytes(), hash_token(token).as_bytes()))
+      .unwrap_or(false)
+  }
+}
+
+/// Lets someone set (or reset) the local password of an existing user, e.g. one from
+/// before local passwords; issued by an admin and used once.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PasswordSetup {
+  hash: String, // hex sha256 of the token, like service account tokens
+  expires_at: GameTime,
+}
+
+impl PasswordSetup {
+  /// Returns the setup and its token, which isn't kept anywhere else.
+  pub fn issue(expires_at: GameTime) -> (PasswordSetup, String) {
+    let token = Uuid::new_v4().to_simple().to_string();
+    let setup = PasswordSetup {
+      hash: hash_token(&token),
+      expires_at,
+    };
+    (setup, token)
+  }
+
+  pub fn accepts(&self, token: &str, now: GameTime) -> bool {
+    now < self.expires_at && constant_time_eq(self.hash.as_bytes(), hash_token(token).as_bytes())
+  }
+}
+
+/// The part of a token used to revoke it.
+pub fn token_id(token: &str) -> &str {
+  token.split('.').next().unwrap_or("")
+}
+
+fn hash_token(token: &str) -> String {
+  format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Compares without stopping at the first difference, so the time taken
+/// doesn't tell an attacker how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn issued_tokens_are_accepted_until_revoked() {
+    let mut account = ServiceAccount::new("bot");
+    let first = account.issue_token();
+    let second = account.issue_token();
+    assert!(account.accepts(&first));
+    assert!(account.accepts(&second));
+    assert_eq!(account.token_ids().count(), 2);
+
+    assert!(account.revoke_token(token_id(&first)));
+    assert!(!account.revoke_token(token_id(&first)));
+    assert!(!account.accepts(&first));
+    assert!(account.accepts(&second));
+
+    account.revoke_all_tokens();
+    assert!(!account.accepts(&second));
+    assert_eq!(account.token_ids().count(), 0);
+  }
+
+  #[test]
+  fn only_the_whole_token_is_accepted() {
+    let mut account = ServiceAccount::new("bot");
+    let token = account.issue_token();
+    let id = token_id(&token);
+    assert!(!account.accepts(id));
+    assert!(!account.accepts(&format!("{}.", id)));
+    assert!(!account.accepts(&format!("{}x", token)));
+    assert!(!account.accepts(&token[..token.len() - 1]));
+    assert!(!account.accepts(""));
+
+    // another account's token, even with the same id, is no good
+    let mut other = ServiceAccount::new("bot");
+    let other_token = other.issue_token();
+    let secret = other_token.split('.').nth(1).unwrap();
+    assert!(!account.accepts(&format!("{}.{}", id, secret)));
+  }
+
+  #[test]
+  fn password_setups_expire() {
+    let now = GameTime::default() + 100;
+    let (setup, token) = PasswordSetup::issue(now + 10);
+    assert!(setup.accepts(&token, now));
+    assert!(!setup.accepts("wrong", now));
+    assert!(!setup.accepts(&token, now + 10));
+  }
+
+  #[test]
+  fn constant_time_eq_compares_everything() {
+    assert!(constant_time_eq(b"abc", b"abc"));
+    assert!(!constant_time_eq(b"abc", b"abd"));
+    assert!(!constant_time_eq(b"abc", b"ab"));
+    assert!(constant_time_eq(b"", b""));
+  }
+}
@@ -14,6 +14,7 @@ pub struct WorldActor {
   lua_host: LuaHost,
   world_ref: WorldRef,
   executors: HashMap<PackageReference, ObjectExecutor>,
+  generation: u64, // of the world's state; see World::restore
 
   start_game_time: Option<GameTime>,
   start_instant: Option<Instant>,
@@ -30,14 +31,33 @@ impl actix::Actor for WorldActor {
   }
 }
 
-impl actix::Message for Message {
+/// A message for an object, stamped with the generation of the state it was
+/// sent in. Any still queued when the state is restored are dropped, since the
+/// objects they're for may be somewhere else or not exist at all in the new one.
+pub struct StampedMessage {
+  pub message: Message,
+  pub generation: u64,
+}
+
+impl actix::Message for StampedMessage {
   type Result = ();
 }
 
-impl actix::Handler<Message> for WorldActor {
+impl actix::Handler<StampedMessage> for WorldActor {
   type Result = ();
 
-  fn handle(&mut self, msg: Message, _ctx: &mut actix::Context<Self>) {
+  fn handle(&mut self, msg: StampedMessage, _ctx: &mut actix::Context<Self>) {
+    let generation = self.world_ref.read(|w| w.generation());
+    if msg.generation != generation {
+      log::info!(
+        "Dropping {} for {} sent before the state was restored",
+        msg.message.name,
+        msg.message.target
+      );
+      return;
+    }
+
+    let msg = msg.message;
     let _ = self.execute_message(&msg).map_err(|err| {
       self.report_error(&msg, &err);
       log::error!("Failed running payload: {:?}", err);
@@ -45,6 +65,26 @@ impl actix::Handler<Message> for WorldActor {
   }
 }
 
+/// Runs a query for a client, giving up if the Lua code runs longer than `budget`.
+pub struct BudgetedQuery {
+  pub message: Message,
+  pub budget: Duration,
+}
+
+impl actix::Message for BudgetedQuery {
+  type Result = Result<SerializableValue, String>;
+}
+
+impl actix::Handler<BudgetedQuery> for WorldActor {
+  type Result = Result<SerializableValue, String>;
+
+  fn handle(&mut self, msg: BudgetedQuery, _ctx: &mut actix::Context<Self>) -> Self::Result {
+    self
+      .execute_query_with_budget(&msg.message, msg.budget)
+      .map_err(|err| err.to_string())
+  }
+}
+
 pub enum ControlMessage {
   ReloadCode,
 }
@@ -72,6 +112,7 @@ impl WorldActor {
       lua_host: lua_host.clone(),
       world_ref: world_ref.clone(),
       executors: HashMap::new(),
+      generation: 0,
       start_game_time: None,
       start_instant: None,
     }
@@ -107,6 +148,19 @@ impl WorldActor {
     executor.run_main(self, &message, true)
   }
 
+  pub fn execute_query_with_budget(
+    &mut self,
+    message: &Message,
+    budget: Duration,
+  ) -> rlua::Result<SerializableValue> {
+    let kind = self
+      .world_ref
+      .read(|w| w.get_state().kind(message.target))?;
+
+    let executor = self.executor(kind);
+    executor.run_query_with_budget(self, &message, budget)
+  }
+
   fn report_error(&self, msg: &Message, err: &rlua::Error) {
     if let Some(user_id) = msg.original_user {
       self.world_ref.read(|w| {
@@ -126,12 +180,24 @@ impl WorldActor {
     let start_instant = self.start_instant.unwrap();
     let elapsed = Instant::now() - start_instant;
     let now = start_game + elapsed.as_secs();
+    let known_generation = self.generation;
 
-    self.world_ref.write(|w| {
+    let (generation, last_updated) = self.world_ref.write(|w| {
       let last_updated = w.get_state().get_current_time();
-      if now > last_updated {
+      if w.generation() == known_generation && now > last_updated {
         w.advance_time(now);
       }
+      w.expire_sessions();
+      (w.generation(), last_updated)
     });
+
+    if generation != known_generation {
+      // the state was restored from a snapshot, so start over from its time with fresh code
+      log::info!("clearing executor cache and restarting the clock for restored state");
+      self.generation = generation;
+      self.start_game_time = Some(last_updated);
+      self.start_instant = Some(Instant::now());
+      self.executors = HashMap::new();
+    }
   }
 }
@@ -0,0 +1,315 @@
+use crate::object::types::{GameTime, Id, ObjectKind};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// The log's file in the state directory: one entry per line, only ever appended to.
+const AUDIT_FILE: &str = "audit.jsonl";
+
+/// We keep this many of the most recent entries in memory for searches.
+const MAX_AUDIT_ENTRIES: usize = 10_000;
+
+/// Searches return at most this many entries.
+pub const AUDIT_PAGE_SIZE: usize = 100;
+
+/// A change to the world we want to be able to account for later.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "action")]
+pub enum AuditAction {
+  SetAttr {
+    object: Id,
+    key: String,
+  },
+  MoveObject {
+    object: Id,
+    new_parent: Option<Id>,
+  },
+  CreateObject {
+    object: Id,
+    kind: ObjectKind,
+    owner: Option<Id>,
+  },
+  SavePackage {
+    package: String,
+    bytes: usize,
+    revision: Option<usize>,
+  },
+  Login {
+    user: Id,
+  },
+  ReloadCode {},
+  PromotePackage {
+    package: String,
+    system: String,
+    commit: String,
+  },
+  Rollback {
+    snapshot: String,
+    kept: String, // the snapshot the state from before the rollback was saved as
+  },
+}
+
+impl AuditAction {
+  fn involves(&self, id: Id) -> bool {
+    match self {
+      AuditAction::SetAttr { object, .. } => *object == id,
+      AuditAction::MoveObject { object, new_parent } => *object == id || *new_parent == Some(id),
+      AuditAction::CreateObject { object, owner, .. } => *object == id || *owner == Some(id),
+      AuditAction::Login { user } => *user == id,
+      AuditAction::SavePackage { .. }
+      | AuditAction::ReloadCode {}
+      | AuditAction::PromotePackage { .. }
+      | AuditAction::Rollback { .. } => false,
+    }
+  }
+}
+
+/// Who made a change: the object whose code did it and the message it was
+/// handling, or just the user for things done directly by clients.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AuditContext {
+  pub original_user: Option<Id>,
+  pub immediate_sender: Option<Id>,
+  pub object: Option<Id>,
+  pub message: Option<String>,
+}
+
+impl AuditContext {
+  pub fn user(id: Id) -> AuditContext {
+    AuditContext {
+      original_user: Some(id),
+      ..AuditContext::default()
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+  pub time: GameTime,
+  pub context: AuditContext,
+  pub action: AuditAction,
+}
+
+impl AuditEntry {
+  /// As shown to admins, with ids in the usual "#123" form.
+  pub fn to_json(&self) -> Value {
+    let id = |id: Option<Id>| id.map(|id| id.to_string());
+    let mut action = serde_json::to_value(&self.action).unwrap_or(Value::Null);
+    if let Value::Object(ref mut fields) = action {
+      for (key, value) in fields.iter_mut() {
+        if ["object", "new_parent", "owner", "user"].contains(&key.as_str()) {
+          *value = match value.as_u64() {
+            Some(index) => json!(Id(index as usize).to_string()),
+            None => Value::Null,
+          };
+        }
+      }
+    }
+
+    json!({
+      "time": self.time,
+      "original_user": id(self.context.original_user),
+      "immediate_sender": id(self.context.immediate_sender),
+      "object": id(self.context.object),
+      "message": self.context.message,
+      "action": action,
+    })
+  }
+}
+
+pub struct AuditQuery {
+  pub object: Option<Id>, // entries changing it, or made by its code
+  pub user: Option<Id>,   // entries made on behalf of this user
+  pub since: Option<GameTime>,
+  pub until: Option<GameTime>,
+  pub limit: usize,
+}
+
+/// The log as it was saved with the world, before it had its own file.
+#[derive(Deserialize, Clone, Default)]
+pub struct SavedAuditLog {
+  entries: Vec<AuditEntry>,
+}
+
+#[derive(Default)]
+pub struct AuditLog {
+  entries: VecDeque<AuditEntry>,
+  // written out by `flush`, so recording doesn't wait on the disk
+  file: Option<BufWriter<File>>,
+}
+
+impl AuditLog {
+  /// Opens the log in `dir`, reading back its most recent entries. If there's
+  /// no file yet, it starts with any entries saved with the world.
+  pub fn open(dir: &Path, saved: SavedAuditLog) -> AuditLog {
+    let path = dir.join(AUDIT_FILE);
+    let mut audit_log = AuditLog::default();
+    let existed = path.exists();
+    if existed {
+      match File::open(&path) {
+        Ok(file) => {
+          for line in BufReader::new(file).lines() {
+            match line.map(|l| serde_json::from_str(&l)) {
+              Ok(Ok(entry)) => audit_log.remember(entry),
+              Ok(Err(e)) => log::warn!("Skipping a bad audit log entry: {}", e),
+              Err(e) => log::warn!("Skipping an unreadable audit log entry: {}", e),
+            }
+          }
+        }
+        Err(e) => log::error!("Unable to read the audit log: {}", e),
+      }
+    }
+
+    match OpenOptions::new().create(true).append(true).open(&path) {
+      Ok(file) => audit_log.file = Some(BufWriter::new(file)),
+      Err(e) => log::error!(
+        "Unable to open {} for writing; audit entries will only be kept in memory: {}",
+        path.display(),
+        e
+      ),
+    }
+    if !existed {
+      for entry in saved.entries {
+        audit_log.record(entry);
+      }
+    }
+    audit_log
+  }
+
+  /// Remembers an entry and buffers it for the file; a crash can at worst leave a
+  /// partial last line there, which `open` skips.
+  pub fn record(&mut self, entry: AuditEntry) {
+    if let Some(file) = self.file.as_mut() {
+      let written = serde_json::to_string(&entry)
+        .map_err(|e| e.to_string())
+        .and_then(|line| {
+          file
+            .write_all(format!("{}\n", line).as_bytes())
+            .map_err(|e| e.to_string())
+        });
+      if let Err(e) = written {
+        log::error!("Unable to write to the audit log: {}", e);
+      }
+    }
+    self.remember(entry);
+  }
+
+  /// Writes out buffered entries and, if `sync`, waits for them to reach the disk.
+  /// Entries which couldn't be written stay buffered for the next try.
+  pub fn flush(&mut self, sync: bool) -> io::Result<()> {
+    if let Some(file) = self.file.as_mut() {
+      file.flush()?;
+      if sync {
+        file.get_ref().sync_data()?;
+      }
+    }
+    Ok(())
+  }
+
+  fn remember(&mut self, entry: AuditEntry) {
+    if self.entries.len() >= MAX_AUDIT_ENTRIES {
+      self.entries.pop_front();
+    }
+    self.entries.push_back(entry);
+  }
+
+  /// Matching entries, newest first.
+  pub fn search(&self, query: &AuditQuery) -> Vec<&AuditEntry> {
+    self
+      .entries
+      .iter()
+      .rev()
+      .filter(|e| query.until.map_or(true, |until| e.time <= until))
+      // not take_while: entries aren't in time order across a rollback
+      .filter(|e| query.since.map_or(true, |since| e.time >= since))
+      .filter(|e| {
+        query.object.map_or(true, |id| {
+          e.context.object == Some(id) || e.action.involves(id)
+        })
+      })
+      .filter(|e| {
+        query
+          .user
+          .map_or(true, |id| e.context.original_user == Some(id))
+      })
+      .take(query.limit)
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::path::PathBuf;
+
+  fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("orisa-audit-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn entry(key: &str) -> AuditEntry {
+    AuditEntry {
+      time: GameTime::default(),
+      context: AuditContext::user(Id(1)),
+      action: AuditAction::SetAttr {
+        object: Id(2),
+        key: key.to_string(),
+      },
+    }
+  }
+
+  fn keys(audit_log: &AuditLog) -> Vec<String> {
+    let everything = AuditQuery {
+      object: None,
+      user: None,
+      since: None,
+      until: None,
+      limit: AUDIT_PAGE_SIZE,
+    };
+    audit_log
+      .search(&everything)
+      .iter()
+      .map(|e| match &e.action {
+        AuditAction::SetAttr { key, .. } => key.clone(),
+        other => panic!("Unexpected entry {:?}", other),
+      })
+      .collect()
+  }
+
+  #[test]
+  fn entries_are_buffered_until_flushed() {
+    let dir = temp_dir();
+    let mut audit_log = AuditLog::open(&dir, SavedAuditLog::default());
+    audit_log.record(entry("a"));
+    audit_log.record(entry("b"));
+    assert_eq!(keys(&audit_log), vec!["b", "a"]);
+    assert_eq!(fs::read_to_string(dir.join(AUDIT_FILE)).unwrap(), "");
+
+    audit_log.flush(true).unwrap();
+    assert_eq!(
+      keys(&AuditLog::open(&dir, SavedAuditLog::default())),
+      vec!["b", "a"]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn saved_entries_only_start_a_new_file() {
+    let dir = temp_dir();
+    let saved = SavedAuditLog {
+      entries: vec![entry("old")],
+    };
+    let mut audit_log = AuditLog::open(&dir, saved.clone());
+    audit_log.record(entry("new"));
+    audit_log.flush(false).unwrap();
+
+    // once the file exists, it's the record
+    assert_eq!(keys(&AuditLog::open(&dir, saved)), vec!["new", "old"]);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
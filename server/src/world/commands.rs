@@ -0,0 +1,323 @@
+use super::names::{Rank, Scope};
+use super::State;
+use crate::lua::SerializableValue;
+use crate::object::types::{Id, Message};
+use std::collections::HashMap;
+
+/// Multi-word prepositions come first so e.g. "on top of" wins over "on".
+const PREPOSITIONS: &[&str] = &[
+  "in front of",
+  "on top of",
+  "out of",
+  "from inside",
+  "off of",
+  "with",
+  "using",
+  "at",
+  "to",
+  "in",
+  "inside",
+  "into",
+  "on",
+  "onto",
+  "upon",
+  "from",
+  "over",
+  "through",
+  "under",
+  "underneath",
+  "beneath",
+  "behind",
+  "beside",
+  "for",
+  "about",
+  "is",
+  "as",
+  "off",
+];
+
+/// A command split MOO-style: `verb [direct object] [preposition [indirect object]]`.
+#[derive(Debug)]
+pub struct ParsedCommand {
+  pub verb: String,
+  pub args: String, // everything after the verb
+  pub dobj: Option<String>,
+  pub prep: Option<String>,
+  pub iobj: Option<String>,
+}
+
+pub fn parse(text: &str) -> Option<ParsedCommand> {
+  let text = text.trim();
+  // the usual shortcuts: "hello for say hello and :waves for emote waves;
+  // what follows them is speech, so it isn't split on prepositions like "is"
+  let (verb, args, speech) = match text.chars().next()? {
+    '"' => ("say".to_string(), text[1..].trim(), true),
+    ':' => ("emote".to_string(), text[1..].trim(), true),
+    _ => {
+      let verb = text.split_whitespace().next()?;
+      (verb.to_lowercase(), text[verb.len()..].trim(), false)
+    }
+  };
+  if speech {
+    return Some(ParsedCommand {
+      verb,
+      args: args.to_string(),
+      dobj: None,
+      prep: None,
+      iobj: None,
+    });
+  }
+
+  let words: Vec<&str> = args.split_whitespace().collect();
+  let found = (0..words.len()).find_map(|start| {
+    PREPOSITIONS
+      .iter()
+      .map(|prep| prep.split(' ').collect::<Vec<&str>>())
+      .find(|prep| {
+        words.len() >= start + prep.len()
+          && prep
+            .iter()
+            .zip(&words[start..])
+            .all(|(p, w)| p.eq_ignore_ascii_case(w))
+      })
+      .map(|prep| (start, prep.len()))
+  });
+
+  let phrase = |words: &[&str]| {
+    if words.is_empty() {
+      None
+    } else {
+      Some(words.join(" "))
+    }
+  };
+  let (dobj, prep, iobj) = match found {
+    Some((start, len)) => (
+      phrase(&words[..start]),
+      Some(words[start..start + len].join(" ").to_lowercase()),
+      phrase(&words[start + len..]),
+    ),
+    None => (phrase(&words), None, None),
+  };
+
+  Some(ParsedCommand {
+    verb,
+    args: args.to_string(),
+    dobj,
+    prep,
+    iobj,
+  })
+}
+
+pub enum Resolution {
+  Found(Id),
+  Ambiguous(Vec<Id>),
+  NotFound,
+}
+
+/// Finds what `name` refers to from where `user` is: "me", "here", an "#id",
+/// or something they're carrying or that's in their room (see names.rs).
+/// It's ambiguous if more than one thing matches equally well.
+pub fn resolve(state: &State, user: Id, name: &str) -> Resolution {
+  let room = state.parent(user).ok().flatten();
+  let name = name.to_lowercase();
+
+  if name == "me" {
+    return Resolution::Found(user);
+  }
+  if name == "here" {
+    return room.map_or(Resolution::NotFound, Resolution::Found);
+  }
+  if name.starts_with('#') {
+    return match name[1..].parse::<usize>() {
+      Ok(index) if state.kind(Id(index)).is_ok() => Resolution::Found(Id(index)),
+      _ => Resolution::NotFound,
+    };
+  }
+
+  let found = state
+    .find_objects(user, Scope::Nearby, &name)
+    .unwrap_or_default();
+  let best = found.first().map(|m| m.rank).unwrap_or(Rank::Exact);
+  let matches: Vec<Id> = found
+    .into_iter()
+    .take_while(|m| m.rank == best)
+    .map(|m| m.id)
+    .collect();
+
+  match matches.len() {
+    0 => Resolution::NotFound,
+    1 => Resolution::Found(matches[0]),
+    _ => Resolution::Ambiguous(matches),
+  }
+}
+
+/// True if the object's `verbs` attr lists `verb`.
+fn handles_verb(state: &State, id: Id, verb: &str) -> bool {
+  match state.get_attr(id, "verbs") {
+    Ok(Some(SerializableValue::Table(verbs))) => verbs.iter().any(|(_, v)| match v {
+      SerializableValue::String(v) => v.eq_ignore_ascii_case(verb),
+      _ => false,
+    }),
+    _ => false,
+  }
+}
+
+fn id_list(ids: Vec<Id>) -> SerializableValue {
+  SerializableValue::Table(
+    ids
+      .into_iter()
+      .enumerate()
+      .map(|(i, id)| {
+        (
+          SerializableValue::Integer(i as i64 + 1),
+          SerializableValue::String(id.to_string()),
+        )
+      })
+      .collect(),
+  )
+}
+
+/// Makes the `command` message for something the user typed. Like MOO, the
+/// first of the user, their room, the direct object and the indirect object
+/// which lists the verb in its `verbs` attr gets it; otherwise the user does.
+/// Users whose `raw_commands` attr is true just get `{message = text}`.
+pub fn command_message(state: &State, user: Id, text: &str) -> Message {
+  let mut payload = HashMap::new();
+  payload.insert(
+    "message".to_string(),
+    SerializableValue::String(text.to_string()),
+  );
+
+  let raw = match state.get_attr(user, "raw_commands") {
+    Ok(Some(SerializableValue::Boolean(raw))) => raw,
+    _ => false,
+  };
+  let parsed = if raw { None } else { parse(text) };
+
+  let mut target = user;
+  if let Some(parsed) = parsed {
+    let mut resolve_into = |key: &str, name: &Option<String>| -> Option<Id> {
+      let name = name.as_ref()?;
+      payload.insert(
+        format!("{}_text", key),
+        SerializableValue::String(name.clone()),
+      );
+      match resolve(state, user, name) {
+        Resolution::Found(id) => {
+          payload.insert(key.to_string(), SerializableValue::String(id.to_string()));
+          Some(id)
+        }
+        Resolution::Ambiguous(ids) => {
+          payload.insert(format!("{}_matches", key), id_list(ids));
+          None
+        }
+        Resolution::NotFound => None,
+      }
+    };
+    let dobj = resolve_into("dobj", &parsed.dobj);
+    let iobj = resolve_into("iobj", &parsed.iobj);
+
+    let room = state.parent(user).ok().flatten();
+    target = vec![Some(user), room, dobj, iobj]
+      .into_iter()
+      .flatten()
+      .find(|id| handles_verb(state, *id, &parsed.verb))
+      .unwrap_or(user);
+
+    payload.insert("verb".to_string(), SerializableValue::String(parsed.verb));
+    payload.insert("args".to_string(), SerializableValue::String(parsed.args));
+    if let Some(prep) = parsed.prep {
+      payload.insert("prep".to_string(), SerializableValue::String(prep));
+    }
+  }
+
+  Message {
+    target,
+    original_user: Some(user),
+    immediate_sender: user,
+    name: "command".to_string(),
+    payload: SerializableValue::Dict(payload),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn split(text: &str) -> (String, Option<String>, Option<String>, Option<String>) {
+    let command = parse(text).unwrap();
+    (command.verb, command.dobj, command.prep, command.iobj)
+  }
+
+  fn some(s: &str) -> Option<String> {
+    Some(s.to_string())
+  }
+
+  #[test]
+  fn empty_commands() {
+    assert!(parse("").is_none());
+    assert!(parse("   ").is_none());
+  }
+
+  #[test]
+  fn verb_only() {
+    assert_eq!(split("LOOK"), ("look".to_string(), None, None, None));
+  }
+
+  #[test]
+  fn direct_and_indirect_objects() {
+    assert_eq!(
+      split("put the red key in the box"),
+      (
+        "put".to_string(),
+        some("the red key"),
+        some("in"),
+        some("the box")
+      )
+    );
+    assert_eq!(
+      split("look at me"),
+      ("look".to_string(), None, some("at"), some("me"))
+    );
+  }
+
+  #[test]
+  fn longest_preposition_wins() {
+    assert_eq!(
+      split("put lamp On Top Of table"),
+      (
+        "put".to_string(),
+        some("lamp"),
+        some("on top of"),
+        some("table")
+      )
+    );
+  }
+
+  #[test]
+  fn is_and_as_split_ordinary_sentences() {
+    let command = parse("say this is great").unwrap();
+    assert_eq!(command.dobj, some("this"));
+    assert_eq!(command.prep, some("is"));
+    assert_eq!(command.iobj, some("great"));
+    assert_eq!(command.args, "this is great");
+
+    let command = parse("describe me as tall").unwrap();
+    assert_eq!(command.dobj, some("me"));
+    assert_eq!(command.prep, some("as"));
+    assert_eq!(command.iobj, some("tall"));
+  }
+
+  #[test]
+  fn speech_shortcuts_are_not_split() {
+    let command = parse("\"this is great, as always").unwrap();
+    assert_eq!(command.verb, "say");
+    assert_eq!(command.args, "this is great, as always");
+    assert!(command.dobj.is_none() && command.prep.is_none() && command.iobj.is_none());
+
+    let command = parse(":looks at the box").unwrap();
+    assert_eq!(command.verb, "emote");
+    assert_eq!(command.args, "looks at the box");
+    assert!(command.prep.is_none());
+  }
+}
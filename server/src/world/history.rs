@@ -0,0 +1,69 @@
+use crate::chat::ChatRowContent;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// How many rows of history we keep per user; older rows are dropped.
+const HISTORY_LIMIT: usize = 1000;
+
+/// How many rows we send by default (on login or when paging).
+pub const HISTORY_PAGE_SIZE: usize = 100;
+
+/// A bounded history of what each user has been told, kept across restarts
+/// so we can replay it when they log in (even if they were offline at the time).
+/// Keyed by username, like `State::users`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ChatHistory {
+  rows: HashMap<String, VecDeque<ChatRowContent>>,
+}
+
+impl ChatHistory {
+  pub fn new() -> ChatHistory {
+    ChatHistory {
+      rows: HashMap::new(),
+    }
+  }
+
+  pub fn record(&mut self, username: &str, content: ChatRowContent) {
+    let rows = self
+      .rows
+      .entry(username.to_string())
+      .or_insert_with(VecDeque::new);
+    rows.push_back(content);
+    while rows.len() > HISTORY_LIMIT {
+      rows.pop_front();
+    }
+  }
+
+  pub fn is_empty(&self, username: &str) -> bool {
+    self.rows.get(username).map_or(true, |rows| rows.is_empty())
+  }
+
+  /// Returns up to `limit` rows (oldest first) immediately preceding the row with id `before`,
+  /// or the most recent rows if `before` is None, along with whether there are older rows.
+  pub fn page(
+    &self,
+    username: &str,
+    before: Option<&str>,
+    limit: usize,
+  ) -> (Vec<ChatRowContent>, bool) {
+    let rows = match self.rows.get(username) {
+      Some(rows) => rows,
+      None => return (vec![], false),
+    };
+
+    let end = match before {
+      None => rows.len(),
+      Some(before) => match rows.iter().position(|r| r.id() == before) {
+        Some(position) => position,
+        // the row has aged out (or never existed), so there's nothing older to show
+        None => return (vec![], false),
+      },
+    };
+    let start = end.saturating_sub(limit);
+
+    (
+      rows.iter().skip(start).take(end - start).cloned().collect(),
+      start > 0,
+    )
+  }
+}
@@ -1,49 +1,372 @@
+pub mod accounts;
 pub mod actor;
+pub mod audit;
+pub mod commands;
+pub mod history;
+pub mod names;
+pub mod presence;
+pub mod query;
+pub mod quotas;
+pub mod rate_limit;
+pub mod revisions;
+pub mod sessions;
+pub mod snapshots;
 pub mod state;
-use self::actor::{ControlMessage, WorldActor};
-use crate::chat::{ChatSocket, ToClientMessage};
-use crate::lua::LuaHost;
+use self::actor::{BudgetedQuery, ControlMessage, StampedMessage, WorldActor};
+use self::audit::{AuditAction, AuditContext, AuditEntry, AuditLog, AuditQuery, SavedAuditLog};
+use self::history::{ChatHistory, HISTORY_PAGE_SIZE};
+use self::presence::Presence;
+use self::rate_limit::{InputKind, Limiter, RateLimits, RateStats, Verdict};
+use self::sessions::Sessions;
+use crate::chat::{ChatRowContent, ClientConnection, ToClientMessage};
+use crate::lua::{LuaHost, SerializableValue};
 use crate::object::types::Message;
 pub use crate::object::types::*;
 use crate::repo;
 use crate::util::WeakRw;
 use actix;
 use actix::prelude::*;
-use git2;
 use multimap::MultiMap;
 use serde::{Deserialize, Serialize};
 use serde_json;
 pub use state::State;
+use std::collections::{HashMap, HashSet};
 use std::io::{Read, Write};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use uuid::Uuid;
 
 pub struct World {
   state: State,
   actor: actix::Addr<WorldActor>,
   lua_host: LuaHost,
-  chat_connections: MultiMap<Id, actix::Addr<ChatSocket>>,
+  chat_connections: MultiMap<Id, ClientConnection>,
+  chat_history: ChatHistory,
+  audit_log: AuditLog,
+  presence: Presence,
+  panels: HashMap<Id, HashMap<String, SerializableValue>>, // as set by Lua, for new connections
+  // Behind a mutex because messages can be sent (and so missed) with only read access
+  sessions: Mutex<Sessions>,
+  admins: HashSet<String>, // usernames
+  rate_limits: RateLimits,
+  user_limiters: HashMap<Id, Limiter>,
+  rate_stats: RateStats,
+
+  // Whether we still owe the log a report of timers which were overdue at startup
+  startup_report_pending: bool,
+
+  // Bumped by `restore` so the actor knows to start over with the new state
+  generation: u64,
+
+  // So revoking a token can hang up the bots using it
+  bot_connections: Vec<BotConnection>,
 }
 
+struct BotConnection {
+  id: Id,
+  username: String,
+  token_id: String,
+  connection: ClientConnection,
+}
+
+/// What clients are told when they're disconnected by a rollback.
+const RESTORED_MESSAGE: &str = "The world was rolled back to an earlier snapshot; reconnecting.";
+
+/// What bots are told when the token they logged in with is revoked.
+const REVOKED_MESSAGE: &str = "Your token was revoked";
+
 /// Weak reference to the world we can freely share.
 pub type WorldRef = WeakRw<World>;
 
 #[derive(Serialize, Deserialize, Clone)]
 struct SaveState {
   state: State,
+
+  #[serde(default)]
+  chat_history: ChatHistory,
+
+  // the audit log has its own file now; this is only read, from older saves
+  #[serde(default, skip_serializing)]
+  audit_log: SavedAuditLog,
   // Maybe other things like user accounts, etc
 }
 
 impl World {
-  pub fn register_chat_connect(&mut self, id: Id, connection: actix::Addr<ChatSocket>) {
-    self.chat_connections.insert(id, connection)
+  /// Attaches a connection to the user `id` (detaching it from `previous`, if it was
+  /// logged in as someone else), delivering anything queued for them.
+  /// The user object only hears `connected` if they weren't already online.
+  /// Returns the latest page of their history, from before the queued tells
+  /// were delivered (and so recorded), so the caller's backlog doesn't repeat them.
+  pub fn login(
+    &mut self,
+    id: Id,
+    previous: Option<Id>,
+    connection: ClientConnection,
+  ) -> Vec<ChatRowContent> {
+    let (history, _has_more) = self.history_page(id, None, HISTORY_PAGE_SIZE);
+    if previous == Some(id) {
+      // logging in again as the same user on this connection changes nothing
+      return history;
+    }
+
+    if let Some(previous) = previous {
+      self.logout(previous, connection.clone());
+    }
+
+    self.audit(AuditContext::user(id), AuditAction::Login { user: id });
+    let came_online = self.register_chat_connect(id, connection);
+    self.send_panels(id);
+    self.send_prompts(id);
+    self.deliver_pending_tells(id);
+    if came_online {
+      self.send_message(presence_message(id, "connected"));
+    }
+    history
+  }
+
+  /// Writes out the audit log's buffered entries, making sure they're on disk if `sync`.
+  pub fn flush_audit_log(&mut self, sync: bool) {
+    if let Err(e) = self.audit_log.flush(sync) {
+      log::error!("Unable to write out the audit log: {}", e);
+    }
+  }
+
+  /// Records a change to the world, and who made it, in the audit log.
+  pub fn audit(&mut self, context: AuditContext, action: AuditAction) {
+    self.audit_log.record(AuditEntry {
+      time: self.state.get_current_time(),
+      context,
+      action,
+    });
   }
 
-  pub fn remove_chat_connection(&mut self, id: Id, connection: actix::Addr<ChatSocket>) {
+  pub fn search_audit_log(&self, query: &AuditQuery) -> Vec<&AuditEntry> {
+    self.audit_log.search(query)
+  }
+
+  /// Detaches a connection; the user object only hears `disconnected`
+  /// once their last connection goes away.
+  pub fn logout(&mut self, id: Id, connection: ClientConnection) {
+    if self.remove_chat_connection(id, connection) {
+      self.send_message(presence_message(id, "disconnected"));
+    }
+  }
+
+  /// Starts a resumable session for a web connection; see `suspend` and `resume`.
+  pub fn start_session(&mut self, id: Id) -> String {
+    self.sessions.lock().unwrap().start(id)
+  }
+
+  pub fn end_session(&mut self, token: &str) {
+    self.sessions.lock().unwrap().end(token)
+  }
+
+  /// Like `logout`, but the user stays connected for a grace period in which
+  /// another connection can `resume` the session and receive what it missed.
+  pub fn suspend(&mut self, id: Id, connection: ClientConnection, token: &str) {
+    if self.sessions.lock().unwrap().suspend(id, token) {
+      self.detach_connection(id, &connection);
+    } else {
+      self.logout(id, connection);
+    }
+  }
+
+  /// Attaches a connection to one of `id`'s suspended sessions, returning
+  /// a new token for the session and the messages sent while it was suspended.
+  pub fn resume(
+    &mut self,
+    token: &str,
+    id: Id,
+    connection: ClientConnection,
+  ) -> Option<(String, Vec<ToClientMessage>)> {
+    let missed = self.sessions.lock().unwrap().resume(id, token)?;
+    self.chat_connections.insert(id, connection);
+    self.send_panels(id);
+    self.send_prompts(id);
+    let token = self.start_session(id);
+    Some((token, missed))
+  }
+
+  /// Disconnects users whose sessions were suspended and never resumed.
+  pub fn expire_sessions(&mut self) {
+    let expired = self.sessions.lock().unwrap().expire();
+    for id in expired {
+      if self.presence.disconnect(id) {
+        self.send_message(presence_message(id, "disconnected"));
+      }
+    }
+  }
+
+  /// Sends a message from a connected user to their own object.
+  pub fn user_command(&mut self, id: Id, name: &str, payload: SerializableValue) {
+    self.presence.record_activity(id);
+    self.send_message(Message {
+      target: id,
+      original_user: Some(id),
+      immediate_sender: id,
+      name: name.to_string(),
+      payload: payload,
+    });
+  }
+
+  /// Parses something the user typed and sends it to the object which handles it.
+  pub fn command(&mut self, id: Id, text: &str) {
+    self.presence.record_activity(id);
+    let message = commands::command_message(&self.state, id, text);
+    self.send_message(message);
+  }
+
+  /// Returns true if this is the user's first connection.
+  fn register_chat_connect(&mut self, id: Id, connection: ClientConnection) -> bool {
+    self.chat_connections.insert(id, connection);
+    self.presence.connect(id)
+  }
+
+  /// Returns true if this was the user's last connection.
+  fn remove_chat_connection(&mut self, id: Id, connection: ClientConnection) -> bool {
+    self.detach_connection(id, &connection) && self.presence.disconnect(id)
+  }
+
+  /// Remembers which service account token a bot connection logged in with;
+  /// see `disconnect_bots`.
+  pub fn register_bot_token(
+    &mut self,
+    id: Id,
+    username: &str,
+    token_id: &str,
+    connection: ClientConnection,
+  ) {
+    self.bot_connections.push(BotConnection {
+      id,
+      username: username.to_string(),
+      token_id: token_id.to_string(),
+      connection,
+    });
+  }
+
+  /// Logs out the bots using one of `username`'s tokens (or any of them, if
+  /// `token_id` is None), e.g. because it was revoked.
+  pub fn disconnect_bots(&mut self, username: &str, token_id: Option<&str>) {
+    let (revoked, kept): (Vec<BotConnection>, Vec<BotConnection>) =
+      std::mem::replace(&mut self.bot_connections, vec![])
+        .into_iter()
+        .partition(|bot| bot.username == username && token_id.map_or(true, |t| bot.token_id == t));
+    self.bot_connections = kept;
+
+    for bot in revoked {
+      self.logout(bot.id, bot.connection.clone());
+      bot.connection.disconnect(REVOKED_MESSAGE);
+    }
+  }
+
+  /// Stops sending to the connection, without changing the user's presence.
+  fn detach_connection(&mut self, id: Id, connection: &ClientConnection) -> bool {
+    self
+      .bot_connections
+      .retain(|bot| bot.connection != *connection);
     if let Some(connections) = self.chat_connections.get_vec_mut(&id) {
-      if let Some(pos) = connections.iter().position(|x| *x == connection) {
+      if let Some(pos) = connections.iter().position(|x| x == connection) {
         connections.remove(pos);
+        return true;
+      }
+    }
+    false
+  }
+
+  pub fn get_presence(&self) -> &Presence {
+    &self.presence
+  }
+
+  pub fn get_rate_limits(&self) -> &RateLimits {
+    &self.rate_limits
+  }
+
+  pub fn get_rate_stats(&self) -> &RateStats {
+    &self.rate_stats
+  }
+
+  /// Checks input from one of `id`'s connections against both that connection's
+  /// limits and the user's, which are shared by all their connections.
+  /// Without an id, only the connection's limits apply.
+  pub fn check_rate(
+    &mut self,
+    id: Option<Id>,
+    connection: &mut Limiter,
+    kind: InputKind,
+  ) -> Verdict {
+    let limits = &self.rate_limits;
+    let user_limiters = &mut self.user_limiters;
+    let mut user = id.map(|id| {
+      user_limiters
+        .entry(id)
+        .or_insert_with(|| Limiter::new(&limits.per_user, limits.strikes))
+    });
+
+    // input refused by one limit doesn't use up the other
+    let allowed =
+      connection.would_allow(kind) && user.as_mut().map_or(true, |u| u.would_allow(kind));
+
+    if allowed {
+      connection.take(kind);
+      if let Some(user) = user {
+        user.take(kind);
+      }
+      Verdict::Allow
+    } else {
+      self.rate_stats.record_throttled(kind);
+      if connection.strike() {
+        Verdict::Throttle
+      } else {
+        log::warn!("Disconnecting {:?} for flooding", id);
+        self.rate_stats.disconnects += 1;
+        Verdict::Disconnect
+      }
+    }
+  }
+
+  pub fn is_admin(&self, id: Id) -> bool {
+    self
+      .state
+      .username(id)
+      .map(|username| self.admins.contains(&username))
+      .unwrap_or(false)
+  }
+
+  pub fn generation(&self) -> u64 {
+    self.generation
+  }
+
+  /// Reads the state from a saved world (e.g. a snapshot) so it can be compared
+  /// with the current one or `restore`d.
+  pub fn read_snapshot(from: impl Read) -> Result<State, serde_json::error::Error> {
+    let saved: SaveState = serde_json::from_reader(from)?;
+    let mut state = saved.state;
+    prepare_loaded_state(&mut state);
+    Ok(state)
+  }
+
+  /// Swaps the running state for another. Everyone is disconnected, since their
+  /// users may be somewhere else or not exist at all in it; web clients reconnect
+  /// and log in again. Messages already queued for objects are dropped, and the
+  /// actor reloads code and picks up the new game time. Chat history and the
+  /// audit log are kept.
+  pub fn restore(&mut self, state: State) {
+    self.state = state;
+    self.generation += 1;
+    self.presence = Presence::new();
+    self.panels.clear();
+    self.sessions = Mutex::new(Sessions::new());
+    self.user_limiters.clear();
+
+    self.bot_connections.clear();
+
+    let connections = std::mem::replace(&mut self.chat_connections, MultiMap::new());
+    for (_id, connections) in connections.into_iter() {
+      for connection in connections {
+        connection.disconnect(RESTORED_MESSAGE);
       }
     }
+    self.reload_code();
   }
 
   pub fn get_state_mut(&mut self) -> &mut State {
@@ -54,26 +377,45 @@ impl World {
     &self.state
   }
 
-  pub fn pull_and_reload_code(&mut self) -> Result<String, git2::Error> {
-    let result = self.lua_host.fetch()?;
-    self.reload_code();
-    Ok(result)
-  }
-
   pub fn reload_code(&mut self) {
     self.actor.do_send(ControlMessage::ReloadCode);
   }
 
   pub fn send_message(&mut self, message: Message) {
-    self.actor.do_send(message);
+    self.actor.do_send(StampedMessage {
+      message,
+      generation: self.generation,
+    });
+  }
+
+  /// Asks the user's object a question on their behalf, e.g. for completions.
+  /// The Lua code gets at most `budget` to answer.
+  pub fn query_user(
+    &self,
+    id: Id,
+    name: &str,
+    payload: SerializableValue,
+    budget: Duration,
+  ) -> actix::dev::Request<WorldActor, BudgetedQuery> {
+    self.actor.send(BudgetedQuery {
+      message: Message {
+        target: id,
+        original_user: Some(id),
+        immediate_sender: id,
+        name: name.to_string(),
+        payload,
+      },
+      budget,
+    })
   }
 
   pub fn send_client_message(&self, id: Id, message: ToClientMessage) {
+    let missed = self.sessions.lock().unwrap().record_missed(id, &message);
     if let Some(connections) = self.chat_connections.get_vec(&id) {
       for conn in connections.iter() {
         conn.do_send(message.clone());
       }
-    } else {
+    } else if !missed {
       log::warn!(
         "No chat connection for object {}; dropping message {:?}",
         id,
@@ -82,6 +424,215 @@ impl World {
     }
   }
 
+  /// Updates one of the user's panels (replacing what was there, or removing it if
+  /// `value` is nil). Each connection only gets what changed; see `ChatSocket::deliver`.
+  pub fn set_panel(&mut self, id: Id, name: &str, value: SerializableValue) {
+    let panels = self.panels.entry(id).or_insert_with(HashMap::new);
+    if let SerializableValue::Nil = value {
+      panels.remove(name);
+    } else {
+      panels.insert(name.to_string(), value.clone());
+    }
+    self.send_client_message(
+      id,
+      ToClientMessage::Panel {
+        name: name.to_string(),
+        value,
+      },
+    );
+  }
+
+  /// Sends all the user's panels; connections which already have them ignore this.
+  fn send_panels(&self, id: Id) {
+    if let Some(panels) = self.panels.get(&id) {
+      for (name, value) in panels.iter() {
+        self.send_client_message(
+          id,
+          ToClientMessage::Panel {
+            name: name.clone(),
+            value: value.clone(),
+          },
+        );
+      }
+    }
+  }
+
+  /// Asks `user` the question described by `spec`, returning its id. The response
+  /// (or cancellation, or timeout after `timeout` seconds) goes to `owner`
+  /// as a `prompt_response` message.
+  pub fn prompt(&mut self, owner: Id, user: Id, spec: SerializableValue, timeout: u64) -> String {
+    let prompt_id = Uuid::new_v4().to_simple().to_string();
+    let expires_at = self.state.get_current_time() + timeout;
+    self.state.add_prompt(
+      &prompt_id,
+      PendingPrompt {
+        user,
+        owner,
+        spec: spec.clone(),
+        expires_at,
+      },
+    );
+    self.send_client_message(
+      user,
+      ToClientMessage::Prompt {
+        id: prompt_id.clone(),
+        spec,
+      },
+    );
+    prompt_id
+  }
+
+  /// Delivers the user's answers (or None if they cancelled) to the object which asked.
+  /// Returns false if there's no such prompt for them, e.g. it timed out.
+  pub fn respond_to_prompt(
+    &mut self,
+    user: Id,
+    prompt_id: &str,
+    answers: Option<SerializableValue>,
+  ) -> bool {
+    match self.state.get_prompt(prompt_id) {
+      Some(prompt) if prompt.user == user => (),
+      _ => return false,
+    }
+
+    if let Some(prompt) = self.state.take_prompt(prompt_id) {
+      let status = if answers.is_some() {
+        "answered"
+      } else {
+        "cancelled"
+      };
+      self.finish_prompt(prompt_id, prompt, status, answers);
+    }
+    true
+  }
+
+  fn finish_prompt(
+    &mut self,
+    prompt_id: &str,
+    prompt: PendingPrompt,
+    status: &str,
+    answers: Option<SerializableValue>,
+  ) {
+    // closes it on any other connections
+    self.send_client_message(
+      prompt.user,
+      ToClientMessage::PromptClosed {
+        id: prompt_id.to_string(),
+      },
+    );
+
+    let mut payload = HashMap::new();
+    payload.insert(
+      "id".to_string(),
+      SerializableValue::String(prompt_id.to_string()),
+    );
+    payload.insert(
+      "user".to_string(),
+      SerializableValue::String(prompt.user.to_string()),
+    );
+    payload.insert(
+      "status".to_string(),
+      SerializableValue::String(status.to_string()),
+    );
+    if let Some(answers) = answers {
+      payload.insert("answers".to_string(), answers);
+    }
+
+    self.send_message(Message {
+      target: prompt.owner,
+      original_user: Some(prompt.user),
+      immediate_sender: prompt.user,
+      name: "prompt_response".to_string(),
+      payload: SerializableValue::Dict(payload),
+    });
+  }
+
+  /// Sends the user any prompts they haven't answered yet.
+  fn send_prompts(&self, id: Id) {
+    for (prompt_id, prompt) in self.state.prompts_for(id) {
+      self.send_client_message(
+        id,
+        ToClientMessage::Prompt {
+          id: prompt_id.to_string(),
+          spec: prompt.spec.clone(),
+        },
+      );
+    }
+  }
+
+  /// Tells the user something, remembering it in their history even if they aren't connected.
+  pub fn send_tell(&mut self, id: Id, content: ChatRowContent) {
+    if let Some(username) = self.state.username(id) {
+      self.chat_history.record(&username, content.clone());
+    }
+    self.send_client_message(id, ToClientMessage::Tell { content });
+  }
+
+  /// Like `send_tell`, but if the user isn't connected we hold on to it
+  /// for `expires_in` seconds and deliver it when they next log in.
+  pub fn send_durable_tell(&mut self, id: Id, content: ChatRowContent, expires_in: u64) {
+    if self.is_connected(id) {
+      self.send_tell(id, content);
+    } else if let Some(username) = self.state.username(id) {
+      let expires_at = self.state.get_current_time() + expires_in;
+      self.state.queue_tell(
+        &username,
+        PendingTell {
+          html: content.html(),
+          expires_at,
+        },
+      );
+    } else {
+      log::warn!("Dropping durable tell for non-user object {}", id);
+    }
+  }
+
+  /// Sends along anything queued by `send_durable_tell` while the user was away.
+  fn deliver_pending_tells(&mut self, id: Id) {
+    if let Some(username) = self.state.username(id) {
+      for tell in self.state.take_pending_tells(&username) {
+        // this was already sanitized (if needed) before it was queued
+        self.send_tell(id, ChatRowContent::new_trusted_html(&tell.html));
+      }
+    }
+  }
+
+  pub fn is_connected(&self, id: Id) -> bool {
+    self.presence.is_online(id)
+  }
+
+  /// Starts an empty history with a backlog kept by the user's code (from before the
+  /// server kept history) and sends it. Logging in already sent any history there
+  /// was, so otherwise this does nothing rather than send it twice.
+  pub fn seed_history(&mut self, id: Id, backlog: Vec<ChatRowContent>) {
+    let username = match self.state.username(id) {
+      Some(username) => username,
+      None => return,
+    };
+    if !self.chat_history.is_empty(&username) {
+      return;
+    }
+    for content in backlog {
+      self.chat_history.record(&username, content);
+    }
+    let (history, _has_more) = self.history_page(id, None, HISTORY_PAGE_SIZE);
+    self.send_client_message(id, ToClientMessage::Backlog { history });
+  }
+
+  /// A page of the user's chat history; see `ChatHistory::page`.
+  pub fn history_page(
+    &self,
+    id: Id,
+    before: Option<&str>,
+    limit: usize,
+  ) -> (Vec<ChatRowContent>, bool) {
+    self
+      .state
+      .username(id)
+      .map(|username| self.chat_history.page(&username, before, limit))
+      .unwrap_or((vec![], false))
+  }
+
   pub fn get_lua_host(&self) -> &LuaHost {
     &self.lua_host
   }
@@ -90,18 +641,26 @@ impl World {
     arbiter: &actix::Arbiter,
     lua_path: &std::path::Path,
     git_config: Option<repo::Repo>,
+    admins: HashSet<String>,
+    rate_limits: RateLimits,
     from: Option<impl Read>,
   ) -> Result<(Arc<RwLock<Option<World>>>, WorldRef), serde_json::error::Error> {
     let arc = Arc::new(RwLock::new(None));
     let world_ref = WorldRef::new(&arc);
 
-    let state = match from {
-      None => State::new(),
-      Some(r) => {
-        let state: SaveState = serde_json::from_reader(r)?;
-        state.state
-      }
+    let mut saved = match from {
+      None => SaveState {
+        state: State::new(),
+        chat_history: ChatHistory::new(),
+        audit_log: SavedAuditLog::default(),
+      },
+      Some(r) => serde_json::from_reader(r)?,
     };
+    prepare_loaded_state(&mut saved.state);
+    let audit_log = AuditLog::open(
+      &snapshots::state_directory(),
+      std::mem::take(&mut saved.audit_log),
+    );
 
     let lua_host = LuaHost::new(lua_path, git_config).unwrap();
     
@@ -113,10 +672,22 @@ impl World {
       let arc = arc.clone();
       WorldActor::start_in_arbiter(arbiter, move |ctx| {
         let world = World {
-          state: state,
+          state: saved.state,
           actor: ctx.address(),
           chat_connections: MultiMap::new(),
+          chat_history: saved.chat_history,
+          audit_log,
+          presence: Presence::new(),
+          panels: HashMap::new(),
+          sessions: Mutex::new(Sessions::new()),
+          admins: admins,
+          rate_limits,
+          user_limiters: HashMap::new(),
+          rate_stats: RateStats::default(),
           lua_host: lua_host.clone(),
+          startup_report_pending: true,
+          generation: 0,
+          bot_connections: vec![],
         };
 
         *arc.write().unwrap() = Some(world);
@@ -132,20 +703,373 @@ impl World {
     // TODO: this drops any oustanding (queued in actor) messages.
     let state = SaveState {
       state: self.state.clone(),
+      chat_history: self.chat_history.clone(),
+      audit_log: SavedAuditLog::default(),
     };
     serde_json::to_writer_pretty(w, &state)
   }
 
   pub fn advance_time(&mut self, new_time: GameTime) {
-    for (id, timer) in self.state.extract_ready_timers(new_time) {
-      self.actor.do_send(Message {
-        immediate_sender: id,
-        target: id,
+    let current_time = self.state.get_current_time();
+    let ready = self.state.extract_ready_timers(new_time);
+    let (deliveries, late_report) = resolve_timers(ready, current_time, new_time);
+    for (id, timer) in deliveries {
+      self.fire_timer(id, timer);
+    }
+
+    for (prompt_id, prompt) in self.state.take_expired_prompts(new_time) {
+      self.finish_prompt(&prompt_id, prompt, "timeout", None);
+    }
+
+    if self.startup_report_pending {
+      self.startup_report_pending = false;
+      log::info!(
+        "Startup timer report: {} overdue timer(s) handled",
+        late_report.len()
+      );
+      for line in late_report.iter() {
+        log::info!("  {}", line);
+      }
+    } else if !late_report.is_empty() {
+      log::warn!("Handled overdue timers: {}", late_report.join("; "));
+    }
+
+    self.state.set_current_time(new_time);
+    self.flush_audit_log(false);
+  }
+
+  fn fire_timer(&self, owner: Id, timer: Timer) {
+    self.actor.do_send(StampedMessage {
+      message: Message {
+        immediate_sender: owner,
+        target: timer.target.unwrap_or(owner),
         name: timer.message_name,
         original_user: timer.original_user,
         payload: timer.payload,
-      });
+      },
+      generation: self.generation,
+    });
+  }
+}
+
+/// Indexes and the default quota aren't saved, so loaded states need them set up.
+fn prepare_loaded_state(state: &mut State) {
+  state.rebuild_indexes(query::indexed_attrs());
+  state.set_default_quota(quotas::Quota::load());
+}
+
+fn presence_message(id: Id, name: &str) -> Message {
+  Message {
+    target: id,
+    original_user: Some(id),
+    immediate_sender: id,
+    name: name.to_string(),
+    payload: SerializableValue::Nil,
+  }
+}
+
+/// Works out what to deliver for timers which are ready at `new_time`: those which
+/// came due since `current_time` go as they are, and overdue ones according to their
+/// `OverduePolicy`. Also returns a line for the log about each overdue timer.
+fn resolve_timers(
+  ready: Vec<(Id, String, Timer)>,
+  current_time: GameTime,
+  new_time: GameTime,
+) -> (Vec<(Id, Timer)>, Vec<String>) {
+  let mut deliveries = vec![];
+  let mut late_report = vec![];
+  let mut coalesced: HashMap<(Id, Option<Id>, String), Vec<Timer>> = HashMap::new();
+
+  for (id, name, timer) in ready {
+    if timer.target_time > current_time {
+      deliveries.push((id, timer));
+      continue;
     }
-    self.state.set_current_time(new_time);
+
+    let late_by = new_time - timer.target_time;
+    late_report.push(format!(
+      "{} {} ({}) late by {}s: {:?}",
+      id, name, timer.message_name, late_by, timer.overdue
+    ));
+
+    match timer.overdue {
+      OverduePolicy::FireLate => deliveries.push((id, timer)),
+      OverduePolicy::Annotate => {
+        let payload = late_payload(timer.payload.clone(), late_by, None);
+        deliveries.push((id, Timer { payload, ..timer }));
+      }
+      OverduePolicy::Coalesce => coalesced
+        .entry((id, timer.target, timer.message_name.clone()))
+        .or_insert_with(|| vec![])
+        .push(timer),
+      OverduePolicy::Drop => {
+        let mut info = HashMap::new();
+        info.insert("name".to_string(), SerializableValue::String(name));
+        info.insert(
+          "message_name".to_string(),
+          SerializableValue::String(timer.message_name.clone()),
+        );
+        info.insert(
+          "late_by".to_string(),
+          SerializableValue::Integer(late_by as i64),
+        );
+        deliveries.push((
+          id,
+          Timer {
+            message_name: "delay_dropped".to_string(),
+            payload: SerializableValue::Dict(info),
+            target: None,
+            ..timer
+          },
+        ));
+      }
+    }
+  }
+
+  for ((id, _target, _message_name), timers) in coalesced.drain() {
+    let count = timers.len();
+    if let Some(latest) = timers.into_iter().max_by_key(|t| t.target_time) {
+      let late_by = new_time - latest.target_time;
+      let payload = late_payload(latest.payload.clone(), late_by, Some(count));
+      deliveries.push((id, Timer { payload, ..latest }));
+    }
+  }
+
+  (deliveries, late_report)
+}
+
+/// Wraps an overdue timer's payload as `{payload = ..., late_by = ...}`, plus
+/// `coalesced` if given, so none of the payload's own keys are touched.
+fn late_payload(
+  payload: SerializableValue,
+  late_by: u64,
+  coalesced: Option<usize>,
+) -> SerializableValue {
+  let mut dict = HashMap::new();
+  dict.insert("payload".to_string(), payload);
+  dict.insert(
+    "late_by".to_string(),
+    SerializableValue::Integer(late_by as i64),
+  );
+  if let Some(count) = coalesced {
+    dict.insert(
+      "coalesced".to_string(),
+      SerializableValue::Integer(count as i64),
+    );
+  }
+  SerializableValue::Dict(dict)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn timer(
+    at: u64,
+    message_name: &str,
+    payload: SerializableValue,
+    overdue: OverduePolicy,
+    target: Option<Id>,
+  ) -> Timer {
+    Timer {
+      target_time: GameTime::default() + at,
+      original_user: None,
+      message_name: message_name.to_string(),
+      payload,
+      overdue,
+      target,
+    }
+  }
+
+  fn dict(value: &SerializableValue) -> &HashMap<String, SerializableValue> {
+    match value {
+      SerializableValue::Dict(dict) => dict,
+      other => panic!("Expected a dict, got {:?}", other),
+    }
+  }
+
+  fn late_by_key_payload() -> SerializableValue {
+    let mut payload = HashMap::new();
+    payload.insert(
+      "late_by".to_string(),
+      SerializableValue::String("mine".to_string()),
+    );
+    SerializableValue::Dict(payload)
+  }
+
+  fn resolve(ready: Vec<(Id, String, Timer)>) -> Vec<(Id, Timer)> {
+    let (deliveries, _report) =
+      resolve_timers(ready, GameTime::default() + 100, GameTime::default() + 110);
+    deliveries
+  }
+
+  #[test]
+  fn timers_due_since_the_last_tick_are_delivered_as_they_are() {
+    let ready = vec![(
+      Id(1),
+      "a".to_string(),
+      timer(
+        105,
+        "tick",
+        late_by_key_payload(),
+        OverduePolicy::Drop,
+        None,
+      ),
+    )];
+    let (deliveries, report) =
+      resolve_timers(ready, GameTime::default() + 100, GameTime::default() + 110);
+    assert!(report.is_empty());
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].1.message_name, "tick");
+    assert_eq!(deliveries[0].1.payload, late_by_key_payload());
+  }
+
+  #[test]
+  fn overdue_fire_late_leaves_the_payload_alone() {
+    let ready = vec![
+      (
+        Id(1),
+        "a".to_string(),
+        timer(
+          90,
+          "tick",
+          late_by_key_payload(),
+          OverduePolicy::FireLate,
+          None,
+        ),
+      ),
+      (
+        Id(1),
+        "b".to_string(),
+        timer(
+          95,
+          "tock",
+          SerializableValue::Integer(3),
+          OverduePolicy::FireLate,
+          None,
+        ),
+      ),
+    ];
+    let mut deliveries = resolve(ready);
+    deliveries.sort_by_key(|(_id, t)| t.target_time);
+    assert_eq!(deliveries.len(), 2);
+    assert_eq!(deliveries[0].1.payload, late_by_key_payload());
+    assert_eq!(deliveries[1].1.payload, SerializableValue::Integer(3));
+  }
+
+  #[test]
+  fn overdue_annotate_wraps_the_payload() {
+    let ready = vec![(
+      Id(1),
+      "a".to_string(),
+      timer(
+        90,
+        "tick",
+        late_by_key_payload(),
+        OverduePolicy::Annotate,
+        None,
+      ),
+    )];
+    let deliveries = resolve(ready);
+    assert_eq!(deliveries.len(), 1);
+    let payload = dict(&deliveries[0].1.payload);
+    assert_eq!(payload["late_by"], SerializableValue::Integer(20));
+    assert_eq!(payload["payload"], late_by_key_payload());
+    assert!(!payload.contains_key("coalesced"));
+  }
+
+  #[test]
+  fn overdue_coalesce_delivers_the_latest_per_target_and_message() {
+    let ready = vec![
+      (
+        Id(1),
+        "a".to_string(),
+        timer(
+          80,
+          "tick",
+          SerializableValue::Integer(1),
+          OverduePolicy::Coalesce,
+          Some(Id(2)),
+        ),
+      ),
+      (
+        Id(1),
+        "b".to_string(),
+        timer(
+          90,
+          "tick",
+          SerializableValue::Integer(2),
+          OverduePolicy::Coalesce,
+          Some(Id(2)),
+        ),
+      ),
+      (
+        Id(1),
+        "c".to_string(),
+        timer(
+          85,
+          "tick",
+          SerializableValue::Integer(3),
+          OverduePolicy::Coalesce,
+          Some(Id(3)),
+        ),
+      ),
+      (
+        Id(1),
+        "d".to_string(),
+        timer(
+          85,
+          "tock",
+          SerializableValue::Nil,
+          OverduePolicy::Coalesce,
+          Some(Id(2)),
+        ),
+      ),
+    ];
+    let mut deliveries = resolve(ready);
+    assert_eq!(deliveries.len(), 3);
+    deliveries.retain(|(_id, t)| t.message_name == "tick");
+    deliveries.sort_by_key(|(_id, t)| t.target.map(|id| id.0));
+
+    let to_2 = dict(&deliveries[0].1.payload);
+    assert_eq!(deliveries[0].1.target, Some(Id(2)));
+    assert_eq!(to_2["payload"], SerializableValue::Integer(2));
+    assert_eq!(to_2["late_by"], SerializableValue::Integer(20));
+    assert_eq!(to_2["coalesced"], SerializableValue::Integer(2));
+
+    let to_3 = dict(&deliveries[1].1.payload);
+    assert_eq!(deliveries[1].1.target, Some(Id(3)));
+    assert_eq!(to_3["payload"], SerializableValue::Integer(3));
+    assert_eq!(to_3["coalesced"], SerializableValue::Integer(1));
+  }
+
+  #[test]
+  fn overdue_drop_tells_the_owner_instead() {
+    let ready = vec![(
+      Id(1),
+      "a".to_string(),
+      timer(
+        90,
+        "tick",
+        SerializableValue::Integer(1),
+        OverduePolicy::Drop,
+        Some(Id(2)),
+      ),
+    )];
+    let (deliveries, report) =
+      resolve_timers(ready, GameTime::default() + 100, GameTime::default() + 110);
+    assert_eq!(report.len(), 1);
+    assert_eq!(deliveries.len(), 1);
+    let (id, dropped) = &deliveries[0];
+    assert_eq!(*id, Id(1));
+    assert_eq!(dropped.message_name, "delay_dropped");
+    assert_eq!(dropped.target, None);
+    let info = dict(&dropped.payload);
+    assert_eq!(info["name"], SerializableValue::String("a".to_string()));
+    assert_eq!(
+      info["message_name"],
+      SerializableValue::String("tick".to_string())
+    );
+    assert_eq!(info["late_by"], SerializableValue::Integer(20));
   }
 }
@@ -0,0 +1,314 @@
+use crate::lua::SerializableValue;
+use crate::object::types::Id;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+
+/// The attrs objects are conventionally named by: `name` is a string and
+/// `aliases` a list of strings.
+pub const NAME_ATTRS: &[&str] = &["name", "aliases"];
+
+const ARTICLES: &[&str] = &["the", "a", "an"];
+
+const ORDINAL_WORDS: &[&str] = &[
+  "first", "second", "third", "fourth", "fifth", "sixth", "seventh", "eighth", "ninth", "tenth",
+];
+
+/// Where to look for objects, relative to the one doing the looking.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+  Inventory, // what it contains
+  Room,      // its container and everything else in there
+  Nearby,    // both of those
+  World,     // everything
+}
+
+impl FromStr for Scope {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Scope, String> {
+    match s {
+      "inventory" => Ok(Scope::Inventory),
+      "room" => Ok(Scope::Room),
+      "nearby" => Ok(Scope::Nearby),
+      "world" => Ok(Scope::World),
+      _ => Err(format!(
+        "Unknown scope {}; expected inventory, room, nearby or world",
+        s
+      )),
+    }
+  }
+}
+
+/// How well a name matched, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rank {
+  Exact,  // "red key" for "red key"
+  Prefix, // "red k" for "red key"
+  Words,  // "k r" for "red key": every word starts one of the name's words
+}
+
+#[derive(Debug, Clone)]
+pub struct NameMatch {
+  pub id: Id,
+  pub rank: Rank,
+}
+
+/// What someone typed to refer to an object: e.g. "the 2nd red key" is the words
+/// ["red", "key"] with ordinal 2.
+#[derive(Debug)]
+pub struct NameQuery {
+  text: String,
+  words: Vec<String>,
+  ordinal: Option<usize>,
+}
+
+impl NameQuery {
+  pub fn parse(text: &str) -> NameQuery {
+    let mut words: Vec<String> = text
+      .split_whitespace()
+      .map(|w| w.to_lowercase())
+      .filter(|w| !ARTICLES.contains(&w.as_str()))
+      .collect();
+
+    let ordinal = words.first().and_then(|w| ordinal(w));
+    if ordinal.is_some() {
+      words.remove(0);
+    }
+
+    NameQuery {
+      text: words.join(" "),
+      words,
+      ordinal,
+    }
+  }
+
+  pub fn first_word(&self) -> Option<&str> {
+    self.words.first().map(|w| w.as_str())
+  }
+
+  fn rank(&self, name: &str) -> Option<Rank> {
+    if name == self.text {
+      Some(Rank::Exact)
+    } else if name.starts_with(&self.text) {
+      Some(Rank::Prefix)
+    } else if self
+      .words
+      .iter()
+      .all(|q| name.split_whitespace().any(|w| w.starts_with(q.as_str())))
+    {
+      Some(Rank::Words)
+    } else {
+      None
+    }
+  }
+}
+
+/// "2nd" or "second" -> 2
+fn ordinal(word: &str) -> Option<usize> {
+  if let Some(position) = ORDINAL_WORDS.iter().position(|w| *w == word) {
+    return Some(position + 1);
+  }
+  let digits = word.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+  let suffix = &word[digits.len()..];
+  match suffix {
+    "st" | "nd" | "rd" | "th" => digits.parse().ok().filter(|n| *n > 0),
+    _ => None,
+  }
+}
+
+/// Every object's names (from NAME_ATTRS), lowercased, plus an index from the
+/// words in them to the objects so we can search the whole world by prefix.
+/// This isn't saved; it's rebuilt from attrs when the state is loaded.
+#[derive(Clone, Default)]
+pub struct NameIndex {
+  names: HashMap<Id, Vec<String>>,
+  words: BTreeMap<String, HashSet<Id>>,
+}
+
+impl NameIndex {
+  /// Updates the names for `id` from its attrs.
+  pub fn update(&mut self, id: Id, attrs: &HashMap<String, SerializableValue>) {
+    let mut names = vec![];
+    if let Some(SerializableValue::String(name)) = attrs.get("name") {
+      names.push(name.to_lowercase());
+    }
+    if let Some(SerializableValue::Table(aliases)) = attrs.get("aliases") {
+      names.extend(aliases.iter().filter_map(|(_, alias)| match alias {
+        SerializableValue::String(alias) => Some(alias.to_lowercase()),
+        _ => None,
+      }));
+    }
+
+    if let Some(old) = self.names.remove(&id) {
+      for word in old.iter().flat_map(|n| n.split_whitespace()) {
+        let now_empty = match self.words.get_mut(word) {
+          Some(ids) => {
+            ids.remove(&id);
+            ids.is_empty()
+          }
+          None => false,
+        };
+        if now_empty {
+          self.words.remove(word);
+        }
+      }
+    }
+
+    for word in names.iter().flat_map(|n| n.split_whitespace()) {
+      self
+        .words
+        .entry(word.to_string())
+        .or_insert_with(HashSet::new)
+        .insert(id);
+    }
+    if !names.is_empty() {
+      self.names.insert(id, names);
+    }
+  }
+
+  fn names(&self, id: Id) -> &[String] {
+    self.names.get(&id).map(|n| n.as_slice()).unwrap_or(&[])
+  }
+
+  /// Objects with a name containing a word starting with `prefix`.
+  pub fn with_word_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = Id> + 'a {
+    self
+      .words
+      .range(prefix.to_string()..)
+      .take_while(move |(word, _)| word.starts_with(prefix))
+      .flat_map(|(_, ids)| ids.iter().cloned())
+  }
+
+  /// Ranks `candidates` (in order of preference) against the query, best
+  /// first; with an ordinal, only the one it picks out is returned.
+  pub fn find(&self, query: &NameQuery, candidates: impl Iterator<Item = Id>) -> Vec<NameMatch> {
+    if query.words.is_empty() {
+      return vec![];
+    }
+
+    let mut seen = HashSet::new();
+    let mut matches: Vec<NameMatch> = candidates
+      .filter(|id| seen.insert(*id))
+      .filter_map(|id| {
+        self
+          .names(id)
+          .iter()
+          .filter_map(|name| query.rank(name))
+          .min()
+          .map(|rank| NameMatch { id, rank })
+      })
+      .collect();
+    // stable, so ties stay in order of preference
+    matches.sort_by_key(|m| m.rank);
+
+    match query.ordinal {
+      Some(n) => matches.into_iter().nth(n - 1).into_iter().collect(),
+      None => matches,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn named(name: &str, aliases: &[&str]) -> HashMap<String, SerializableValue> {
+    let mut attrs = HashMap::new();
+    attrs.insert(
+      "name".to_string(),
+      SerializableValue::String(name.to_string()),
+    );
+    attrs.insert(
+      "aliases".to_string(),
+      SerializableValue::Table(
+        aliases
+          .iter()
+          .enumerate()
+          .map(|(i, a)| {
+            (
+              SerializableValue::Integer(i as i64 + 1),
+              SerializableValue::String(a.to_string()),
+            )
+          })
+          .collect(),
+      ),
+    );
+    attrs
+  }
+
+  fn index() -> NameIndex {
+    let mut index = NameIndex::default();
+    index.update(Id(1), &named("Red Key", &[]));
+    index.update(Id(2), &named("key", &[]));
+    index.update(Id(3), &named("lamp", &["blue key"]));
+    index.update(Id(4), &named("keyring", &[]));
+    index
+  }
+
+  fn find(index: &NameIndex, text: &str) -> Vec<(usize, Rank)> {
+    index
+      .find(&NameQuery::parse(text), (1..=4).map(Id))
+      .into_iter()
+      .map(|m| (m.id.0, m.rank))
+      .collect()
+  }
+
+  #[test]
+  fn articles_are_dropped() {
+    let query = NameQuery::parse("The red key");
+    assert_eq!(query.words, vec!["red", "key"]);
+    assert_eq!(query.text, "red key");
+    assert_eq!(query.ordinal, None);
+    assert_eq!(NameQuery::parse("an apple").first_word(), Some("apple"));
+  }
+
+  #[test]
+  fn ordinals() {
+    let query = NameQuery::parse("the 2nd key");
+    assert_eq!(query.words, vec!["key"]);
+    assert_eq!(query.ordinal, Some(2));
+    assert_eq!(NameQuery::parse("second key").ordinal, Some(2));
+    assert_eq!(NameQuery::parse("21st key").ordinal, Some(21));
+
+    // not ordinals, so they stay part of the name
+    assert_eq!(NameQuery::parse("0th key").words, vec!["0th", "key"]);
+    assert_eq!(NameQuery::parse("2x key").ordinal, None);
+  }
+
+  #[test]
+  fn ranks_best_first() {
+    assert_eq!(
+      find(&index(), "key"),
+      vec![
+        (2, Rank::Exact),
+        (4, Rank::Prefix),
+        (1, Rank::Words),
+        (3, Rank::Words)
+      ]
+    );
+    assert_eq!(find(&index(), "the RED key"), vec![(1, Rank::Exact)]);
+    assert_eq!(find(&index(), "k r"), vec![(1, Rank::Words)]);
+    assert_eq!(find(&index(), "bl"), vec![(3, Rank::Prefix)]);
+  }
+
+  #[test]
+  fn ordinal_picks_one_match() {
+    assert_eq!(find(&index(), "2nd key"), vec![(4, Rank::Prefix)]);
+    assert_eq!(find(&index(), "the first key"), vec![(2, Rank::Exact)]);
+    assert!(find(&index(), "5th key").is_empty());
+    assert!(find(&index(), "2nd").is_empty());
+    assert!(find(&index(), "the").is_empty());
+  }
+
+  #[test]
+  fn updates_replace_old_names() {
+    let mut index = index();
+    index.update(Id(3), &named("lantern", &[]));
+    assert!(find(&index, "blue").is_empty());
+    assert_eq!(find(&index, "lantern"), vec![(3, Rank::Exact)]);
+
+    let mut ids: Vec<usize> = index.with_word_prefix("ke").map(|id| id.0).collect();
+    ids.sort();
+    assert_eq!(ids, vec![1, 2, 4]);
+  }
+}
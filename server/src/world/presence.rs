@@ -0,0 +1,112 @@
+use crate::object::types::Id;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Session {
+  connections: usize,
+  last_activity: Instant,
+}
+
+/// Tracks which users are online across all their connections,
+/// so we can tell a user's first connection and last disconnection
+/// apart from them opening or closing another tab.
+pub struct Presence {
+  sessions: HashMap<Id, Session>,
+}
+
+impl Presence {
+  pub fn new() -> Presence {
+    Presence {
+      sessions: HashMap::new(),
+    }
+  }
+
+  /// Returns true if this is the user's first connection (i.e. they just came online).
+  pub fn connect(&mut self, id: Id) -> bool {
+    let session = self.sessions.entry(id).or_insert_with(|| Session {
+      connections: 0,
+      last_activity: Instant::now(),
+    });
+    session.connections += 1;
+    session.connections == 1
+  }
+
+  /// Returns true if this was the user's last connection (i.e. they just went offline).
+  pub fn disconnect(&mut self, id: Id) -> bool {
+    match self.sessions.get_mut(&id) {
+      None => false,
+      Some(session) => {
+        session.connections -= 1;
+        if session.connections == 0 {
+          self.sessions.remove(&id);
+          true
+        } else {
+          false
+        }
+      }
+    }
+  }
+
+  pub fn record_activity(&mut self, id: Id) {
+    if let Some(session) = self.sessions.get_mut(&id) {
+      session.last_activity = Instant::now();
+    }
+  }
+
+  pub fn is_online(&self, id: Id) -> bool {
+    self.sessions.contains_key(&id)
+  }
+
+  pub fn idle_time(&self, id: Id) -> Option<Duration> {
+    self
+      .sessions
+      .get(&id)
+      .map(|session| session.last_activity.elapsed())
+  }
+
+  pub fn online(&self) -> impl Iterator<Item = Id> + '_ {
+    self.sessions.keys().cloned()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_the_first_connection_and_last_disconnection_count() {
+    let mut presence = Presence::new();
+    assert!(presence.connect(Id(1)));
+    assert!(!presence.connect(Id(1)));
+    assert!(presence.connect(Id(2)));
+    assert!(presence.is_online(Id(1)));
+
+    assert!(!presence.disconnect(Id(1)));
+    assert!(presence.is_online(Id(1)));
+    assert!(presence.disconnect(Id(1)));
+    assert!(!presence.is_online(Id(1)));
+
+    // disconnecting again (or someone who was never here) isn't a transition
+    assert!(!presence.disconnect(Id(1)));
+    assert!(!presence.disconnect(Id(3)));
+    assert_eq!(presence.online().collect::<Vec<Id>>(), vec![Id(2)]);
+
+    // and coming back is
+    assert!(presence.connect(Id(1)));
+  }
+
+  #[test]
+  fn activity_resets_idle_time() {
+    let mut presence = Presence::new();
+    assert_eq!(presence.idle_time(Id(1)), None);
+    presence.connect(Id(1));
+    presence.sessions.get_mut(&Id(1)).unwrap().last_activity -= Duration::from_secs(60);
+    assert!(presence.idle_time(Id(1)).unwrap() >= Duration::from_secs(60));
+
+    presence.record_activity(Id(1));
+    assert!(presence.idle_time(Id(1)).unwrap() < Duration::from_secs(60));
+    // only for users who are online
+    presence.record_activity(Id(2));
+    assert!(!presence.is_online(Id(2)));
+  }
+}
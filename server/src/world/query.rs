@@ -0,0 +1,464 @@
+use crate::lua::SerializableValue;
+use crate::object::types::Id;
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+/// Queries never return more objects than this.
+pub const MAX_QUERY_RESULTS: usize = 1000;
+
+/// Attrs to keep indexes for, from `ORISA_INDEXED_ATTRS` (comma separated).
+/// Queries work on any attr; these are just faster to query by value.
+pub fn indexed_attrs() -> HashSet<String> {
+  env::var("ORISA_INDEXED_ATTRS")
+    .map(|attrs| {
+      attrs
+        .split(',')
+        .map(|a| a.trim().to_string())
+        .filter(|a| !a.is_empty())
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// A condition on an object's attrs. Written as tables like:
+/// * `{attr = "owner", equals = "#5"}`
+/// * `{attr = "name", exists = true}`
+/// * `{attr = "price", min = 1, max = 10}` (inclusive; either may be left out)
+/// * `{attr = "tags", contains = "shop"}` (an item of a list, or part of a string)
+/// * `{all = {...}}`, `{any = {...}}` and `{["not"] = {...}}`
+#[derive(Debug, Clone)]
+pub enum Filter {
+  Equals(String, SerializableValue),
+  Exists(String, bool),
+  Range(String, Option<f64>, Option<f64>),
+  Contains(String, SerializableValue),
+  All(Vec<Filter>),
+  Any(Vec<Filter>),
+  Not(Box<Filter>),
+}
+
+impl Filter {
+  pub fn parse(value: &SerializableValue) -> Result<Filter, String> {
+    if let Some(filters) = field(value, "all") {
+      return Ok(Filter::All(Filter::parse_list(filters)?));
+    }
+    if let Some(filters) = field(value, "any") {
+      return Ok(Filter::Any(Filter::parse_list(filters)?));
+    }
+    if let Some(filter) = field(value, "not") {
+      return Ok(Filter::Not(Box::new(Filter::parse(filter)?)));
+    }
+
+    let attr = match field(value, "attr") {
+      Some(SerializableValue::String(attr)) => attr.clone(),
+      _ => return Err("A filter needs an attr (or all, any or not)".to_string()),
+    };
+    if let Some(expected) = field(value, "equals") {
+      return Ok(Filter::Equals(attr, expected.clone()));
+    }
+    if let Some(expected) = field(value, "contains") {
+      return Ok(Filter::Contains(attr, expected.clone()));
+    }
+    if let Some(exists) = field(value, "exists") {
+      return match exists {
+        SerializableValue::Boolean(exists) => Ok(Filter::Exists(attr, *exists)),
+        _ => Err("exists must be true or false".to_string()),
+      };
+    }
+
+    let bound = |name: &str| match field(value, name) {
+      None => Ok(None),
+      Some(bound) => number(bound)
+        .map(Some)
+        .ok_or_else(|| format!("{} must be a number", name)),
+    };
+    let (min, max) = (bound("min")?, bound("max")?);
+    if min.is_none() && max.is_none() {
+      return Err(format!(
+        "The filter on {} needs one of equals, contains, exists, min or max",
+        attr
+      ));
+    }
+    Ok(Filter::Range(attr, min, max))
+  }
+
+  fn parse_list(value: &SerializableValue) -> Result<Vec<Filter>, String> {
+    list(value)
+      .ok_or_else(|| "all and any take a list of filters".to_string())?
+      .into_iter()
+      .map(Filter::parse)
+      .collect()
+  }
+
+  pub fn matches(&self, attrs: &HashMap<String, SerializableValue>) -> bool {
+    match self {
+      Filter::Equals(attr, expected) => attrs.get(attr).map_or(false, |v| same(v, expected)),
+      Filter::Exists(attr, exists) => attrs.contains_key(attr) == *exists,
+      Filter::Range(attr, min, max) => match attrs.get(attr).and_then(number) {
+        Some(n) => min.map_or(true, |min| n >= min) && max.map_or(true, |max| n <= max),
+        None => false,
+      },
+      Filter::Contains(attr, expected) => match (attrs.get(attr), expected) {
+        (Some(SerializableValue::String(s)), SerializableValue::String(part)) => s.contains(part),
+        (Some(value), _) => {
+          list(value).map_or(false, |items| items.into_iter().any(|v| same(v, expected)))
+        }
+        (None, _) => false,
+      },
+      Filter::All(filters) => filters.iter().all(|f| f.matches(attrs)),
+      Filter::Any(filters) => filters.iter().any(|f| f.matches(attrs)),
+      Filter::Not(filter) => !filter.matches(attrs),
+    }
+  }
+}
+
+/// Looks up a key in a table from Lua or a dict from JSON.
+fn field<'a>(value: &'a SerializableValue, key: &str) -> Option<&'a SerializableValue> {
+  match value {
+    SerializableValue::Dict(fields) => fields.get(key),
+    SerializableValue::Table(pairs) => pairs
+      .iter()
+      .find(|(k, _)| *k == SerializableValue::String(key.to_string()))
+      .map(|(_, v)| v),
+    _ => None,
+  }
+}
+
+/// The items of a list-like table, in order.
+fn list(value: &SerializableValue) -> Option<Vec<&SerializableValue>> {
+  match value {
+    SerializableValue::Table(pairs) => {
+      let mut items: Vec<(i64, &SerializableValue)> = pairs
+        .iter()
+        .filter_map(|(k, v)| match k {
+          SerializableValue::Integer(i) => Some((*i, v)),
+          _ => None,
+        })
+        .collect();
+      items.sort_by_key(|(i, _)| *i);
+      Some(items.into_iter().map(|(_, v)| v).collect())
+    }
+    SerializableValue::Dict(fields) if fields.is_empty() => Some(vec![]),
+    _ => None,
+  }
+}
+
+fn number(value: &SerializableValue) -> Option<f64> {
+  match value {
+    SerializableValue::Integer(i) => Some(*i as f64),
+    SerializableValue::Number(n) => Some(*n),
+    _ => None,
+  }
+}
+
+/// Like ==, except 2 and 2.0 are the same.
+fn same(a: &SerializableValue, b: &SerializableValue) -> bool {
+  match (number(a), number(b)) {
+    (Some(a), Some(b)) => a == b,
+    _ => a == b,
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum IndexKey {
+  Boolean(bool),
+  Integer(i64),
+  String(String),
+}
+
+impl IndexKey {
+  fn of(value: &SerializableValue) -> Option<IndexKey> {
+    match value {
+      SerializableValue::Boolean(b) => Some(IndexKey::Boolean(*b)),
+      SerializableValue::Integer(i) => Some(IndexKey::Integer(*i)),
+      SerializableValue::Number(n) if n.fract() == 0.0 => Some(IndexKey::Integer(*n as i64)),
+      SerializableValue::String(s) => Some(IndexKey::String(s.clone())),
+      _ => None,
+    }
+  }
+
+  /// The keys an attr value is indexed under: itself, or its items if it's a list.
+  fn all_from(value: &SerializableValue) -> Vec<IndexKey> {
+    match list(value) {
+      Some(items) => items.into_iter().filter_map(IndexKey::of).collect(),
+      None => IndexKey::of(value).into_iter().collect(),
+    }
+  }
+}
+
+/// Which objects have each value of the indexed attrs. Like the name index,
+/// it isn't saved but rebuilt when the state is loaded.
+#[derive(Clone, Default)]
+pub struct AttrIndexes {
+  indexes: HashMap<String, AttrIndex>,
+}
+
+#[derive(Clone, Default)]
+struct AttrIndex {
+  values: HashMap<IndexKey, HashSet<Id>>,
+  // objects whose value is a string, since any of them could contain a given substring
+  strings: HashSet<Id>,
+}
+
+impl AttrIndexes {
+  pub fn new(attrs: HashSet<String>) -> AttrIndexes {
+    AttrIndexes {
+      indexes: attrs
+        .into_iter()
+        .map(|a| (a, AttrIndex::default()))
+        .collect(),
+    }
+  }
+
+  pub fn update(
+    &mut self,
+    id: Id,
+    attr: &str,
+    old: Option<&SerializableValue>,
+    new: Option<&SerializableValue>,
+  ) {
+    let index = match self.indexes.get_mut(attr) {
+      Some(index) => index,
+      None => return,
+    };
+
+    index.strings.remove(&id);
+    for key in old.map(IndexKey::all_from).unwrap_or_default() {
+      let now_empty = match index.values.get_mut(&key) {
+        Some(ids) => {
+          ids.remove(&id);
+          ids.is_empty()
+        }
+        None => false,
+      };
+      if now_empty {
+        index.values.remove(&key);
+      }
+    }
+
+    if let Some(SerializableValue::String(_)) = new {
+      index.strings.insert(id);
+    }
+    for key in new.map(IndexKey::all_from).unwrap_or_default() {
+      index
+        .values
+        .entry(key)
+        .or_insert_with(HashSet::new)
+        .insert(id);
+    }
+  }
+
+  /// A superset of the objects which could match, if the indexes can tell us;
+  /// None means everything has to be checked.
+  pub fn candidates(&self, filter: &Filter) -> Option<HashSet<Id>> {
+    match filter {
+      Filter::Equals(attr, value) => {
+        let index = self.indexes.get(attr)?;
+        let key = IndexKey::of(value)?;
+        Some(index.values.get(&key).cloned().unwrap_or_default())
+      }
+      Filter::Contains(attr, value) => {
+        let index = self.indexes.get(attr)?;
+        let key = IndexKey::of(value)?;
+        let mut ids = index.values.get(&key).cloned().unwrap_or_default();
+        if let SerializableValue::String(_) = value {
+          ids.extend(index.strings.iter().cloned());
+        }
+        Some(ids)
+      }
+      Filter::All(filters) => filters.iter().filter_map(|f| self.candidates(f)).fold(
+        None,
+        |acc: Option<HashSet<Id>>, ids| match acc {
+          None => Some(ids),
+          Some(acc) => Some(acc.intersection(&ids).cloned().collect()),
+        },
+      ),
+      Filter::Any(filters) => {
+        let mut all = HashSet::new();
+        for f in filters {
+          all.extend(self.candidates(f)?);
+        }
+        Some(all)
+      }
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn string(s: &str) -> SerializableValue {
+    SerializableValue::String(s.to_string())
+  }
+
+  fn dict(fields: Vec<(&str, SerializableValue)>) -> SerializableValue {
+    SerializableValue::Dict(
+      fields
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect(),
+    )
+  }
+
+  fn list_of(items: Vec<SerializableValue>) -> SerializableValue {
+    SerializableValue::Table(
+      items
+        .into_iter()
+        .enumerate()
+        .map(|(i, v)| (SerializableValue::Integer(i as i64 + 1), v))
+        .collect(),
+    )
+  }
+
+  fn attrs(fields: Vec<(&str, SerializableValue)>) -> HashMap<String, SerializableValue> {
+    fields
+      .into_iter()
+      .map(|(k, v)| (k.to_string(), v))
+      .collect()
+  }
+
+  fn equals(attr: &str, value: SerializableValue) -> SerializableValue {
+    dict(vec![("attr", string(attr)), ("equals", value)])
+  }
+
+  fn indexes() -> AttrIndexes {
+    let mut indexes = AttrIndexes::new(vec!["kind".to_string()].into_iter().collect());
+    indexes.update(Id(1), "kind", None, Some(&string("shop")));
+    indexes.update(Id(2), "kind", None, Some(&string("room")));
+    indexes.update(Id(3), "price", None, Some(&SerializableValue::Integer(5)));
+    indexes
+  }
+
+  fn ids(ids: &[usize]) -> Option<HashSet<Id>> {
+    Some(ids.iter().cloned().map(Id).collect())
+  }
+
+  #[test]
+  fn parse_errors() {
+    assert!(Filter::parse(&dict(vec![("equals", string("x"))])).is_err());
+    assert!(Filter::parse(&dict(vec![("attr", string("price"))])).is_err());
+    assert!(Filter::parse(&dict(vec![("attr", string("price")), ("min", string("1"))])).is_err());
+    assert!(Filter::parse(&dict(vec![("any", string("x"))])).is_err());
+  }
+
+  #[test]
+  fn matching() {
+    let object = attrs(vec![
+      ("price", SerializableValue::Number(2.0)),
+      ("tags", list_of(vec![string("shop"), string("open")])),
+      ("name", string("Corner shop")),
+    ]);
+    let matches = |filter: SerializableValue| Filter::parse(&filter).unwrap().matches(&object);
+
+    assert!(matches(equals("price", SerializableValue::Integer(2))));
+    assert!(!matches(equals("price", string("2"))));
+    assert!(matches(dict(vec![
+      ("attr", string("price")),
+      ("min", SerializableValue::Integer(2)),
+      ("max", SerializableValue::Integer(2)),
+    ])));
+    assert!(matches(dict(vec![
+      ("attr", string("tags")),
+      ("contains", string("open")),
+    ])));
+    assert!(matches(dict(vec![
+      ("attr", string("name")),
+      ("contains", string("shop")),
+    ])));
+    assert!(matches(dict(vec![
+      ("attr", string("owner")),
+      ("exists", SerializableValue::Boolean(false)),
+    ])));
+    assert!(matches(dict(vec![("not", equals("name", string("shop")))])));
+    assert!(matches(dict(vec![(
+      "any",
+      list_of(vec![
+        equals("name", string("shop")),
+        equals("price", SerializableValue::Integer(2)),
+      ])
+    )])));
+    assert!(!matches(dict(vec![(
+      "all",
+      list_of(vec![
+        equals("name", string("shop")),
+        equals("price", SerializableValue::Integer(2)),
+      ])
+    )])));
+  }
+
+  #[test]
+  fn candidates_from_indexes() {
+    let indexes = indexes();
+    let candidates =
+      |filter: SerializableValue| indexes.candidates(&Filter::parse(&filter).unwrap());
+
+    assert_eq!(candidates(equals("kind", string("shop"))), ids(&[1]));
+    assert_eq!(candidates(equals("kind", string("bank"))), ids(&[]));
+    // price isn't indexed
+    assert_eq!(
+      candidates(equals("price", SerializableValue::Integer(5))),
+      None
+    );
+    // any string could contain the part we're looking for
+    assert_eq!(
+      candidates(dict(vec![
+        ("attr", string("kind")),
+        ("contains", string("oo")),
+      ])),
+      ids(&[1, 2])
+    );
+  }
+
+  #[test]
+  fn all_narrows_by_indexed_filters_only() {
+    let indexes = indexes();
+    let filter = Filter::parse(&dict(vec![(
+      "all",
+      list_of(vec![
+        equals("kind", string("shop")),
+        equals("price", SerializableValue::Integer(5)),
+      ]),
+    )]))
+    .unwrap();
+    assert_eq!(indexes.candidates(&filter), ids(&[1]));
+  }
+
+  #[test]
+  fn any_with_a_non_indexed_filter_checks_everything() {
+    let indexes = indexes();
+    let filter = Filter::parse(&dict(vec![(
+      "any",
+      list_of(vec![
+        equals("kind", string("shop")),
+        equals("price", SerializableValue::Integer(5)),
+      ]),
+    )]))
+    .unwrap();
+    // narrowing to the shops would miss #3, which matches on price
+    assert_eq!(indexes.candidates(&filter), None);
+    assert!(filter.matches(&attrs(vec![("price", SerializableValue::Integer(5))])));
+
+    let filter = Filter::parse(&dict(vec![(
+      "any",
+      list_of(vec![
+        equals("kind", string("shop")),
+        equals("kind", string("room")),
+      ]),
+    )]))
+    .unwrap();
+    assert_eq!(indexes.candidates(&filter), ids(&[1, 2]));
+  }
+
+  #[test]
+  fn updates_move_objects_between_values() {
+    let mut indexes = indexes();
+    indexes.update(Id(1), "kind", Some(&string("shop")), Some(&string("room")));
+    let filter = Filter::parse(&equals("kind", string("room"))).unwrap();
+    assert_eq!(indexes.candidates(&filter), ids(&[1, 2]));
+
+    indexes.update(Id(2), "kind", Some(&string("room")), None);
+    assert_eq!(indexes.candidates(&filter), ids(&[1]));
+  }
+}
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::File;
+
+/// How much each user may own. Loaded from the JSON file named by
+/// `ORISA_QUOTAS` if set; any missing fields fall back to the defaults below.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct Quota {
+  pub objects: usize,
+  pub package_bytes: usize, // across all of the user's live packages
+}
+
+impl Default for Quota {
+  fn default() -> Quota {
+    Quota {
+      objects: 1000,
+      package_bytes: 1_000_000,
+    }
+  }
+}
+
+impl Quota {
+  pub fn load() -> Quota {
+    match env::var("ORISA_QUOTAS") {
+      Err(_) => Quota::default(),
+      Ok(path) => {
+        let quota = File::open(&path)
+          .map_err(|e| e.to_string())
+          .and_then(|f| serde_json::from_reader(f).map_err(|e| e.to_string()));
+        match quota {
+          Ok(quota) => {
+            log::info!("Loaded quotas from {}", path);
+            quota
+          }
+          Err(e) => {
+            log::error!("Unable to load quotas from {}: {}; using default", path, e);
+            Quota::default()
+          }
+        }
+      }
+    }
+  }
+
+  pub fn with_override(self, over: &QuotaOverride) -> Quota {
+    Quota {
+      objects: over.objects.unwrap_or(self.objects),
+      package_bytes: over.package_bytes.unwrap_or(self.package_bytes),
+    }
+  }
+}
+
+/// An admin's change to one user's quota; missing fields use the default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QuotaOverride {
+  pub objects: Option<usize>,
+  pub package_bytes: Option<usize>,
+}
@@ -0,0 +1,463 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::time::Instant;
+
+/// What we tell clients when they're throttled or disconnected.
+pub const THROTTLED_MESSAGE: &str = "You're sending too fast; that was ignored.";
+pub const DISCONNECTED_MESSAGE: &str = "Disconnected for sending too fast.";
+pub const TOO_MANY_LOGIN_ATTEMPTS_MESSAGE: &str =
+  "Too many failed logins; wait a few minutes and try again.";
+
+/// Failed logins allowed for each username and for each address: a few at once,
+/// then one a minute.
+const LOGIN_FAILURES: Rate = Rate {
+  burst: 5.0,
+  per_second: 1.0 / 60.0,
+};
+
+/// Past this many usernames and addresses, the ones which have been forgiven
+/// their failures are forgotten.
+const MAX_LOGIN_KEYS: usize = 10_000;
+
+/// The kinds of client input we limit; each has its own bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputKind {
+  Command,
+  SendMessage,
+  Complete, // asks the world actor for Lua time, so kept apart from messages
+  SaveFile,
+  ReloadCode,
+  LoadHistory,
+  Admin,
+  Invalid, // frames we couldn't make sense of
+}
+
+/// A token bucket: up to `burst` inputs at once, refilling at `per_second`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Rate {
+  pub burst: f64,
+  pub per_second: f64,
+}
+
+/// Missing fields fall back to the per-connection defaults.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Rates {
+  pub command: Rate,
+  pub send_message: Rate,
+  pub complete: Rate,
+  pub save_file: Rate,
+  pub reload_code: Rate,
+  pub load_history: Rate,
+  pub admin: Rate,
+  pub invalid: Rate,
+}
+
+impl Default for Rates {
+  fn default() -> Rates {
+    Rates {
+      command: Rate {
+        burst: 20.0,
+        per_second: 5.0,
+      },
+      send_message: Rate {
+        burst: 50.0,
+        per_second: 20.0,
+      },
+      complete: Rate {
+        burst: 5.0,
+        per_second: 2.0,
+      },
+      save_file: Rate {
+        burst: 5.0,
+        per_second: 0.5,
+      },
+      reload_code: Rate {
+        burst: 3.0,
+        per_second: 0.1,
+      },
+      load_history: Rate {
+        burst: 10.0,
+        per_second: 1.0,
+      },
+      admin: Rate {
+        burst: 10.0,
+        per_second: 1.0,
+      },
+      invalid: Rate {
+        burst: 5.0,
+        per_second: 0.5,
+      },
+    }
+  }
+}
+
+/// Loaded from the JSON file named by `ORISA_RATE_LIMITS` if set;
+/// any missing fields fall back to the defaults below.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimits {
+  pub per_connection: Rates,
+  /// Shared by all of a user's connections, so opening more doesn't help.
+  pub per_user: Rates,
+  /// Each throttled input is a strike; connections which run out are disconnected.
+  pub strikes: Rate,
+}
+
+impl Default for RateLimits {
+  fn default() -> RateLimits {
+    RateLimits {
+      per_connection: Rates::default(),
+      per_user: Rates {
+        command: Rate {
+          burst: 30.0,
+          per_second: 8.0,
+        },
+        send_message: Rate {
+          burst: 80.0,
+          per_second: 30.0,
+        },
+        complete: Rate {
+          burst: 8.0,
+          per_second: 3.0,
+        },
+        save_file: Rate {
+          burst: 8.0,
+          per_second: 1.0,
+        },
+        reload_code: Rate {
+          burst: 5.0,
+          per_second: 0.2,
+        },
+        load_history: Rate {
+          burst: 20.0,
+          per_second: 2.0,
+        },
+        admin: Rate {
+          burst: 20.0,
+          per_second: 2.0,
+        },
+        invalid: Rate {
+          burst: 10.0,
+          per_second: 1.0,
+        },
+      },
+      strikes: Rate {
+        burst: 20.0,
+        per_second: 0.2,
+      },
+    }
+  }
+}
+
+impl RateLimits {
+  pub fn load() -> RateLimits {
+    match env::var("ORISA_RATE_LIMITS") {
+      Err(_) => RateLimits::default(),
+      Ok(path) => {
+        let limits = File::open(&path)
+          .map_err(|e| e.to_string())
+          .and_then(|f| serde_json::from_reader(f).map_err(|e| e.to_string()));
+        match limits {
+          Ok(limits) => {
+            log::info!("Loaded rate limits from {}", path);
+            limits
+          }
+          Err(e) => {
+            log::error!(
+              "Unable to load rate limits from {}: {}; using default",
+              path,
+              e
+            );
+            RateLimits::default()
+          }
+        }
+      }
+    }
+  }
+}
+
+struct TokenBucket {
+  rate: Rate,
+  tokens: f64,
+  updated: Instant,
+}
+
+impl TokenBucket {
+  fn new(rate: Rate) -> TokenBucket {
+    TokenBucket {
+      rate,
+      tokens: rate.burst,
+      updated: Instant::now(),
+    }
+  }
+
+  /// Refills the bucket for the time since we last looked, then checks for a token.
+  fn has_token(&mut self) -> bool {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.updated).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst);
+    self.updated = now;
+    self.tokens >= 1.0
+  }
+
+  fn take(&mut self) {
+    self.tokens -= 1.0;
+  }
+
+  fn try_take(&mut self) -> bool {
+    if self.has_token() {
+      self.take();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn is_full(&mut self) -> bool {
+    self.has_token();
+    self.tokens >= self.rate.burst
+  }
+}
+
+/// Limits password guessing, whichever way people log in: each username and each
+/// address can only get their credentials wrong so often.
+#[derive(Default)]
+pub struct LoginAttempts {
+  failures: HashMap<String, TokenBucket>, // keyed by "user:<name>" or "address:<ip>"
+}
+
+impl LoginAttempts {
+  fn keys(username: &str, address: Option<&str>) -> Vec<String> {
+    let mut keys = vec![format!("user:{}", username)];
+    if let Some(address) = address {
+      keys.push(format!("address:{}", address));
+    }
+    keys
+  }
+
+  /// Whether the username may try to log in from the address.
+  pub fn allows(&mut self, username: &str, address: Option<&str>) -> bool {
+    let failures = &mut self.failures;
+    LoginAttempts::keys(username, address).iter().all(|key| {
+      failures
+        .get_mut(key)
+        .map_or(true, |bucket| bucket.has_token())
+    })
+  }
+
+  pub fn record_failure(&mut self, username: &str, address: Option<&str>) {
+    if self.failures.len() > MAX_LOGIN_KEYS {
+      self.failures.retain(|_, bucket| !bucket.is_full());
+    }
+    for key in LoginAttempts::keys(username, address) {
+      self
+        .failures
+        .entry(key)
+        .or_insert_with(|| TokenBucket::new(LOGIN_FAILURES))
+        .try_take();
+    }
+  }
+}
+
+/// The buckets for one connection or one user.
+pub struct Limiter {
+  command: TokenBucket,
+  send_message: TokenBucket,
+  complete: TokenBucket,
+  save_file: TokenBucket,
+  reload_code: TokenBucket,
+  load_history: TokenBucket,
+  admin: TokenBucket,
+  invalid: TokenBucket,
+  strikes: TokenBucket,
+}
+
+impl Limiter {
+  pub fn new(rates: &Rates, strikes: Rate) -> Limiter {
+    Limiter {
+      command: TokenBucket::new(rates.command),
+      send_message: TokenBucket::new(rates.send_message),
+      complete: TokenBucket::new(rates.complete),
+      save_file: TokenBucket::new(rates.save_file),
+      reload_code: TokenBucket::new(rates.reload_code),
+      load_history: TokenBucket::new(rates.load_history),
+      admin: TokenBucket::new(rates.admin),
+      invalid: TokenBucket::new(rates.invalid),
+      strikes: TokenBucket::new(strikes),
+    }
+  }
+
+  pub fn for_connection(limits: &RateLimits) -> Limiter {
+    Limiter::new(&limits.per_connection, limits.strikes)
+  }
+
+  fn bucket(&mut self, kind: InputKind) -> &mut TokenBucket {
+    match kind {
+      InputKind::Command => &mut self.command,
+      InputKind::SendMessage => &mut self.send_message,
+      InputKind::Complete => &mut self.complete,
+      InputKind::SaveFile => &mut self.save_file,
+      InputKind::ReloadCode => &mut self.reload_code,
+      InputKind::LoadHistory => &mut self.load_history,
+      InputKind::Admin => &mut self.admin,
+      InputKind::Invalid => &mut self.invalid,
+    }
+  }
+
+  /// Whether input of this kind would be allowed, without using up any of the limit;
+  /// see `take`.
+  pub fn would_allow(&mut self, kind: InputKind) -> bool {
+    self.bucket(kind).has_token()
+  }
+
+  /// Uses up the limit for input `would_allow` said was fine.
+  pub fn take(&mut self, kind: InputKind) {
+    self.bucket(kind).take()
+  }
+
+  /// Records throttled input; false once there have been too many.
+  pub fn strike(&mut self) -> bool {
+    self.strikes.try_take()
+  }
+}
+
+pub enum Verdict {
+  Allow,
+  Throttle,
+  Disconnect,
+}
+
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct RateStats {
+  pub throttled_commands: u64,
+  pub throttled_messages: u64,
+  pub throttled_completions: u64,
+  pub throttled_saves: u64,
+  pub throttled_reloads: u64,
+  pub throttled_history: u64,
+  pub throttled_admin: u64,
+  pub throttled_invalid: u64,
+  pub disconnects: u64,
+}
+
+impl RateStats {
+  pub fn record_throttled(&mut self, kind: InputKind) {
+    match kind {
+      InputKind::Command => self.throttled_commands += 1,
+      InputKind::SendMessage => self.throttled_messages += 1,
+      InputKind::Complete => self.throttled_completions += 1,
+      InputKind::SaveFile => self.throttled_saves += 1,
+      InputKind::ReloadCode => self.throttled_reloads += 1,
+      InputKind::LoadHistory => self.throttled_history += 1,
+      InputKind::Admin => self.throttled_admin += 1,
+      InputKind::Invalid => self.throttled_invalid += 1,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  const SLOW: Rate = Rate {
+    burst: 3.0,
+    per_second: 2.0,
+  };
+
+  /// Pretends the bucket was last looked at `seconds` ago.
+  fn rewind(bucket: &mut TokenBucket, seconds: f64) {
+    bucket.updated -= Duration::from_secs_f64(seconds);
+  }
+
+  #[test]
+  fn buckets_allow_a_burst_then_refill() {
+    let mut bucket = TokenBucket::new(SLOW);
+    for _ in 0..3 {
+      assert!(bucket.try_take());
+    }
+    assert!(!bucket.try_take());
+
+    rewind(&mut bucket, 1.0);
+    assert!(bucket.try_take());
+    assert!(bucket.try_take());
+    assert!(!bucket.try_take());
+
+    // it refills to the burst and no further
+    rewind(&mut bucket, 60.0);
+    assert!(bucket.is_full());
+    for _ in 0..3 {
+      assert!(bucket.try_take());
+    }
+    assert!(!bucket.try_take());
+  }
+
+  #[test]
+  fn kinds_have_their_own_buckets() {
+    let rates = Rates {
+      command: SLOW,
+      ..Rates::default()
+    };
+    let mut limiter = Limiter::new(&rates, SLOW);
+    for _ in 0..3 {
+      assert!(limiter.would_allow(InputKind::Command));
+      limiter.take(InputKind::Command);
+    }
+    assert!(!limiter.would_allow(InputKind::Command));
+    assert!(limiter.would_allow(InputKind::Complete));
+    assert!(limiter.would_allow(InputKind::SendMessage));
+  }
+
+  #[test]
+  fn strikes_run_out_and_come_back() {
+    let mut limiter = Limiter::new(&Rates::default(), SLOW);
+    assert!(limiter.strike());
+    assert!(limiter.strike());
+    assert!(limiter.strike());
+    assert!(!limiter.strike());
+
+    rewind(&mut limiter.strikes, 0.5);
+    assert!(limiter.strike());
+    assert!(!limiter.strike());
+  }
+
+  fn same(a: Rate, b: Rate) -> bool {
+    serde_json::to_value(a).unwrap() == serde_json::to_value(b).unwrap()
+  }
+
+  #[test]
+  fn missing_rates_use_the_defaults() {
+    let one = Rate {
+      burst: 1.0,
+      per_second: 1.0,
+    };
+    let limits: RateLimits =
+      serde_json::from_str(r#"{"per_user": {"command": {"burst": 1, "per_second": 1}}}"#).unwrap();
+    assert!(same(limits.per_user.command, one));
+    assert!(same(limits.per_user.save_file, Rates::default().save_file));
+    assert!(same(
+      limits.per_connection.command,
+      Rates::default().command
+    ));
+    assert!(same(limits.strikes, RateLimits::default().strikes));
+  }
+
+  #[test]
+  fn login_failures_are_limited_by_username_and_address() {
+    let mut attempts = LoginAttempts::default();
+    for _ in 0..LOGIN_FAILURES.burst as usize {
+      assert!(attempts.allows("alice", Some("10.0.0.1")));
+      attempts.record_failure("alice", Some("10.0.0.1"));
+    }
+    assert!(!attempts.allows("alice", Some("10.0.0.1")));
+    // a new connection or address doesn't help with the same username
+    assert!(!attempts.allows("alice", Some("10.0.0.2")));
+    assert!(!attempts.allows("alice", None));
+    // nor does trying other usernames from the same address
+    assert!(!attempts.allows("bob", Some("10.0.0.1")));
+    assert!(attempts.allows("bob", Some("10.0.0.2")));
+  }
+}
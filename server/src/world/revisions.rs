@@ -0,0 +1,322 @@
+use crate::object::types::{GameTime, Id};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// How many revisions we keep per live package; older ones are dropped.
+const REVISION_LIMIT: usize = 100;
+
+/// Each user's revisions can take up this many times their live package quota;
+/// past that, their oldest revisions are dropped.
+pub const HISTORY_QUOTA_MULTIPLE: usize = 10;
+
+/// Lines of unchanged code shown around each change in a diff.
+const DIFF_CONTEXT: usize = 3;
+
+/// Above this many (changed lines before) x (changed lines after), we don't look for
+/// the smallest diff and just show the whole changed region as replaced.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// One saved version of a live package.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Revision {
+  pub number: usize, // counts up from 1 with each save
+  pub author: Option<Id>,
+  pub time: GameTime,
+  pub saved_at: String, // wall clock time, RFC 3339
+  pub message: Option<String>,
+  pub content: String,
+}
+
+/// Every save of a live package, so a bad save can be undone.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PackageHistory {
+  revisions: VecDeque<Revision>,
+}
+
+impl PackageHistory {
+  pub fn record(
+    &mut self,
+    author: Option<Id>,
+    time: GameTime,
+    message: Option<String>,
+    content: String,
+  ) -> usize {
+    let number = self.latest().map_or(1, |r| r.number + 1);
+    self.revisions.push_back(Revision {
+      number,
+      author,
+      time,
+      saved_at: Utc::now().to_rfc3339(),
+      message,
+      content,
+    });
+    while self.revisions.len() > REVISION_LIMIT {
+      self.revisions.pop_front();
+    }
+    number
+  }
+
+  pub fn latest(&self) -> Option<&Revision> {
+    self.revisions.back()
+  }
+
+  pub fn get(&self, number: usize) -> Option<&Revision> {
+    self.revisions.iter().find(|r| r.number == number)
+  }
+
+  /// Newest first.
+  pub fn revisions(&self) -> impl Iterator<Item = &Revision> {
+    self.revisions.iter().rev()
+  }
+
+  /// Bytes of code across all the revisions.
+  pub fn bytes(&self) -> usize {
+    self.revisions.iter().map(|r| r.content.len()).sum()
+  }
+
+  /// The oldest revision, unless it's the latest (which is the current code).
+  pub fn oldest_droppable(&self) -> Option<&Revision> {
+    if self.revisions.len() > 1 {
+      self.revisions.front()
+    } else {
+      None
+    }
+  }
+
+  /// Drops `oldest_droppable`, returning how many bytes that freed.
+  pub fn drop_oldest(&mut self) -> usize {
+    if self.oldest_droppable().is_none() {
+      return 0;
+    }
+    self.revisions.pop_front().map_or(0, |r| r.content.len())
+  }
+}
+
+enum Line<'a> {
+  Same(&'a str),
+  Removed(&'a str),
+  Added(&'a str),
+}
+
+/// A unified diff (like `diff -u`, without the file headers) from `old` to `new`.
+pub fn diff(old: &str, new: &str) -> String {
+  let old: Vec<&str> = old.lines().collect();
+  let new: Vec<&str> = new.lines().collect();
+
+  let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+  let suffix = old[prefix..]
+    .iter()
+    .rev()
+    .zip(new[prefix..].iter().rev())
+    .take_while(|(a, b)| a == b)
+    .count();
+
+  let mut lines: Vec<Line> = old[..prefix].iter().map(|l| Line::Same(l)).collect();
+  lines.extend(changed_lines(
+    &old[prefix..old.len() - suffix],
+    &new[prefix..new.len() - suffix],
+  ));
+  lines.extend(old[old.len() - suffix..].iter().map(|l| Line::Same(l)));
+
+  render_hunks(&lines)
+}
+
+/// The shortest edit between the middles of the files, from their longest common subsequence.
+fn changed_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+  if old.len() * new.len() > MAX_DIFF_CELLS {
+    return old
+      .iter()
+      .map(|l| Line::Removed(l))
+      .chain(new.iter().map(|l| Line::Added(l)))
+      .collect();
+  }
+
+  // common[i][j] is the length of the LCS of old[i..] and new[j..]
+  let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+  for i in (0..old.len()).rev() {
+    for j in (0..new.len()).rev() {
+      common[i][j] = if old[i] == new[j] {
+        common[i + 1][j + 1] + 1
+      } else {
+        common[i + 1][j].max(common[i][j + 1])
+      };
+    }
+  }
+
+  let mut lines = vec![];
+  let (mut i, mut j) = (0, 0);
+  while i < old.len() || j < new.len() {
+    if i < old.len() && j < new.len() && old[i] == new[j] {
+      lines.push(Line::Same(old[i]));
+      i += 1;
+      j += 1;
+    } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+      lines.push(Line::Removed(old[i]));
+      i += 1;
+    } else {
+      lines.push(Line::Added(new[j]));
+      j += 1;
+    }
+  }
+  lines
+}
+
+fn render_hunks(lines: &[Line]) -> String {
+  let changed: Vec<usize> = lines
+    .iter()
+    .enumerate()
+    .filter(|(_, l)| match l {
+      Line::Same(_) => false,
+      _ => true,
+    })
+    .map(|(index, _)| index)
+    .collect();
+
+  // group changes whose context would overlap into the same hunk
+  let mut hunks: Vec<(usize, usize)> = vec![];
+  for index in changed {
+    let start = index.saturating_sub(DIFF_CONTEXT);
+    let end = (index + DIFF_CONTEXT + 1).min(lines.len());
+    match hunks.last_mut() {
+      Some(last) if start <= last.1 => last.1 = end,
+      _ => hunks.push((start, end)),
+    }
+  }
+
+  let mut out = String::new();
+  // line numbers in the old and new files where the current hunk starts
+  let (mut old_line, mut new_line) = (1, 1);
+  let mut position = 0;
+  for (start, end) in hunks {
+    for line in &lines[position..start] {
+      match line {
+        Line::Same(_) => {
+          old_line += 1;
+          new_line += 1;
+        }
+        Line::Removed(_) => old_line += 1,
+        Line::Added(_) => new_line += 1,
+      }
+    }
+
+    let hunk = &lines[start..end];
+    let old_count = hunk
+      .iter()
+      .filter(|l| match l {
+        Line::Added(_) => false,
+        _ => true,
+      })
+      .count();
+    let new_count = hunk
+      .iter()
+      .filter(|l| match l {
+        Line::Removed(_) => false,
+        _ => true,
+      })
+      .count();
+    // like diff -u, an empty side starts at the line before
+    let start = |line: usize, count: usize| if count == 0 { line - 1 } else { line };
+    out.push_str(&format!(
+      "@@ -{},{} +{},{} @@\n",
+      start(old_line, old_count),
+      old_count,
+      start(new_line, new_count),
+      new_count
+    ));
+    for line in hunk {
+      match line {
+        Line::Same(l) => out.push_str(&format!(" {}\n", l)),
+        Line::Removed(l) => out.push_str(&format!("-{}\n", l)),
+        Line::Added(l) => out.push_str(&format!("+{}\n", l)),
+      }
+    }
+
+    old_line += old_count;
+    new_line += new_count;
+    position = end;
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unchanged_files_have_empty_diffs() {
+    assert_eq!(diff("", ""), "");
+    assert_eq!(diff("a\nb\n", "a\nb\n"), "");
+    // only the final newline differs
+    assert_eq!(diff("a\nb", "a\nb\n"), "");
+  }
+
+  #[test]
+  fn one_sided_diffs() {
+    assert_eq!(diff("", "x\ny"), "@@ -0,0 +1,2 @@\n+x\n+y\n");
+    assert_eq!(diff("x\ny\n", ""), "@@ -1,2 +0,0 @@\n-x\n-y\n");
+  }
+
+  #[test]
+  fn additions_and_removals() {
+    assert_eq!(diff("a", "a\nb"), "@@ -1,1 +1,2 @@\n a\n+b\n");
+    assert_eq!(diff("a\nb\nc", "a\nc"), "@@ -1,3 +1,2 @@\n a\n-b\n c\n");
+  }
+
+  #[test]
+  fn context_around_changes() {
+    let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10";
+    let new = old.replace("5", "five");
+    assert_eq!(
+      diff(old, &new),
+      "@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+    );
+  }
+
+  #[test]
+  fn distant_changes_get_separate_hunks() {
+    let old: Vec<String> = (1..=20).map(|n| n.to_string()).collect();
+    let mut new = old.clone();
+    new[0] = "one".to_string();
+    new[19] = "twenty".to_string();
+    assert_eq!(
+      diff(&old.join("\n"), &new.join("\n")),
+      "@@ -1,4 +1,4 @@\n-1\n+one\n 2\n 3\n 4\n\
+       @@ -17,4 +17,4 @@\n 17\n 18\n 19\n-20\n+twenty\n"
+    );
+  }
+
+  fn history(contents: &[&str]) -> PackageHistory {
+    let mut history = PackageHistory::default();
+    for (i, content) in contents.iter().enumerate() {
+      history.record(
+        None,
+        GameTime::default() + i as u64,
+        None,
+        content.to_string(),
+      );
+    }
+    history
+  }
+
+  #[test]
+  fn revisions_are_numbered_and_limited() {
+    let contents: Vec<String> = (0..REVISION_LIMIT + 5).map(|n| n.to_string()).collect();
+    let history = history(&contents.iter().map(|c| c.as_str()).collect::<Vec<&str>>());
+    assert_eq!(history.revisions().count(), REVISION_LIMIT);
+    assert_eq!(history.latest().unwrap().number, REVISION_LIMIT + 5);
+    assert!(history.get(5).is_none());
+    assert_eq!(history.get(6).unwrap().content, "5");
+  }
+
+  #[test]
+  fn the_latest_revision_is_never_dropped() {
+    let mut history = history(&["aaaa", "bb"]);
+    assert_eq!(history.bytes(), 6);
+    assert_eq!(history.oldest_droppable().unwrap().content, "aaaa");
+    assert_eq!(history.drop_oldest(), 4);
+    assert!(history.oldest_droppable().is_none());
+    assert_eq!(history.drop_oldest(), 0);
+    assert_eq!(history.latest().unwrap().content, "bb");
+  }
+}
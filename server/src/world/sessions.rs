@@ -0,0 +1,202 @@
+use crate::chat::ToClientMessage;
+use crate::object::types::Id;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How long a dropped connection can be resumed for before the user is
+/// treated as disconnected.
+pub const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(120);
+
+/// Messages beyond this many sent during a gap are dropped, oldest first.
+const MAX_MISSED_MESSAGES: usize = 500;
+
+/// Resumable web sessions. While a session is suspended (its socket dropped but
+/// the grace period hasn't passed) the user still counts as connected and
+/// messages sent to them are kept to replay when it's resumed.
+pub struct Sessions {
+  sessions: HashMap<String, Session>, // by token
+}
+
+struct Session {
+  id: Id,
+  suspended: Option<(Instant, VecDeque<ToClientMessage>)>,
+}
+
+impl Sessions {
+  pub fn new() -> Sessions {
+    Sessions {
+      sessions: HashMap::new(),
+    }
+  }
+
+  /// Starts an attached session for `id`, returning its token.
+  pub fn start(&mut self, id: Id) -> String {
+    let token = Uuid::new_v4().to_simple().to_string();
+    self.sessions.insert(
+      token.clone(),
+      Session {
+        id,
+        suspended: None,
+      },
+    );
+    token
+  }
+
+  pub fn end(&mut self, token: &str) {
+    self.sessions.remove(token);
+  }
+
+  /// Returns false if there's no such session, in which case the caller
+  /// should treat the connection as gone.
+  pub fn suspend(&mut self, id: Id, token: &str) -> bool {
+    match self.sessions.get_mut(token) {
+      Some(session) if session.id == id && session.suspended.is_none() => {
+        session.suspended = Some((Instant::now(), VecDeque::new()));
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Ends one of `id`'s suspended sessions, returning what they missed.
+  /// Attached sessions can't be resumed.
+  pub fn resume(&mut self, id: Id, token: &str) -> Option<Vec<ToClientMessage>> {
+    match self.sessions.get(token) {
+      Some(Session {
+        id: session_id,
+        suspended: Some((since, _)),
+      }) if *session_id == id && since.elapsed() < SESSION_GRACE_PERIOD => (),
+      _ => return None,
+    }
+
+    self
+      .sessions
+      .remove(token)
+      .and_then(|session| session.suspended)
+      .map(|(_, missed)| missed.into_iter().collect())
+  }
+
+  /// Keeps the message for any of the user's suspended sessions;
+  /// returns true if there were any.
+  pub fn record_missed(&mut self, id: Id, message: &ToClientMessage) -> bool {
+    let mut recorded = false;
+    for session in self.sessions.values_mut().filter(|s| s.id == id) {
+      if let Some((_, ref mut missed)) = session.suspended {
+        if missed.len() >= MAX_MISSED_MESSAGES {
+          missed.pop_front();
+        }
+        missed.push_back(message.clone());
+        recorded = true;
+      }
+    }
+    recorded
+  }
+
+  /// Removes sessions whose grace period has passed, returning who they were for.
+  pub fn expire(&mut self) -> Vec<Id> {
+    let expired: Vec<String> = self
+      .sessions
+      .iter()
+      .filter(|(_, session)| match session.suspended {
+        Some((since, _)) => since.elapsed() >= SESSION_GRACE_PERIOD,
+        None => false,
+      })
+      .map(|(token, _)| token.clone())
+      .collect();
+
+    expired
+      .iter()
+      .filter_map(|token| self.sessions.remove(token))
+      .map(|session| session.id)
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn numbered(i: usize) -> ToClientMessage {
+    ToClientMessage::Log {
+      level: "info".to_string(),
+      message: i.to_string(),
+    }
+  }
+
+  fn logged(messages: &[ToClientMessage]) -> Vec<String> {
+    messages
+      .iter()
+      .map(|m| match m {
+        ToClientMessage::Log { message, .. } => message.clone(),
+        other => panic!("Unexpected message {:?}", other),
+      })
+      .collect()
+  }
+
+  /// Pretends the session was suspended `ago`.
+  fn suspended_for(sessions: &mut Sessions, token: &str, ago: Duration) {
+    if let Some((ref mut since, _)) = sessions.sessions.get_mut(token).unwrap().suspended {
+      *since = Instant::now() - ago;
+    }
+  }
+
+  #[test]
+  fn only_suspended_sessions_resume() {
+    let mut sessions = Sessions::new();
+    let token = sessions.start(Id(1));
+    assert!(sessions.resume(Id(1), &token).is_none());
+
+    assert!(sessions.suspend(Id(1), &token));
+    assert!(!sessions.suspend(Id(1), &token));
+    // it's someone else's
+    assert!(sessions.resume(Id(2), &token).is_none());
+
+    assert!(sessions.record_missed(Id(1), &numbered(1)));
+    assert!(!sessions.record_missed(Id(2), &numbered(2)));
+    assert_eq!(logged(&sessions.resume(Id(1), &token).unwrap()), vec!["1"]);
+    // and only once
+    assert!(sessions.resume(Id(1), &token).is_none());
+  }
+
+  #[test]
+  fn sessions_resume_within_the_grace_period() {
+    let mut sessions = Sessions::new();
+    let token = sessions.start(Id(1));
+    sessions.suspend(Id(1), &token);
+    suspended_for(&mut sessions, &token, SESSION_GRACE_PERIOD / 2);
+    assert!(sessions.expire().is_empty());
+    assert!(sessions.resume(Id(1), &token).is_some());
+  }
+
+  #[test]
+  fn sessions_expire_after_the_grace_period() {
+    let mut sessions = Sessions::new();
+    let late = sessions.start(Id(1));
+    let attached = sessions.start(Id(2));
+    sessions.suspend(Id(1), &late);
+    suspended_for(&mut sessions, &late, SESSION_GRACE_PERIOD);
+    assert!(sessions.resume(Id(1), &late).is_none());
+
+    assert_eq!(sessions.expire(), vec![Id(1)]);
+    assert!(sessions.expire().is_empty());
+    assert!(sessions.suspend(Id(2), &attached));
+  }
+
+  #[test]
+  fn missed_messages_are_capped() {
+    let mut sessions = Sessions::new();
+    let token = sessions.start(Id(1));
+    sessions.suspend(Id(1), &token);
+    for i in 0..MAX_MISSED_MESSAGES + 10 {
+      sessions.record_missed(Id(1), &numbered(i));
+    }
+    let missed = logged(&sessions.resume(Id(1), &token).unwrap());
+    assert_eq!(missed.len(), MAX_MISSED_MESSAGES);
+    assert_eq!(missed[0], "10");
+    assert_eq!(
+      missed.last().unwrap(),
+      &(MAX_MISSED_MESSAGES + 9).to_string()
+    );
+  }
+}
@@ -0,0 +1,88 @@
+use super::World;
+use crate::util::ResultAnyError;
+use chrono::prelude::*;
+use serde::Serialize;
+use std::env;
+use std::fs::{self, copy, rename, File};
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// The world we load at startup; saves write a temporary file and rename it over this.
+const CURRENT: &str = "world.json";
+const TEMPORARY: &str = "world-out.json";
+
+/// The directory from `ORISA_STATE_DIRECTORY`, which holds the current world and a
+/// timestamped `world-<time>.json` snapshot from every save.
+pub fn state_directory() -> PathBuf {
+  PathBuf::from(env::var("ORISA_STATE_DIRECTORY").unwrap_or("state".to_string()))
+}
+
+pub fn current_path() -> PathBuf {
+  state_directory().join(CURRENT)
+}
+
+/// Saves the world as the current one and as a new snapshot, whose name we return.
+pub fn save(world: &World) -> ResultAnyError<String> {
+  let state_dir = state_directory();
+  let temp_path = state_dir.join(TEMPORARY);
+  world.save(File::create(&temp_path)?)?;
+
+  let name = format!("world-{}.json", Utc::now().to_rfc3339());
+  copy(&temp_path, state_dir.join(&name))?;
+  rename(temp_path, state_dir.join(CURRENT))?;
+  Ok(name)
+}
+
+/// Saves the world as the current one without making a snapshot.
+pub fn save_current(world: &World) -> ResultAnyError<()> {
+  let state_dir = state_directory();
+  let temp_path = state_dir.join(TEMPORARY);
+  world.save(File::create(&temp_path)?)?;
+  rename(temp_path, state_dir.join(CURRENT))?;
+  Ok(())
+}
+
+#[derive(Serialize, Debug)]
+pub struct Snapshot {
+  pub name: String,
+  pub bytes: u64,
+  pub saved_at: Option<String>, // from the file's modification time
+}
+
+/// Every snapshot in the state directory, newest first.
+pub fn list() -> io::Result<Vec<Snapshot>> {
+  let mut snapshots = vec![];
+  for entry in fs::read_dir(state_directory())? {
+    let entry = entry?;
+    let name = match entry.file_name().into_string() {
+      Ok(name) => name,
+      Err(_) => continue,
+    };
+    if !name.starts_with("world-") || !name.ends_with(".json") || name == TEMPORARY {
+      continue;
+    }
+
+    let metadata = entry.metadata()?;
+    let saved_at = metadata
+      .modified()
+      .ok()
+      .map(|t: SystemTime| DateTime::<Utc>::from(t).to_rfc3339());
+    snapshots.push(Snapshot {
+      name,
+      bytes: metadata.len(),
+      saved_at,
+    });
+  }
+  // the names start with the time they were saved, so this puts the newest first
+  snapshots.sort_by(|a, b| b.name.cmp(&a.name));
+  Ok(snapshots)
+}
+
+/// Opens a snapshot by name; only names from `list` are accepted.
+pub fn open(name: &str) -> ResultAnyError<File> {
+  if !list()?.iter().any(|s| s.name == name) {
+    return Err(format!("No snapshot {}", name).into());
+  }
+  Ok(File::open(state_directory().join(name))?)
+}
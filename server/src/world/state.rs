@@ -1,13 +1,21 @@
+use super::accounts::{PasswordSetup, ServiceAccount};
+use super::names::{NameIndex, NameMatch, NameQuery, Scope, NAME_ATTRS};
+use super::query::{AttrIndexes, Filter};
+use super::quotas::{Quota, QuotaOverride};
+use super::revisions::{PackageHistory, HISTORY_QUOTA_MULTIPLE};
 use crate::lua::{PackageReference, SerializableValue};
 use crate::object::types::*;
 use core::fmt::Display;
 use serde::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug)]
 pub enum Error {
   InvalidObjectId(Id),
   CyclicHierarchy { child: Id, parent: Id },
+  QuotaExceeded { username: String, limit: String },
+  NotAUser(Id),
+  TooManySubscriptions(Id),
 }
 
 impl std::error::Error for Error {}
@@ -21,6 +29,15 @@ impl Display for Error {
         "Moving child {} to parent {} causes a cycle",
         child, parent
       ),
+      Error::QuotaExceeded { username, limit } => {
+        write!(f, "{} has reached their quota of {}", username, limit)
+      }
+      Error::NotAUser(id) => write!(f, "{} is not a user", id),
+      Error::TooManySubscriptions(id) => write!(
+        f,
+        "{} has {} subscriptions already; unsubscribe from some first",
+        id, MAX_SUBSCRIPTIONS
+      ),
     }
   }
 }
@@ -33,6 +50,16 @@ impl From<Error> for rlua::Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// How many undelivered tells we hold per user before dropping the oldest.
+const PENDING_TELL_LIMIT: usize = 200;
+
+/// Diffs list at most this many objects of each sort; the counts are always complete.
+const MAX_DIFF_IDS: usize = 100;
+
+/// How many subscriptions an object can have, so ones to publishers which never
+/// publish can't pile up forever.
+const MAX_SUBSCRIPTIONS: usize = 100;
+
 #[derive(Serialize, Deserialize, Clone)]
 struct Object {
   parent: Option<Id>,
@@ -42,12 +69,16 @@ struct Object {
 
   #[serde(default)]
   timers: HashMap<String, Timer>,
+
+  #[serde(default)]
+  owner: Option<Id>, // the user who created it, unless it's been given away
 }
 
 impl Object {
   fn new(kind: ObjectKind) -> Object {
     Object {
       parent: None,
+      owner: None,
       kind: kind,
       attrs: HashMap::new(),
       state: HashMap::new(),
@@ -63,8 +94,65 @@ pub struct State {
   users: HashMap<String, Id>,
   live_packages: HashMap<PackageReference, String>, // string is lua code
 
+  #[serde(default)]
+  package_history: HashMap<PackageReference, PackageHistory>,
+
   #[serde(default)]
   current_time: GameTime,
+
+  #[serde(default)]
+  pending_tells: HashMap<String, Vec<PendingTell>>, // by username
+
+  #[serde(default)]
+  service_accounts: HashMap<String, ServiceAccount>, // by username
+
+  #[serde(default)]
+  login_providers: HashMap<String, String>, // username -> id of the auth provider they log in with
+
+  #[serde(default)]
+  password_hashes: HashMap<String, String>, // by username, for the local auth provider
+
+  #[serde(default)]
+  password_setups: HashMap<String, PasswordSetup>, // by username
+
+  #[serde(default)]
+  identities: HashMap<String, HashMap<String, String>>, // issuer -> subject -> username
+
+  #[serde(default)]
+  prompts: HashMap<String, PendingPrompt>, // by prompt id
+
+  #[serde(default)]
+  subscriptions: HashMap<Id, Vec<Subscription>>, // by publisher
+
+  #[serde(default)]
+  quota_overrides: HashMap<String, QuotaOverride>, // by username
+
+  #[serde(skip)]
+  default_quota: Quota,
+
+  #[serde(skip)]
+  owned_counts: HashMap<Id, usize>, // by owner; see rebuild_indexes
+
+  #[serde(skip)]
+  names: NameIndex, // see rebuild_indexes
+
+  #[serde(skip)]
+  attr_indexes: AttrIndexes,
+}
+
+/// How another state (e.g. a snapshot) differs from this one.
+#[derive(Serialize, Debug)]
+pub struct StateDiff {
+  pub current_time: GameTime,
+  pub other_time: GameTime,
+  pub missing_objects: usize, // here, but not in the other state
+  pub extra_objects: usize,   // in the other state, but not here
+  pub changed_objects: usize,
+  pub missing_ids: Vec<String>,
+  pub changed_ids: Vec<String>,
+  pub missing_users: Vec<String>,
+  pub extra_users: Vec<String>, // in the other state, but not here
+  pub changed_packages: Vec<String>,
 }
 
 /// Methods for manipulating the state of the world.
@@ -83,14 +171,238 @@ impl State {
       entrance: Id(0),
       users: HashMap::new(),
       live_packages: HashMap::new(),
+      package_history: HashMap::new(),
       current_time: Default::default(),
+      pending_tells: HashMap::new(),
+      service_accounts: HashMap::new(),
+      login_providers: HashMap::new(),
+      password_hashes: HashMap::new(),
+      password_setups: HashMap::new(),
+      identities: HashMap::new(),
+      prompts: HashMap::new(),
+      subscriptions: HashMap::new(),
+      quota_overrides: HashMap::new(),
+      default_quota: Quota::default(),
+      owned_counts: HashMap::new(),
+      names: NameIndex::default(),
+      attr_indexes: AttrIndexes::default(),
     }
   }
 
-  pub fn create_object(&mut self, kind: ObjectKind) -> Id {
+  /// Indexes aren't saved, so this needs calling after loading.
+  pub fn rebuild_indexes(&mut self, indexed_attrs: HashSet<String>) {
+    let mut names = NameIndex::default();
+    let mut attr_indexes = AttrIndexes::new(indexed_attrs);
+    let mut owned_counts = HashMap::new();
+    for (index, object) in self.objects.iter().enumerate() {
+      names.update(Id(index), &object.attrs);
+      for (attr, value) in object.attrs.iter() {
+        attr_indexes.update(Id(index), attr, None, Some(value));
+      }
+      if let Some(owner) = object.owner {
+        *owned_counts.entry(owner).or_insert(0) += 1;
+      }
+    }
+    self.names = names;
+    self.attr_indexes = attr_indexes;
+    self.owned_counts = owned_counts;
+  }
+
+  /// Compares objects by their parent, kind, owner, attrs and state.
+  pub fn diff(&self, other: &State) -> StateDiff {
+    let mut missing = vec![];
+    let mut changed = vec![];
+    for (index, object) in self.objects.iter().enumerate() {
+      match other.objects.get(index) {
+        None => missing.push(Id(index)),
+        Some(o) => {
+          if o.parent != object.parent
+            || o.kind != object.kind
+            || o.owner != object.owner
+            || o.attrs != object.attrs
+            || o.state != object.state
+          {
+            changed.push(Id(index))
+          }
+        }
+      }
+    }
+
+    let users_only_in = |a: &State, b: &State| {
+      let mut users: Vec<String> = a
+        .users
+        .keys()
+        .filter(|u| !b.users.contains_key(*u))
+        .cloned()
+        .collect();
+      users.sort();
+      users
+    };
+
+    let mut changed_packages: Vec<String> = self
+      .live_packages
+      .keys()
+      .chain(other.live_packages.keys())
+      .filter(|p| self.live_packages.get(*p) != other.live_packages.get(*p))
+      .map(|p| p.to_string())
+      .collect::<HashSet<String>>()
+      .into_iter()
+      .collect();
+    changed_packages.sort();
+
+    let ids = |ids: &[Id]| {
+      ids
+        .iter()
+        .take(MAX_DIFF_IDS)
+        .map(|id| id.to_string())
+        .collect()
+    };
+    StateDiff {
+      current_time: self.current_time,
+      other_time: other.current_time,
+      missing_objects: missing.len(),
+      extra_objects: other.objects.len().saturating_sub(self.objects.len()),
+      changed_objects: changed.len(),
+      missing_ids: ids(&missing),
+      changed_ids: ids(&changed),
+      missing_users: users_only_in(self, other),
+      extra_users: users_only_in(other, self),
+      changed_packages,
+    }
+  }
+
+  pub fn set_default_quota(&mut self, quota: Quota) {
+    self.default_quota = quota;
+  }
+
+  pub fn quota(&self, username: &str) -> Quota {
+    match self.quota_overrides.get(username) {
+      Some(over) => self.default_quota.with_override(over),
+      None => self.default_quota,
+    }
+  }
+
+  /// An override with no limits set removes the user's override.
+  pub fn set_quota_override(&mut self, username: &str, over: QuotaOverride) {
+    if over.objects.is_none() && over.package_bytes.is_none() {
+      self.quota_overrides.remove(username);
+    } else {
+      self.quota_overrides.insert(username.to_string(), over);
+    }
+  }
+
+  pub fn owned_object_count(&self, owner: Id) -> usize {
+    self.owned_counts.get(&owner).cloned().unwrap_or(0)
+  }
+
+  pub fn live_package_bytes(&self, username: &str) -> usize {
+    self
+      .live_packages
+      .iter()
+      .filter(|(package, _)| package.user() == username)
+      .map(|(_, content)| content.len())
+      .sum()
+  }
+
+  fn check_object_quota(&self, owner: Id) -> Result<()> {
+    let username = self.username(owner).unwrap_or_else(|| owner.to_string());
+    let limit = self.quota(&username).objects;
+    if self.owned_object_count(owner) >= limit {
+      return Err(Error::QuotaExceeded {
+        username,
+        limit: format!("{} objects", limit),
+      });
+    }
+    Ok(())
+  }
+
+  pub fn owner(&self, id: Id) -> Result<Option<Id>> {
+    self.object(id).map(|o| o.owner)
+  }
+
+  /// Gives the object to another user (who needs room in their quota for it),
+  /// or to nobody.
+  pub fn set_owner(&mut self, id: Id, owner: Option<Id>) -> Result<()> {
+    let old_owner = self.owner(id)?;
+    if old_owner == owner {
+      return Ok(());
+    }
+    if let Some(owner) = owner {
+      if self.username(owner).is_none() {
+        return Err(Error::NotAUser(owner));
+      }
+      self.check_object_quota(owner)?;
+      *self.owned_counts.entry(owner).or_insert(0) += 1;
+    }
+    if let Some(old_owner) = old_owner {
+      if let Some(count) = self.owned_counts.get_mut(&old_owner) {
+        *count = count.saturating_sub(1);
+      }
+    }
+    self.object_mut(id)?.owner = owner;
+    Ok(())
+  }
+
+  /// Objects (in id order) whose attrs match the filter; see query.rs.
+  pub fn query_objects(&self, filter: &Filter, limit: usize) -> Vec<Id> {
+    let matches = |id: &Id| {
+      self
+        .objects
+        .get(id.0)
+        .map_or(false, |o| filter.matches(&o.attrs))
+    };
+    match self.attr_indexes.candidates(filter) {
+      Some(candidates) => {
+        let mut candidates: Vec<Id> = candidates.into_iter().collect();
+        candidates.sort_by_key(|id| id.0);
+        candidates.into_iter().filter(matches).take(limit).collect()
+      }
+      None => (0..self.objects.len())
+        .map(Id)
+        .filter(matches)
+        .take(limit)
+        .collect(),
+    }
+  }
+
+  /// Finds objects by name or alias, best matches first; see names.rs.
+  pub fn find_objects(&self, from: Id, scope: Scope, text: &str) -> Result<Vec<NameMatch>> {
+    let query = NameQuery::parse(text);
+    let room = self.parent(from)?;
+
+    let mut candidates: Vec<Id> = vec![];
+    if scope == Scope::Inventory || scope == Scope::Nearby {
+      candidates.extend(self.children(from));
+    }
+    if scope == Scope::Room || scope == Scope::Nearby {
+      if let Some(room) = room {
+        candidates.push(room);
+        candidates.extend(self.children(room).filter(|id| *id != from));
+      }
+    }
+    if scope == Scope::World {
+      if let Some(word) = query.first_word() {
+        candidates.extend(self.names.with_word_prefix(word));
+      }
+      candidates.sort_by_key(|id| id.0);
+    }
+
+    Ok(self.names.find(&query, candidates.into_iter()))
+  }
+
+  /// Creates an object owned by `owner`, if they haven't reached their quota.
+  pub fn create_object(&mut self, kind: ObjectKind, owner: Option<Id>) -> Result<Id> {
+    if let Some(owner) = owner {
+      self.object(owner)?;
+      self.check_object_quota(owner)?;
+      *self.owned_counts.entry(owner).or_insert(0) += 1;
+    }
+
     let id = Id(self.objects.len());
-    self.objects.push(Object::new(kind));
-    id
+    let mut object = Object::new(kind);
+    object.owner = owner;
+    self.objects.push(object);
+    Ok(id)
   }
 
   fn object(&self, id: Id) -> Result<&Object> {
@@ -115,7 +427,10 @@ impl State {
     if let Some(id) = self.users.get(username) {
       *id
     } else {
-      let id = self.create_object(ObjectKind::for_user(username, user_type));
+      // users don't count against anyone's quota
+      let id = self
+        .create_object(ObjectKind::for_user(username, user_type), None)
+        .unwrap();
       let entrance = self.entrance();
       self.object_mut(id).unwrap().parent = Some(entrance);
 
@@ -138,6 +453,177 @@ impl State {
     None
   }
 
+  /// Returns the service account with this username, creating it (with the given user type) if needed.
+  pub fn get_or_create_service_account(
+    &mut self,
+    username: &str,
+    user_type: &str,
+  ) -> &mut ServiceAccount {
+    self
+      .service_accounts
+      .entry(username.to_string())
+      .or_insert_with(|| ServiceAccount::new(user_type))
+  }
+
+  pub fn service_account_mut(&mut self, username: &str) -> Option<&mut ServiceAccount> {
+    self.service_accounts.get_mut(username)
+  }
+
+  pub fn get_service_accounts(&self) -> &HashMap<String, ServiceAccount> {
+    &self.service_accounts
+  }
+
+  pub fn is_service_account(&self, username: &str) -> bool {
+    self.service_accounts.contains_key(username)
+  }
+
+  /// Finds the service account (username and user type) a token belongs to.
+  pub fn authenticate_service_token(&self, token: &str) -> Option<(String, String)> {
+    self
+      .service_accounts
+      .iter()
+      .find(|(_username, account)| account.accepts(token))
+      .map(|(username, account)| (username.clone(), account.user_type.clone()))
+  }
+
+  /// Each username belongs to the first auth provider to log it in, so (e.g.) someone
+  /// at the identity provider can't log in as a user with a local password.
+  /// Returns false if it belongs to another provider.
+  pub fn claim_username(&mut self, username: &str, provider: &str) -> bool {
+    let owner = self
+      .login_providers
+      .entry(username.to_string())
+      .or_insert_with(|| provider.to_string());
+    owner == provider
+  }
+
+  /// Whether `username` is in use, or reserved for someone, in any way.
+  pub fn username_taken(&self, username: &str) -> bool {
+    self.users.contains_key(username)
+      || self.service_accounts.contains_key(username)
+      || self.login_providers.contains_key(username)
+      || self.password_hashes.contains_key(username)
+      || self
+        .identities
+        .values()
+        .any(|subjects| subjects.values().any(|u| u == username))
+  }
+
+  /// The username an external identity (e.g. from OpenID Connect) logs in as.
+  pub fn identity_username(&self, issuer: &str, subject: &str) -> Option<&str> {
+    self
+      .identities
+      .get(issuer)
+      .and_then(|subjects| subjects.get(subject))
+      .map(|u| u.as_str())
+  }
+
+  pub fn link_identity(&mut self, issuer: &str, subject: &str, username: &str) {
+    self
+      .identities
+      .entry(issuer.to_string())
+      .or_insert_with(HashMap::new)
+      .insert(subject.to_string(), username.to_string());
+  }
+
+  /// The auth provider which claimed `username`, if any has yet.
+  pub fn login_provider(&self, username: &str) -> Option<&str> {
+    self.login_providers.get(username).map(|p| p.as_str())
+  }
+
+  pub fn password_hash(&self, username: &str) -> Option<&str> {
+    self.password_hashes.get(username).map(|h| h.as_str())
+  }
+
+  pub fn set_password_hash(&mut self, username: &str, hash: String) {
+    self.password_hashes.insert(username.to_string(), hash);
+    self.password_setups.remove(username);
+  }
+
+  /// Lets `username` set a new password with the returned token in the next
+  /// `expires_in` seconds, replacing any earlier token.
+  pub fn issue_password_setup(&mut self, username: &str, expires_in: u64) -> String {
+    let (setup, token) = PasswordSetup::issue(self.current_time + expires_in);
+    self.password_setups.insert(username.to_string(), setup);
+    token
+  }
+
+  pub fn accepts_password_setup(&self, username: &str, token: &str) -> bool {
+    self
+      .password_setups
+      .get(username)
+      .map(|setup| setup.accepts(token, self.current_time))
+      .unwrap_or(false)
+  }
+
+  pub fn queue_tell(&mut self, username: &str, tell: PendingTell) {
+    let queue = self
+      .pending_tells
+      .entry(username.to_string())
+      .or_insert_with(|| vec![]);
+    queue.push(tell);
+    if queue.len() > PENDING_TELL_LIMIT {
+      let excess = queue.len() - PENDING_TELL_LIMIT;
+      log::warn!("Dropping {} queued tells for {}", excess, username);
+      *queue = queue.split_off(excess);
+    }
+  }
+
+  /// Removes and returns the unexpired tells queued for this user, oldest first.
+  pub fn take_pending_tells(&mut self, username: &str) -> Vec<PendingTell> {
+    let now = self.current_time;
+    self
+      .pending_tells
+      .remove(username)
+      .unwrap_or_default()
+      .into_iter()
+      .filter(|t| t.expires_at > now)
+      .collect()
+  }
+
+  pub fn count_pending_tells(&self, username: &str) -> usize {
+    let now = self.current_time;
+    self
+      .pending_tells
+      .get(username)
+      .map(|queue| queue.iter().filter(|t| t.expires_at > now).count())
+      .unwrap_or(0)
+  }
+
+  pub fn add_prompt(&mut self, prompt_id: &str, prompt: PendingPrompt) {
+    self.prompts.insert(prompt_id.to_string(), prompt);
+  }
+
+  pub fn get_prompt(&self, prompt_id: &str) -> Option<&PendingPrompt> {
+    self.prompts.get(prompt_id)
+  }
+
+  pub fn take_prompt(&mut self, prompt_id: &str) -> Option<PendingPrompt> {
+    self.prompts.remove(prompt_id)
+  }
+
+  pub fn prompts_for(&self, user: Id) -> impl Iterator<Item = (&str, &PendingPrompt)> {
+    self
+      .prompts
+      .iter()
+      .filter(move |(_, p)| p.user == user)
+      .map(|(id, p)| (id.as_str(), p))
+  }
+
+  /// Removes and returns prompts which expire at or before `time`.
+  pub fn take_expired_prompts(&mut self, time: GameTime) -> Vec<(String, PendingPrompt)> {
+    let expired: Vec<String> = self
+      .prompts
+      .iter()
+      .filter(|(_, p)| p.expires_at <= time)
+      .map(|(id, _)| id.clone())
+      .collect();
+    expired
+      .into_iter()
+      .filter_map(|id| self.prompts.remove(&id).map(|p| (id, p)))
+      .collect()
+  }
+
   // TODO: move to Object?
   pub fn children(&self, id: Id) -> impl Iterator<Item = Id> + '_ {
     self
@@ -161,13 +647,87 @@ impl State {
     self.live_packages.get(&package)
   }
 
-  pub fn set_live_package_content(&mut self, package: PackageReference, content: String) {
+  /// Fails if this would take the package's user over their quota. Every save is
+  /// kept as a revision; this returns its number, or None if the package isn't live.
+  pub fn set_live_package_content(
+    &mut self,
+    package: PackageReference,
+    content: String,
+    author: Option<Id>,
+    message: Option<String>,
+  ) -> Result<Option<usize>> {
     // TODO: per-user permissions
     if !package.is_live_package() {
       log::warn!("Ignoring request to set non-live package");
-      return;
+      return Ok(None);
     }
+
+    let username = package.user().to_string();
+    let limit = self.quota(&username).package_bytes;
+    let current = self.live_packages.get(&package).map_or(0, |c| c.len());
+    if self.live_package_bytes(&username) - current + content.len() > limit {
+      return Err(Error::QuotaExceeded {
+        username,
+        limit: format!("{} bytes of live packages", limit),
+      });
+    }
+
+    let time = self.current_time;
+    let history = self
+      .package_history
+      .entry(package.clone())
+      .or_insert_with(PackageHistory::default);
+    if history.latest().is_none() {
+      // code saved before we kept history becomes the first revision
+      if let Some(previous) = self.live_packages.get(&package) {
+        history.record(
+          None,
+          time,
+          Some("Saved before history was kept".to_string()),
+          previous.clone(),
+        );
+      }
+    }
+    let revision = history.record(author, time, message, content.clone());
+    self.trim_package_history(&username, limit.saturating_mul(HISTORY_QUOTA_MULTIPLE));
+
     self.live_packages.insert(package, content);
+    Ok(Some(revision))
+  }
+
+  /// Bytes of code in all the revisions of the user's live packages.
+  pub fn package_history_bytes(&self, username: &str) -> usize {
+    self
+      .package_history
+      .iter()
+      .filter(|(package, _)| package.user() == username)
+      .map(|(_, history)| history.bytes())
+      .sum()
+  }
+
+  /// Drops the user's oldest revisions (but never a package's latest) until
+  /// their history fits in `limit` bytes.
+  fn trim_package_history(&mut self, username: &str, limit: usize) {
+    let mut histories: Vec<&mut PackageHistory> = self
+      .package_history
+      .iter_mut()
+      .filter(|(package, _)| package.user() == username)
+      .map(|(_, history)| history)
+      .collect();
+    let mut total: usize = histories.iter().map(|h| h.bytes()).sum();
+    while total > limit {
+      let oldest = (0..histories.len())
+        .filter_map(|i| histories[i].oldest_droppable().map(|r| (r.time, i)))
+        .min();
+      match oldest {
+        Some((_, i)) => total -= histories[i].drop_oldest(),
+        None => break,
+      }
+    }
+  }
+
+  pub fn package_history(&self, package: &PackageReference) -> Option<&PackageHistory> {
+    self.package_history.get(package)
   }
 
   pub fn set_attr(
@@ -176,7 +736,19 @@ impl State {
     key: String,
     value: SerializableValue,
   ) -> Result<Option<SerializableValue>> {
-    self.object_mut(id).map(|o| o.attrs.insert(key, value))
+    // not object_mut, so we can update the indexes while borrowing it
+    let object = self
+      .objects
+      .get_mut(id.0)
+      .ok_or_else(|| Error::InvalidObjectId(id))?;
+    let old = object.attrs.insert(key.clone(), value);
+    if NAME_ATTRS.contains(&key.as_str()) {
+      self.names.update(id, &object.attrs);
+    }
+    self
+      .attr_indexes
+      .update(id, &key, old.as_ref(), object.attrs.get(&key));
+    Ok(old)
   }
 
   pub fn get_attr(&self, id: Id, name: &str) -> Result<Option<SerializableValue>> {
@@ -224,9 +796,154 @@ impl State {
       }
     }
 
+    self.object_mut(child)?.parent = new_parent;
+    Ok(())
+  }
+
+  /// The outermost object containing this one (or itself, if nothing does).
+  pub fn room_of(&self, id: Id) -> Result<Id> {
+    match self.parent(id)? {
+      None => Ok(id),
+      Some(parent) => self.room_of(parent),
+    }
+  }
+
+  /// Objects can hear events from themselves, anything in the same room, and
+  /// anything which lists the event in its `public_events` attr.
+  pub fn may_subscribe(&self, subscriber: Id, publisher: Id, event: &str) -> bool {
+    if subscriber == publisher {
+      return true;
+    }
+    match (self.room_of(subscriber), self.room_of(publisher)) {
+      (Ok(a), Ok(b)) if a == b => return true,
+      (Ok(_), Ok(_)) => (),
+      _ => return false,
+    }
+    match self.get_attr(publisher, "public_events") {
+      Ok(Some(SerializableValue::Table(events))) => events
+        .iter()
+        .any(|(_, e)| *e == SerializableValue::String(event.to_string())),
+      _ => false,
+    }
+  }
+
+  pub fn subscribe(&mut self, publisher: Id, event: &str, subscriber: Id) -> Result<()> {
+    self.object(publisher)?;
+    self.object(subscriber)?;
+    let subscription = Subscription {
+      event: event.to_string(),
+      subscriber,
+    };
+    let exists = self
+      .subscriptions
+      .get(&publisher)
+      .map_or(false, |subscriptions| subscriptions.contains(&subscription));
+    if exists {
+      return Ok(());
+    }
+
+    if self.subscription_count(subscriber) >= MAX_SUBSCRIPTIONS {
+      // only worth checking them all when we'd otherwise refuse
+      self.prune_subscriber(subscriber);
+      if self.subscription_count(subscriber) >= MAX_SUBSCRIPTIONS {
+        return Err(Error::TooManySubscriptions(subscriber));
+      }
+    }
     self
-      .object_mut(child)
-      .map(|child| child.parent = new_parent)
+      .subscriptions
+      .entry(publisher)
+      .or_insert_with(Vec::new)
+      .push(subscription);
+    Ok(())
+  }
+
+  fn subscription_count(&self, subscriber: Id) -> usize {
+    self
+      .subscriptions
+      .values()
+      .flat_map(|subscriptions| subscriptions.iter())
+      .filter(|s| s.subscriber == subscriber)
+      .count()
+  }
+
+  /// Returns false if there was no such subscription.
+  pub fn unsubscribe(&mut self, publisher: Id, event: &str, subscriber: Id) -> bool {
+    match self.subscriptions.get_mut(&publisher) {
+      Some(subscriptions) => {
+        let before = subscriptions.len();
+        subscriptions.retain(|s| !(s.event == event && s.subscriber == subscriber));
+        let removed = subscriptions.len() < before;
+        if subscriptions.is_empty() {
+          self.subscriptions.remove(&publisher);
+        }
+        removed
+      }
+      None => false,
+    }
+  }
+
+  /// Who should hear `event` from `publisher`, dropping anyone no longer allowed to.
+  /// Moves don't prune subscriptions, since that would mean checking every one;
+  /// they're checked here instead, when they're next used.
+  pub fn subscribers(&mut self, publisher: Id, event: &str) -> Vec<Id> {
+    self.prune_subscriptions(publisher);
+    self
+      .subscriptions
+      .get(&publisher)
+      .map(|subscriptions| {
+        subscriptions
+          .iter()
+          .filter(|s| s.event == event)
+          .map(|s| s.subscriber)
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+
+  /// Drops subscriptions to `publisher` which are no longer allowed (e.g.
+  /// because one side has left the room).
+  fn prune_subscriptions(&mut self, publisher: Id) {
+    let disallowed: Vec<Subscription> = match self.subscriptions.get(&publisher) {
+      Some(subscriptions) => subscriptions
+        .iter()
+        .filter(|s| !self.may_subscribe(s.subscriber, publisher, &s.event))
+        .cloned()
+        .collect(),
+      None => return,
+    };
+    for s in disallowed {
+      self.unsubscribe(publisher, &s.event, s.subscriber);
+    }
+  }
+
+  /// Drops `subscriber`'s subscriptions which are no longer allowed, to any publisher.
+  fn prune_subscriber(&mut self, subscriber: Id) {
+    let disallowed: Vec<(Id, String)> = self
+      .subscriptions
+      .iter()
+      .flat_map(|(publisher, subscriptions)| {
+        subscriptions
+          .iter()
+          .filter(move |s| s.subscriber == subscriber)
+          .map(move |s| (*publisher, s.event.clone()))
+      })
+      .filter(|(publisher, event)| !self.may_subscribe(subscriber, *publisher, event))
+      .collect();
+    for (publisher, event) in disallowed {
+      self.unsubscribe(publisher, &event, subscriber);
+    }
+  }
+
+  /// Makes every object of one kind another, returning the ones changed.
+  pub fn change_kind(&mut self, from: &ObjectKind, to: &ObjectKind) -> Vec<Id> {
+    let mut changed = vec![];
+    for (index, object) in self.objects.iter_mut().enumerate() {
+      if object.kind == *from {
+        object.kind = to.clone();
+        changed.push(Id(index));
+      }
+    }
+    changed
   }
 
   pub fn kind(&self, id: Id) -> Result<ObjectKind> {
@@ -247,14 +964,26 @@ impl State {
     Ok(())
   }
 
+  pub fn get_timer(&self, id: Id, name: &str) -> Result<Option<&Timer>> {
+    self.object(id).map(|o| o.timers.get(name))
+  }
+
+  pub fn list_timers(&self, id: Id) -> Result<impl Iterator<Item = (&str, &Timer)>> {
+    self
+      .object(id)
+      .map(|o| o.timers.iter().map(|(k, t)| (k.as_str(), t)))
+  }
+
   pub fn clear_timer(&mut self, id: Id, name: &str) -> Result<()> {
     let o = self.object_mut(id)?;
     o.timers.remove(name);
     Ok(())
   }
 
-  pub fn extract_ready_timers(&mut self, new_time: GameTime) -> Vec<(Id, Timer)> {
-    let current_time = self.current_time;
+  /// Removes and returns all timers due at or before `new_time`, including
+  /// overdue ones (at or before the current time), which the caller is
+  /// expected to handle according to their `OverduePolicy`.
+  pub fn extract_ready_timers(&mut self, new_time: GameTime) -> Vec<(Id, String, Timer)> {
     self
       .objects
       .iter_mut()
@@ -263,13 +992,65 @@ impl State {
         let (mut ready, not_ready) = o
           .timers
           .drain()
-          .partition(|(_k, t)| t.target_time <= new_time && t.target_time > current_time);
+          .partition(|(_k, t)| t.target_time <= new_time);
         o.timers = not_ready;
         ready
           .drain()
-          .map(|(_k, t)| (Id(id), t))
-          .collect::<Vec<(Id, Timer)>>()
+          .map(|(k, t)| (Id(id), k, t))
+          .collect::<Vec<(Id, String, Timer)>>()
       })
       .collect()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn room(state: &mut State) -> Id {
+    state.create_object(ObjectKind::for_room(), None).unwrap()
+  }
+
+  #[test]
+  fn subscriptions_are_capped_per_subscriber() {
+    let mut state = State::new();
+    let subscriber = room(&mut state);
+    for i in 0..MAX_SUBSCRIPTIONS {
+      state
+        .subscribe(subscriber, &format!("event{}", i), subscriber)
+        .unwrap();
+    }
+    // again is fine, since it's not a new one
+    state.subscribe(subscriber, "event0", subscriber).unwrap();
+    assert!(state.subscribe(subscriber, "another", subscriber).is_err());
+
+    // the cap is per subscriber
+    let other = room(&mut state);
+    state.subscribe(subscriber, "another", other).unwrap();
+    assert!(state.unsubscribe(subscriber, "event0", subscriber));
+    state.subscribe(subscriber, "another", subscriber).unwrap();
+  }
+
+  #[test]
+  fn subscriptions_no_longer_allowed_make_room() {
+    let mut state = State::new();
+    let here = room(&mut state);
+    let there = room(&mut state);
+    let subscriber = room(&mut state);
+    state.move_object(subscriber, Some(here)).unwrap();
+    for _ in 0..MAX_SUBSCRIPTIONS {
+      let publisher = room(&mut state);
+      state.move_object(publisher, Some(here)).unwrap();
+      state.subscribe(publisher, "quiet", subscriber).unwrap();
+    }
+    let publisher = room(&mut state);
+    state.move_object(publisher, Some(there)).unwrap();
+    assert!(state.subscribe(publisher, "loud", subscriber).is_err());
+
+    // once it's left, it can't hear the others, so those go
+    state.move_object(subscriber, Some(there)).unwrap();
+    state.subscribe(publisher, "loud", subscriber).unwrap();
+    assert_eq!(state.subscription_count(subscriber), 1);
+    assert_eq!(state.subscribers(publisher, "loud"), vec![subscriber]);
+  }
+}